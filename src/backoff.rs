@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+const INITIAL_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Exponentially increasing delays between attempts to reconnect to the server.
+pub struct Backoff {
+    next_delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            next_delay: INITIAL_DELAY,
+        }
+    }
+}
+
+impl Backoff {
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next_delay;
+        self.next_delay = (self.next_delay * 2).min(MAX_DELAY);
+
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next_delay = INITIAL_DELAY;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double() {
+        let mut backoff = Backoff::default();
+
+        assert_eq!(backoff.next_delay(), INITIAL_DELAY);
+        assert_eq!(backoff.next_delay(), INITIAL_DELAY * 2);
+        assert_eq!(backoff.next_delay(), INITIAL_DELAY * 4);
+    }
+
+    #[test]
+    fn delays_are_capped() {
+        let mut backoff = Backoff::default();
+
+        for _ in 0..20 {
+            backoff.next_delay();
+        }
+
+        assert_eq!(backoff.next_delay(), MAX_DELAY);
+    }
+
+    #[test]
+    fn reset_starts_again_from_initial_delay() {
+        let mut backoff = Backoff::default();
+
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+
+        assert_eq!(backoff.next_delay(), INITIAL_DELAY);
+    }
+}
@@ -1,74 +1,187 @@
+use clap::Parser;
 use crossterm::{cursor, event, execute, terminal};
-use flume::Sender;
-use jsonl::Connection;
-use nunitius::sender::ui;
-use nunitius::{
-    Color, ConnectionKind, Login, LoginResponse, Message, SenderEvent, TypingEvent, User,
-};
+use flume::{Receiver, Selector, Sender};
+use nunitius::config::{self, ConnectionArgs};
+use nunitius::sender::command::{self, Command, Input};
+use nunitius::sender::connection::{self, ConnectionEvent, ServerConnection};
+use nunitius::sender::input_history::InputHistory;
+use nunitius::sender::ui::{self, read_and_clear};
+use nunitius::sender::upload::Upload;
+use nunitius::viewer;
+use nunitius::{Color, LoginResponse, Message, SenderEvent, SenderMessage, DEFAULT_ROOM};
 use std::io::{self, Write};
-use std::net::TcpStream;
-use std::{fs, thread};
-
-type TcpConnection = Connection<io::BufReader<TcpStream>, TcpStream>;
+use std::path::Path;
+use std::thread;
+
+/// Sends messages to a nunitius server.
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Nickname to log in with instead of asking for one
+    #[arg(long, env = "NUNITIUS_NICKNAME")]
+    nickname: Option<String>,
+
+    /// Password to log in with instead of asking for one
+    #[arg(long, env = "NUNITIUS_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+
+    /// Color of your nickname (red, green, yellow, blue, magenta or cyan)
+    #[arg(long, env = "NUNITIUS_COLOR")]
+    color: Option<Color>,
+}
 
 fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let (config, connector) = args.connection.resolve()?;
+    let nickname = args.nickname.or(config.client.nickname.clone());
+    let password = args.password.or(config.client.password.clone());
+    let color = config::resolve_color(args.color, &config.client)?;
+
     let mut stdout = io::stdout();
     let mut stderr = io::stderr();
 
-    let stream = TcpStream::connect("127.0.0.1:9999")?;
-    let mut connection = Connection::new_from_tcp_stream(stream)?;
-
-    connection.write(&ConnectionKind::Sender)?;
-
-    login(&mut connection, &mut stdout, &mut stderr)?;
+    let mut connection = ServerConnection::connect(&connector)?;
+    let (user, password) = ui::log_in(
+        &mut connection,
+        nickname,
+        password,
+        color,
+        &mut stdout,
+        &mut stderr,
+    )?;
 
-    let (typing_event_tx, typing_event_rx) = flume::bounded(100);
     let (sender_event_tx, sender_event_rx) = flume::bounded(100);
-
-    thread::spawn({
-        let sender_event_tx = sender_event_tx.clone();
-
-        move || {
-            for typing_event in typing_event_rx {
-                sender_event_tx
-                    .send(SenderEvent::Typing(typing_event))
-                    .unwrap();
-            }
-        }
+    let (upload_tx, upload_rx) = flume::bounded(100);
+    let (sender_message_tx, sender_message_rx) = flume::bounded(100);
+    let (connection_event_tx, connection_event_rx) = flume::bounded(100);
+
+    let connection_handle = thread::spawn(move || {
+        connection::maintain_connection(
+            connector,
+            user,
+            password,
+            connection,
+            connection::Channels {
+                sender_event_rx,
+                upload_rx,
+                sender_message_tx,
+            },
+            connection_event_tx,
+        )
     });
 
-    thread::spawn(move || {
-        for sender_event in sender_event_rx {
-            connection.write(&sender_event).unwrap();
+    thread::spawn(|| {
+        if let Err(e) = print_notices(sender_message_rx, connection_event_rx) {
+            eprintln!("Error: {:#}", e);
         }
     });
 
+    let mut history = InputHistory::open_default_or_warn();
+    let mut room = DEFAULT_ROOM.to_string();
+
     loop {
+        let prompt = format!("Type a message in #{}", room);
+
         let input = read_and_clear_evented(
-            "Type a message",
+            &prompt,
             &mut io::stdout(),
-            typing_event_tx.clone(),
+            &mut history,
+            sender_event_tx.clone(),
             |code, modifiers| {
-                if let (event::KeyCode::Char('u'), event::KeyModifiers::CONTROL) = (code, modifiers)
-                {
-                    handle_file_upload(&mut stdout, &sender_event_tx)?;
+                match (code, modifiers) {
+                    (event::KeyCode::Char('u'), event::KeyModifiers::CONTROL) => {
+                        handle_file_upload(&mut stdout, &upload_tx)?
+                    }
+                    (event::KeyCode::Char('o'), event::KeyModifiers::CONTROL) => {
+                        handle_room_change(&mut stdout, &mut room, &sender_event_tx)?
+                    }
+                    (event::KeyCode::Char('p'), event::KeyModifiers::CONTROL) => {
+                        handle_direct_message(&mut stdout, &sender_event_tx)?
+                    }
+                    (event::KeyCode::Char('l'), event::KeyModifiers::CONTROL) => {
+                        sender_event_tx.send(SenderEvent::ListRooms).unwrap()
+                    }
+                    _ => {}
                 }
 
                 Ok(())
             },
         )?;
 
-        if let Some(input) = input {
-            sender_event_tx
-                .send(SenderEvent::Message(Message::Text { body: input }))
-                .unwrap();
+        let input = match input {
+            Some(input) => input,
+            None => continue,
+        };
+
+        let result = match command::parse(&input) {
+            Ok(Input::Message(body)) => {
+                sender_event_tx
+                    .send(SenderEvent::Message(Message::Text { body }))
+                    .unwrap();
+                Ok(())
+            }
+            Ok(Input::Command(Command::Quit)) => break,
+            Ok(Input::Command(command)) => {
+                run_command(command, &mut stdout, &sender_event_tx, &upload_tx)
+            }
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            print_notice(&format!("{:#}", e), &mut stdout)?;
         }
     }
+
+    // once nothing can send events any more
+    // the connection sends whatever is left and logs out,
+    // abandoning any unfinished uploads
+    drop(sender_event_tx);
+    connection_handle.join().unwrap();
+
+    Ok(())
+}
+
+fn run_command(
+    command: Command,
+    stdout: &mut io::Stdout,
+    sender_event_tx: &Sender<SenderEvent>,
+    upload_tx: &Sender<Upload>,
+) -> anyhow::Result<()> {
+    let sender_event = match command {
+        Command::Nick { nickname } => {
+            let prompt = format!("Password for {}", nickname);
+            let password = ui::read_password_and_clear(&prompt, stdout)?.unwrap_or_default();
+            SenderEvent::ChangeNickname { nickname, password }
+        }
+        Command::Color { color } => SenderEvent::ChangeColor { color },
+        Command::Me { action } => SenderEvent::Message(Message::Action { body: action }),
+        Command::Msg { to, body } => SenderEvent::DirectMessage {
+            to,
+            message: Message::Text { body },
+        },
+        Command::Upload { path } => {
+            upload_tx.send(Upload::open(&path)?).unwrap();
+            return Ok(());
+        }
+        Command::Help => {
+            for line in command::HELP {
+                print_notice(line, stdout)?;
+            }
+            return Ok(());
+        }
+        Command::Quit => unreachable!("quitting is handled by the main loop"),
+    };
+
+    sender_event_tx.send(sender_event).unwrap();
+
+    Ok(())
 }
 
 fn handle_file_upload(
     stdout: &mut io::Stdout,
-    sender_event_tx: &flume::Sender<SenderEvent>,
+    upload_tx: &flume::Sender<Upload>,
 ) -> Result<(), anyhow::Error> {
     loop {
         let path = read_and_clear("Choose a file to upload", stdout)?;
@@ -79,13 +192,10 @@ fn handle_file_upload(
             continue;
         };
 
-        let file_contents = fs::read(path)?;
-
-        sender_event_tx
-            .send(SenderEvent::Message(Message::File {
-                contents: file_contents,
-            }))
-            .unwrap();
+        match Upload::open(Path::new(&path)) {
+            Ok(upload) => upload_tx.send(upload).unwrap(),
+            Err(e) => print_notice(&format!("{:#}", e), stdout)?,
+        }
 
         break;
     }
@@ -100,82 +210,166 @@ fn handle_file_upload(
     Ok(())
 }
 
-fn login(
-    connection: &mut TcpConnection,
+fn handle_room_change(
     stdout: &mut io::Stdout,
-    stderr: &mut io::Stderr,
+    room: &mut String,
+    sender_event_tx: &flume::Sender<SenderEvent>,
 ) -> anyhow::Result<()> {
-    loop {
-        let nickname = read_and_clear("Choose a nickname", stdout)?;
+    let prompt = format!(
+        "Choose a room to join (leave empty to return to #{})",
+        DEFAULT_ROOM
+    );
+
+    let sender_event = match read_and_clear(&prompt, stdout)? {
+        Some(new_room) => {
+            *room = new_room.trim_start_matches('#').to_string();
+            SenderEvent::JoinRoom { room: room.clone() }
+        }
+        None => {
+            *room = DEFAULT_ROOM.to_string();
+            SenderEvent::LeaveRoom
+        }
+    };
 
-        let nickname = if let Some(n) = nickname {
-            n
-        } else {
-            continue;
-        };
+    sender_event_tx.send(sender_event).unwrap();
 
-        let user = User {
-            nickname: nickname.clone(),
-            color: read_color(stdout, stderr)?,
-        };
+    execute!(
+        stdout,
+        cursor::MoveUp(1),
+        terminal::Clear(terminal::ClearType::CurrentLine),
+    )?;
 
-        connection.write(&Login { user })?;
+    Ok(())
+}
 
-        let response: LoginResponse = connection.read()?;
+fn handle_direct_message(
+    stdout: &mut io::Stdout,
+    sender_event_tx: &flume::Sender<SenderEvent>,
+) -> anyhow::Result<()> {
+    if let Some(to) = read_and_clear("Choose who to message privately", stdout)? {
+        let prompt = format!("Type a private message to {}", to);
 
-        if response.nickname_taken {
-            writeln!(stderr, "Nickname ‘{}’ taken. Try another one.", nickname)?;
-        } else {
-            return Ok(());
+        if let Some(body) = read_and_clear(&prompt, stdout)? {
+            sender_event_tx
+                .send(SenderEvent::DirectMessage {
+                    to,
+                    message: Message::Text { body },
+                })
+                .unwrap();
         }
     }
+
+    execute!(
+        stdout,
+        cursor::MoveUp(1),
+        terminal::Clear(terminal::ClearType::CurrentLine),
+    )?;
+
+    Ok(())
 }
 
-fn read_color(stdout: &mut io::Stdout, stderr: &mut io::Stderr) -> anyhow::Result<Option<Color>> {
-    loop {
-        let color = if let Some(s) = read_and_clear("Choose a color", stdout)? {
-            s
-        } else {
-            return Ok(None);
-        };
+fn print_notices(
+    sender_message_rx: Receiver<SenderMessage>,
+    connection_event_rx: Receiver<ConnectionEvent>,
+) -> anyhow::Result<()> {
+    let mut stdout = io::stdout();
 
-        let color = match color.as_str() {
-            "red" => Color::Red,
-            "green" => Color::Green,
-            "yellow" => Color::Yellow,
-            "blue" => Color::Blue,
-            "magenta" => Color::Magenta,
-            "cyan" => Color::Cyan,
-            _ => {
-                writeln!(stderr, "‘{}’ is an invalid color.", color)?;
-                continue;
-            }
+    // a line for every percent would push everything else off the screen
+    let mut reported_tenth = None;
+
+    loop {
+        let notice = Selector::new()
+            .recv(&sender_message_rx, |sender_message| {
+                sender_message.map(|sender_message| match sender_message {
+                    SenderMessage::Rooms(rooms) => {
+                        let rooms: Vec<_> = rooms
+                            .iter()
+                            .map(|room| format!("#{}", viewer::sanitize(room)))
+                            .collect();
+                        Some(format!("Rooms: {}", rooms.join(" ")))
+                    }
+                    SenderMessage::NoSuchUser { nickname } => {
+                        Some(format!("‘{}’ is not logged in.", nickname))
+                    }
+                    SenderMessage::NicknameChange { nickname, response } => Some(match response {
+                        LoginResponse::LoggedIn => format!("You’re now known as {}.", nickname),
+                        LoginResponse::NicknameTaken => {
+                            format!("‘{}’ is already logged in elsewhere.", nickname)
+                        }
+                        LoginResponse::BadCredentials => {
+                            "Incorrect nickname or password.".to_string()
+                        }
+                    }),
+                    // the connection deals with these itself,
+                    // so there’s nothing to show if they get this far
+                    SenderMessage::UploadProgress { .. }
+                    | SenderMessage::UploadFinished { .. }
+                    | SenderMessage::UploadFailed { .. }
+                    | SenderMessage::Heartbeat => None,
+                })
+            })
+            .recv(&connection_event_rx, |connection_event| {
+                connection_event.map(|connection_event| match connection_event {
+                    ConnectionEvent::Reconnected => Some("Reconnected.".to_string()),
+                    ConnectionEvent::Disconnected { error, retry_in } => Some(format!(
+                        "Disconnected ({:#}), reconnecting in {}s…",
+                        error,
+                        retry_in.as_secs_f32().ceil()
+                    )),
+                    ConnectionEvent::UploadProgress { filename, percent } => {
+                        if reported_tenth == Some(percent / 10) {
+                            return None;
+                        }
+
+                        reported_tenth = Some(percent / 10);
+                        Some(format!("Uploading {}… {}%", filename, percent))
+                    }
+                    ConnectionEvent::UploadFinished { filename } => {
+                        reported_tenth = None;
+                        Some(format!("Uploaded {}.", filename))
+                    }
+                    ConnectionEvent::UploadFailed { filename, reason } => {
+                        reported_tenth = None;
+                        Some(format!("Couldn’t upload {} ({}).", filename, reason))
+                    }
+                })
+            })
+            .wait();
+
+        let notice = match notice {
+            Ok(Some(notice)) => notice,
+            Ok(None) => continue,
+            Err(_) => return Ok(()),
         };
 
-        return Ok(Some(color));
+        print_notice(&notice, &mut stdout)?;
     }
 }
 
-fn read_and_clear(prompt: &str, stdout: &mut io::Stdout) -> anyhow::Result<Option<String>> {
-    let output = ui::read_input(prompt, stdout)?;
+/// Shows `notice` where the prompt is,
+/// which is redrawn below it the next time a key is pressed.
+fn print_notice(notice: &str, stdout: &mut io::Stdout) -> anyhow::Result<()> {
+    execute!(stdout, terminal::Clear(terminal::ClearType::CurrentLine))?;
+    write!(stdout, "\r{}\r\n", notice)?;
+    stdout.flush()?;
 
-    execute!(
-        stdout,
-        cursor::MoveUp(1),
-        terminal::Clear(terminal::ClearType::CurrentLine),
-    )?;
-
-    Ok(output)
+    Ok(())
 }
 
 fn read_and_clear_evented(
     prompt: &str,
     stdout: &mut io::Stdout,
-    typing_event_tx: Sender<TypingEvent>,
+    history: &mut InputHistory,
+    sender_event_tx: Sender<SenderEvent>,
     unknown_key_event_handler: impl FnMut(event::KeyCode, event::KeyModifiers) -> anyhow::Result<()>,
 ) -> anyhow::Result<Option<String>> {
-    let output =
-        ui::read_input_evented(prompt, stdout, typing_event_tx, unknown_key_event_handler)?;
+    let output = ui::read_input_evented(
+        prompt,
+        stdout,
+        history,
+        sender_event_tx,
+        unknown_key_event_handler,
+    )?;
 
     execute!(
         stdout,
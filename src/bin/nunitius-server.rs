@@ -1,11 +1,143 @@
+use clap::{Parser, Subcommand};
 use flume::{Receiver, Sender};
-use log::error;
-use std::net::TcpListener;
+use log::{error, info, warn};
+use nunitius::config::{Config, Heartbeat, DEFAULT_ADDRESS, DEFAULT_PORT};
+use nunitius::prompt;
+use nunitius::server::{Accounts, EventLog, FileStore, FsyncPolicy, UploadSettings};
+use nunitius::stream::Acceptor;
+use std::io::{self, BufRead, IsTerminal};
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::time;
 
-fn main() -> anyhow::Result<()> {
-    fern::Dispatch::new()
-        .level(log::LevelFilter::Trace)
+/// Seconds to wait for a sender to reconnect before logging them out.
+const DEFAULT_GRACE_PERIOD: u64 = 30;
+
+/// Seconds a new client has to say whether it’s a sender or a viewer.
+const DEFAULT_HANDSHAKE_TIMEOUT: u64 = 10;
+
+/// Megabytes in the largest file a sender can upload.
+const DEFAULT_MAX_UPLOAD_SIZE: u64 = 100;
+
+/// How many new clients can be working out what they are at once;
+/// any more wait in the listen backlog.
+const MAX_PENDING_HANDSHAKES: usize = 1024;
+
+/// Relays chat messages between senders and viewers.
+#[derive(Parser)]
+struct Args {
+    /// Config file to use instead of the one in your config directory
+    #[arg(long, env = "NUNITIUS_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on
+    #[arg(long, env = "NUNITIUS_LISTEN_ADDRESS")]
+    address: Option<String>,
+
+    /// Port to listen on
+    #[arg(long, env = "NUNITIUS_LISTEN_PORT")]
+    port: Option<u16>,
+
+    /// Where to keep the chat history
+    #[arg(long, env = "NUNITIUS_HISTORY")]
+    history: Option<PathBuf>,
+
+    /// Where to keep direct messages
+    #[arg(long, env = "NUNITIUS_DIRECT_MESSAGES")]
+    direct_messages: Option<PathBuf>,
+
+    /// Most verbose level of log messages to show
+    #[arg(long, env = "NUNITIUS_LOG_LEVEL")]
+    log_level: Option<log::LevelFilter>,
+
+    /// Write log messages to this file instead of stdout
+    #[arg(long, env = "NUNITIUS_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Certificate chain to use for TLS, in PEM format
+    #[arg(long, env = "NUNITIUS_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key to use for TLS, in PEM format
+    #[arg(long, env = "NUNITIUS_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Where to keep the accounts of registered users
+    #[arg(long, env = "NUNITIUS_ACCOUNTS")]
+    accounts: Option<PathBuf>,
+
+    /// Seconds to keep the nickname of a sender whose connection broke
+    /// reserved for them to reconnect
+    #[arg(long, env = "NUNITIUS_GRACE_PERIOD")]
+    grace_period: Option<u64>,
+
+    /// Seconds a new client has to say whether it’s a sender or a viewer
+    /// before being disconnected
+    #[arg(long, env = "NUNITIUS_HANDSHAKE_TIMEOUT")]
+    handshake_timeout: Option<u64>,
+
+    /// Seconds of quiet after which to send clients a heartbeat
+    #[arg(long, env = "NUNITIUS_HEARTBEAT_INTERVAL")]
+    heartbeat_interval: Option<u64>,
+
+    /// Seconds without hearing from a client before disconnecting it
+    #[arg(long, env = "NUNITIUS_HEARTBEAT_TIMEOUT")]
+    heartbeat_timeout: Option<u64>,
+
+    /// Megabytes in the largest file senders can upload
+    #[arg(long, env = "NUNITIUS_MAX_UPLOAD_SIZE")]
+    max_upload_size: Option<u64>,
+
+    /// Directory to keep uploaded files in
+    #[arg(long, env = "NUNITIUS_FILES")]
+    files: Option<PathBuf>,
+
+    /// When to make sure the history is on disk:
+    /// after every event (always), after every so many events (a number),
+    /// or whenever the operating system likes (never)
+    #[arg(long, env = "NUNITIUS_FSYNC")]
+    fsync: Option<FsyncPolicy>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Registers a user, or changes their password if they already have an account;
+    /// a running server picks the change up without restarting
+    SetPassword { nickname: String },
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let config = Config::load(args.config.as_deref())?.server;
+
+    let accounts_path = args
+        .accounts
+        .or(config.accounts)
+        .unwrap_or_else(|| PathBuf::from("nunitius-accounts.jsonl"));
+    let mut accounts = Accounts::open(&accounts_path)?;
+
+    if let Some(Command::SetPassword { nickname }) = args.command {
+        let password = read_new_password()?;
+        accounts.set_password(&nickname, &password)?;
+        println!("Set password for ‘{}’.", nickname);
+
+        return Ok(());
+    }
+
+    let log = fern::Dispatch::new()
+        .level(
+            args.log_level
+                .or(config.log_level)
+                .unwrap_or(log::LevelFilter::Trace),
+        )
         .format(|out, message, record| {
             out.finish(format_args!(
                 "{:<7} {:<40} {}",
@@ -13,11 +145,105 @@ fn main() -> anyhow::Result<()> {
                 format!("[{}]", record.target()),
                 message,
             ))
-        })
-        .chain(std::io::stdout())
-        .apply()?;
+        });
+
+    match args.log_file.or(config.log_file) {
+        Some(log_file) => log.chain(fern::log_file(log_file)?).apply()?,
+        None => log.chain(std::io::stdout()).apply()?,
+    }
 
-    let listener = TcpListener::bind("127.0.0.1:9999")?;
+    let history_path = args
+        .history
+        .or(config.history)
+        .unwrap_or_else(|| PathBuf::from("nunitius-history.jsonl"));
+    let direct_messages_path = args
+        .direct_messages
+        .or(config.direct_messages)
+        .unwrap_or_else(|| PathBuf::from("nunitius-direct-messages.jsonl"));
+
+    let files_path = args
+        .files
+        .or(config.files)
+        .unwrap_or_else(|| PathBuf::from("nunitius-files"));
+    let files = FileStore::open(&files_path)?;
+
+    let fsync_policy = match args.fsync {
+        Some(fsync_policy) => fsync_policy,
+        None => config
+            .fsync
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or(FsyncPolicy::Always),
+    };
+
+    let (mut event_log, mut existing_events) = EventLog::open(&history_path, fsync_policy)?;
+    let (mut direct_message_log, mut existing_direct_messages) =
+        EventLog::open(&direct_messages_path, fsync_policy)?;
+
+    move_contents_into_store(&mut event_log, &mut existing_events, &files)?;
+    move_contents_into_store(
+        &mut direct_message_log,
+        &mut existing_direct_messages,
+        &files,
+    )?;
+
+    let last_seq = existing_events
+        .iter()
+        .chain(&existing_direct_messages)
+        .map(|event| event.seq)
+        .max()
+        .unwrap_or(0);
+
+    let address = args
+        .address
+        .or(config.address)
+        .unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+    let port = args.port.or(config.port).unwrap_or(DEFAULT_PORT);
+
+    let tls = match (
+        args.tls_cert.or(config.tls_cert),
+        args.tls_key.or(config.tls_key),
+    ) {
+        (Some(cert), Some(key)) => Some(nunitius::tls::server_config(&cert, &key)?),
+        (None, None) => None,
+        _ => anyhow::bail!("TLS needs both a certificate and a private key"),
+    };
+    let acceptor = Acceptor { tls };
+
+    let grace_period = Duration::from_secs(
+        args.grace_period
+            .or(config.grace_period)
+            .unwrap_or(DEFAULT_GRACE_PERIOD),
+    );
+    let handshake_timeout = Duration::from_secs(
+        args.handshake_timeout
+            .or(config.handshake_timeout)
+            .unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT),
+    );
+    let heartbeat = Heartbeat::resolve(
+        args.heartbeat_interval.or(config.heartbeat_interval),
+        args.heartbeat_timeout.or(config.heartbeat_timeout),
+    )?;
+    let files = Arc::new(files);
+    let upload_settings = UploadSettings {
+        files: Arc::clone(&files),
+        max_size: args
+            .max_upload_size
+            .or(config.max_upload_size)
+            .unwrap_or(DEFAULT_MAX_UPLOAD_SIZE)
+            * 1_000_000,
+    };
+
+    if accounts.is_empty() {
+        warn!(
+            "no accounts have been registered, so nobody can log in; \
+             add one with the set-password command"
+        );
+    }
+    let accounts = Arc::new(accounts);
+
+    let listener = TcpListener::bind((address, port)).await?;
 
     let (sender_tx, sender_rx) = flume::bounded(100);
     let (viewer_tx, viewer_rx) = flume::bounded(100);
@@ -25,38 +251,150 @@ fn main() -> anyhow::Result<()> {
     let (history_request_tx, history_request_rx) = flume::bounded(100);
 
     let (event_tx, event_rx) = flume::bounded(100);
+    let (sequenced_event_tx, sequenced_event_rx) = flume::bounded(100);
     let (viewer_handler_event_tx, viewer_handler_event_rx) = flume::bounded(100);
     let (history_handler_event_tx, history_handler_event_rx) = flume::bounded(100);
 
-    thread::spawn(|| nunitius::server::sender_handler(sender_rx, nickname_event_tx, event_tx));
-    thread::spawn(|| {
-        nunitius::server::viewer_handler(viewer_rx, viewer_handler_event_rx, history_request_tx)
+    thread::spawn({
+        let event_tx = event_tx.clone();
+        move || nunitius::server::nickname_handler(nickname_event_rx, event_tx, grace_period)
     });
-    thread::spawn(|| nunitius::server::nickname_handler(nickname_event_rx));
+    // everything that deals with connections runs on the async runtime
+    // so that idle ones are cheap,
+    // while the handlers that only talk to other handlers or the disk
+    // keep a thread each
+    tokio::spawn(nunitius::server::sender_handler(
+        sender_rx,
+        nickname_event_tx,
+        event_tx,
+        history_request_tx.clone(),
+        Arc::clone(&accounts),
+        heartbeat,
+        upload_settings,
+    ));
+    tokio::spawn(nunitius::server::viewer_handler(
+        viewer_rx,
+        viewer_handler_event_rx,
+        history_request_tx,
+        Arc::clone(&accounts),
+        heartbeat,
+        files,
+    ));
     thread::spawn(|| {
-        nunitius::server::history_handler(history_handler_event_rx, history_request_rx)
+        nunitius::server::history_handler(
+            history_handler_event_rx,
+            history_request_rx,
+            event_log,
+            existing_events,
+            direct_message_log,
+            existing_direct_messages,
+        )
+    });
+
+    thread::spawn(move || {
+        nunitius::server::sequence_handler(event_rx, sequenced_event_tx, last_seq)
     });
 
+    // events go to the history handler first
+    // so that a viewer joining a room as an event arrives
+    // receives it in its history page rather than missing it entirely
     thread::spawn(|| {
         fanout(
-            event_rx,
-            &[viewer_handler_event_tx, history_handler_event_tx],
+            sequenced_event_rx,
+            &[history_handler_event_tx, viewer_handler_event_tx],
         )
     });
 
-    for stream in listener.incoming() {
-        let stream = stream?;
+    let pending_handshakes = Arc::new(Semaphore::new(MAX_PENDING_HANDSHAKES));
+
+    loop {
+        // waiting here rather than turning clients away
+        // lets the handshake timeout make room for them
+        let permit = Arc::clone(&pending_handshakes)
+            .acquire_owned()
+            .await
+            .unwrap();
+
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                // most likely we’ve run out of file descriptors,
+                // so give some connections a chance to close
+                error!("failed to accept connection: {}", e);
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
         let sender_tx = sender_tx.clone();
         let viewer_tx = viewer_tx.clone();
 
-        if let Err(e) = nunitius::server::handle_connection(stream, sender_tx, viewer_tx) {
-            error!("{:#}", e);
-        }
+        // a client that takes its time mustn’t hold up everyone connecting after it
+        tokio::spawn(async move {
+            let _permit = permit;
+
+            if let Err(e) = nunitius::server::handle_connection(
+                socket,
+                acceptor,
+                sender_tx,
+                viewer_tx,
+                handshake_timeout,
+            )
+            .await
+            {
+                error!("{:#}", e);
+            }
+        });
+    }
+}
+
+/// Files in logs written before the server had a file store
+/// have their contents in their events,
+/// which would otherwise be kept in memory and sent to every viewer.
+fn move_contents_into_store(
+    event_log: &mut EventLog,
+    events: &mut [nunitius::Event],
+    files: &FileStore,
+) -> anyhow::Result<()> {
+    if files.take_contents(events)? {
+        info!("moving files out of event log and into file store");
+        event_log.replace_events(events)?;
     }
 
     Ok(())
 }
 
+/// Reads a password from the terminal without echoing it,
+/// or from the first line of stdin if it’s been redirected.
+fn read_new_password() -> anyhow::Result<String> {
+    let stdin = io::stdin();
+
+    if !stdin.is_terminal() {
+        let mut password = String::new();
+        stdin.lock().read_line(&mut password)?;
+        let password = password.trim_end_matches(&['\r', '\n'][..]);
+
+        anyhow::ensure!(!password.is_empty(), "the password can’t be empty");
+        return Ok(password.to_string());
+    }
+
+    let mut stdout = io::stdout();
+
+    loop {
+        let password = prompt::read_password("New password", &mut stdout)?.unwrap_or_default();
+        let confirmation =
+            prompt::read_password("Repeat password", &mut stdout)?.unwrap_or_default();
+
+        if password.is_empty() {
+            eprintln!("The password can’t be empty.");
+        } else if password != confirmation {
+            eprintln!("The passwords don’t match.");
+        } else {
+            return Ok(password);
+        }
+    }
+}
+
 fn fanout<T: Clone>(rx: Receiver<T>, txs: &[Sender<T>]) {
     for t in rx {
         for tx in txs {
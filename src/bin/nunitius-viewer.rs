@@ -1,29 +1,88 @@
+use clap::Parser;
 use crossterm::{cursor, event, queue, terminal};
 use flume::{Selector, Sender};
-use itertools::Itertools;
-use nunitius::viewer::{App, Protocol, RenderedUi};
-use nunitius::{Event as ServerEvent, EventKind as ServerEventKind, TypingEvent};
+use nunitius::config::{self, ConnectionArgs};
+use nunitius::prompt;
+use nunitius::sender::ui;
+use nunitius::viewer::{
+    self, App, Channels, ConnectionEvent, ConnectionState, Downloads, Requester,
+};
+use nunitius::{
+    Credentials, Event as ServerEvent, EventKind as ServerEventKind, TypingEvent, DEFAULT_ROOM,
+};
 use std::cell::RefCell;
 use std::io::{self, Write};
+use std::path::PathBuf;
 use std::thread;
 
+/// Shows the messages sent to a nunitius server.
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Room to show when starting up
+    #[arg(long, env = "NUNITIUS_ROOM")]
+    room: Option<String>,
+
+    /// Nickname to log in with instead of asking for one
+    #[arg(long, env = "NUNITIUS_NICKNAME")]
+    nickname: Option<String>,
+
+    /// Password to log in with instead of asking for one
+    #[arg(long, env = "NUNITIUS_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+
+    /// Directory to save files in
+    #[arg(long, env = "NUNITIUS_DOWNLOAD_DIR")]
+    download_dir: Option<PathBuf>,
+}
+
 fn main() -> anyhow::Result<()> {
-    terminal::enable_raw_mode()?;
+    let args = Args::parse();
+    let (config, connector) = args.connection.resolve()?;
+    let download_dir = config::resolve_download_dir(args.download_dir, &config.client);
+    let room = args
+        .room
+        .or(config.client.room)
+        .unwrap_or_else(|| DEFAULT_ROOM.to_string());
+    let nickname = args.nickname.or(config.client.nickname);
+    let password = args.password.or(config.client.password);
 
     let mut stdout = io::stdout();
-    let protocol = Protocol::connect("127.0.0.1:9999")?;
+    let credentials = read_credentials(nickname, password, &mut stdout)?;
+
+    terminal::enable_raw_mode()?;
 
     let (server_event_tx, server_event_rx) = flume::bounded(100);
     let (event_tx, event_rx) = flume::bounded(100);
+    let (history_page_tx, history_page_rx) = flume::bounded(100);
+    let (rooms_tx, rooms_rx) = flume::bounded(100);
+    let (download_message_tx, download_message_rx) = flume::bounded(100);
+    let (connection_event_tx, connection_event_rx) = flume::bounded(100);
 
-    let protocol = protocol.send_connection_kind(server_event_tx, event_tx)?;
-    let mut protocol = protocol.read_history()?;
+    let channels = Channels {
+        server_event_tx,
+        event_tx,
+        history_page_tx,
+        rooms_tx,
+        download_message_tx,
+    };
+
+    thread::spawn(move || {
+        viewer::maintain_connection(connector, credentials, channels, connection_event_tx)
+    });
 
     let app = {
         let (_, num_terminal_rows) = terminal::size()?;
-        RefCell::new(App::new(num_terminal_rows.into()))
+        RefCell::new(App::new(num_terminal_rows.into(), room))
     };
 
+    // only present while we’re connected
+    let requester: RefCell<Option<Requester>> = RefCell::new(None);
+
+    let downloads = RefCell::new(Downloads::new(download_dir));
+
     let (ui_event_tx, ui_event_rx) = flume::unbounded();
 
     thread::spawn(|| {
@@ -32,19 +91,13 @@ fn main() -> anyhow::Result<()> {
         }
     });
 
-    thread::spawn(move || {
-        if let Err(e) = protocol.read_events() {
-            eprintln!("Error: {:#}", e);
-        }
-    });
-
     loop {
         queue!(
             stdout,
             terminal::Clear(terminal::ClearType::All),
             cursor::MoveTo(0, 0),
         )?;
-        print_rendered_ui(app.borrow().render(), &mut stdout)?;
+        app.borrow().render().print(&mut stdout)?;
         stdout.flush()?;
 
         let control_flow = Selector::new()
@@ -54,10 +107,16 @@ fn main() -> anyhow::Result<()> {
                 if let ServerEvent {
                     event: ServerEventKind::Typing(typing_event),
                     user,
+                    room,
                     ..
                 } = server_event
                 {
                     let mut app = app.borrow_mut();
+
+                    if room != app.room() {
+                        return ControlFlow::Continue;
+                    }
+
                     match typing_event {
                         TypingEvent::Start => app.start_typing(user),
                         TypingEvent::Stop => app.stop_typing(&user),
@@ -70,22 +129,145 @@ fn main() -> anyhow::Result<()> {
                 app.borrow_mut().handle_event(event.unwrap());
                 ControlFlow::Continue
             })
+            .recv(&history_page_rx, |page| {
+                let mut app = app.borrow_mut();
+                let query = app.handle_history_page(page.unwrap());
+
+                if let (Some(query), Some(requester)) = (query, requester.borrow_mut().as_mut()) {
+                    if let Err(e) = requester.fetch_history(app.room().to_string(), query) {
+                        app.set_notice(Some(format!("Error: {:#}", e)));
+                    }
+                }
+
+                ControlFlow::Continue
+            })
+            .recv(&rooms_rx, |rooms| {
+                app.borrow_mut().set_rooms(rooms.unwrap());
+                ControlFlow::Continue
+            })
+            .recv(&download_message_rx, |download_message| {
+                let mut app = app.borrow_mut();
+                let mut downloads = downloads.borrow_mut();
+
+                let download_event = app
+                    .handle_download_message(download_message.unwrap())
+                    .and_then(|download_message| downloads.handle_message(download_message));
+
+                if let Some(download_event) = download_event {
+                    app.set_notice(Some(download_event.to_string()));
+                }
+
+                if let Some(requester) = requester.borrow_mut().as_mut() {
+                    if let Err(e) = downloads.send_requests(requester) {
+                        app.set_notice(Some(format!("Error: {:#}", e)));
+                    }
+                }
+
+                ControlFlow::Continue
+            })
+            .recv(&connection_event_rx, |connection_event| {
+                let mut app = app.borrow_mut();
+
+                match connection_event.unwrap() {
+                    ConnectionEvent::Connected(mut new_requester) => {
+                        app.set_connection_state(ConnectionState::Connected);
+
+                        let result = viewer::rejoin(
+                            &mut app,
+                            &mut downloads.borrow_mut(),
+                            &mut new_requester,
+                        );
+                        match result {
+                            Ok(()) => *requester.borrow_mut() = Some(new_requester),
+                            Err(e) => app.set_notice(Some(format!("Error: {:#}", e))),
+                        }
+                    }
+
+                    ConnectionEvent::Disconnected { retry_in, .. } => {
+                        app.set_connection_state(ConnectionState::Reconnecting { retry_in });
+                        *requester.borrow_mut() = None;
+                    }
+
+                    ConnectionEvent::LoginFailed => return ControlFlow::LoginFailed,
+                }
+
+                ControlFlow::Continue
+            })
             .recv(&ui_event_rx, |ui_event| {
                 let mut app = app.borrow_mut();
+                let mut requester = requester.borrow_mut();
+
+                let result = match ui_event.unwrap() {
+                    UiEvent::Up => {
+                        app.scroll_up();
 
-                match ui_event.unwrap() {
-                    UiEvent::Up => app.scroll_up(),
-                    UiEvent::Down => app.scroll_down(),
-                    UiEvent::Resize { height } => app.resize(height),
+                        match (app.older_history_to_fetch(), requester.as_mut()) {
+                            (Some(before), Some(requester)) => requester.fetch_history_before(
+                                app.room().to_string(),
+                                before,
+                                viewer::HISTORY_PAGE_SIZE,
+                            ),
+                            _ => Ok(()),
+                        }
+                    }
+                    UiEvent::Down => {
+                        app.scroll_down();
+                        Ok(())
+                    }
+                    UiEvent::NextRoom => {
+                        let old_room = app.room().to_string();
+                        viewer::switch_room(old_room, app.next_room(), requester.as_mut())
+                    }
+                    UiEvent::PreviousRoom => {
+                        let old_room = app.room().to_string();
+                        viewer::switch_room(old_room, app.previous_room(), requester.as_mut())
+                    }
+                    UiEvent::SelectOlderFile => {
+                        viewer::select_file(&mut app, App::select_older_file);
+                        Ok(())
+                    }
+                    UiEvent::SelectNewerFile => {
+                        viewer::select_file(&mut app, App::select_newer_file);
+                        Ok(())
+                    }
+                    UiEvent::PreviewFile => {
+                        viewer::preview_selected_file(&mut app, requester.as_mut())
+                    }
+                    UiEvent::SaveFile => viewer::save_selected_file(
+                        &mut app,
+                        &mut downloads.borrow_mut(),
+                        requester.as_mut(),
+                    ),
+                    UiEvent::ClearSelection => {
+                        // a preview is closed before the selection is cleared
+                        if !app.close_preview() {
+                            app.clear_selection();
+                            app.set_notice(None);
+                        }
+                        Ok(())
+                    }
+                    UiEvent::Resize { height } => {
+                        app.resize(height);
+                        Ok(())
+                    }
                     UiEvent::Quit => return ControlFlow::Break,
+                };
+
+                if let Err(e) = result {
+                    app.set_notice(Some(format!("Error: {:#}", e)));
                 }
 
                 ControlFlow::Continue
             })
             .wait();
 
-        if let ControlFlow::Break = control_flow {
-            break;
+        match control_flow {
+            ControlFlow::Continue => {}
+            ControlFlow::Break => break,
+            ControlFlow::LoginFailed => {
+                terminal::disable_raw_mode()?;
+                anyhow::bail!("incorrect nickname or password");
+            }
         }
     }
 
@@ -97,11 +279,41 @@ fn main() -> anyhow::Result<()> {
 enum ControlFlow {
     Continue,
     Break,
+    LoginFailed,
+}
+
+fn read_credentials(
+    nickname: Option<String>,
+    password: Option<String>,
+    stdout: &mut io::Stdout,
+) -> anyhow::Result<Credentials> {
+    let nickname = match nickname {
+        Some(nickname) => nickname,
+        None => loop {
+            if let Some(nickname) = ui::read_input("Nickname", stdout)? {
+                break nickname;
+            }
+        },
+    };
+
+    let password = match password {
+        Some(password) => password,
+        None => prompt::read_password("Password", stdout)?.unwrap_or_default(),
+    };
+
+    Ok(Credentials { nickname, password })
 }
 
 enum UiEvent {
     Up,
     Down,
+    NextRoom,
+    PreviousRoom,
+    SelectOlderFile,
+    SelectNewerFile,
+    PreviewFile,
+    SaveFile,
+    ClearSelection,
     Resize { height: usize },
     Quit,
 }
@@ -116,8 +328,23 @@ fn listen_for_ui_events(ui_event_tx: Sender<UiEvent>) -> anyhow::Result<()> {
                     ui_event_tx.send(UiEvent::Quit).unwrap();
                     break;
                 }
+                (event::KeyCode::Up, event::KeyModifiers::ALT) => {
+                    ui_event_tx.send(UiEvent::SelectOlderFile).unwrap()
+                }
+                (event::KeyCode::Down, event::KeyModifiers::ALT) => {
+                    ui_event_tx.send(UiEvent::SelectNewerFile).unwrap()
+                }
+                (event::KeyCode::Char('v'), event::KeyModifiers::CONTROL) => {
+                    ui_event_tx.send(UiEvent::PreviewFile).unwrap()
+                }
+                (event::KeyCode::Char('s'), event::KeyModifiers::CONTROL) => {
+                    ui_event_tx.send(UiEvent::SaveFile).unwrap()
+                }
+                (event::KeyCode::Esc, _) => ui_event_tx.send(UiEvent::ClearSelection).unwrap(),
                 (event::KeyCode::Up, _) => ui_event_tx.send(UiEvent::Up).unwrap(),
                 (event::KeyCode::Down, _) => ui_event_tx.send(UiEvent::Down).unwrap(),
+                (event::KeyCode::Tab, _) => ui_event_tx.send(UiEvent::NextRoom).unwrap(),
+                (event::KeyCode::BackTab, _) => ui_event_tx.send(UiEvent::PreviousRoom).unwrap(),
                 _ => {}
             },
 
@@ -132,11 +359,3 @@ fn listen_for_ui_events(ui_event_tx: Sender<UiEvent>) -> anyhow::Result<()> {
 
     Ok(())
 }
-
-fn print_rendered_ui(rendered: RenderedUi, stdout: &mut io::Stdout) -> anyhow::Result<()> {
-    for line in Itertools::intersperse(rendered.lines(), "\r\n") {
-        write!(stdout, "{}", line)?;
-    }
-
-    Ok(())
-}
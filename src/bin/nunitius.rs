@@ -0,0 +1,664 @@
+use clap::Parser;
+use crossterm::{cursor, event, queue, terminal};
+use flume::{Selector, Sender};
+use nunitius::config::{self, ConnectionArgs};
+use nunitius::prompt;
+use nunitius::sender::connection::{self as sender_connection, ServerConnection};
+use nunitius::sender::edit_buffer::EditBuffer;
+use nunitius::sender::input_history::InputHistory;
+use nunitius::sender::ui::{self, TypingDetector};
+use nunitius::sender::upload::Upload;
+use nunitius::viewer::{
+    self, App, Channels, ConnectionEvent, ConnectionState, Downloads, Requester,
+};
+use nunitius::{
+    Color, Credentials, Event as ServerEvent, EventKind as ServerEventKind, Message, SenderEvent,
+    SenderMessage, TypingEvent, DEFAULT_ROOM,
+};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::{mem, thread};
+
+/// Chats on a nunitius server,
+/// showing the conversation above a line to type your own messages in.
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Room to show when starting up
+    #[arg(long, env = "NUNITIUS_ROOM")]
+    room: Option<String>,
+
+    /// Nickname to log in with instead of asking for one
+    #[arg(long, env = "NUNITIUS_NICKNAME")]
+    nickname: Option<String>,
+
+    /// Password to log in with instead of asking for one
+    #[arg(long, env = "NUNITIUS_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+
+    /// Color of your nickname (red, green, yellow, blue, magenta or cyan)
+    #[arg(long, env = "NUNITIUS_COLOR")]
+    color: Option<Color>,
+
+    /// Directory to save files in
+    #[arg(long, env = "NUNITIUS_DOWNLOAD_DIR")]
+    download_dir: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let (config, connector) = args.connection.resolve()?;
+    let room = args
+        .room
+        .or(config.client.room.clone())
+        .unwrap_or_else(|| DEFAULT_ROOM.to_string());
+    let nickname = args.nickname.or(config.client.nickname.clone());
+    let password = args.password.or(config.client.password.clone());
+    let color = config::resolve_color(args.color, &config.client)?;
+    let download_dir = config::resolve_download_dir(args.download_dir, &config.client);
+
+    let mut stdout = io::stdout();
+    let mut stderr = io::stderr();
+
+    // under the hood we’re a sender and a viewer at once;
+    // sending is the one that needs a nickname nobody else is using,
+    // so we log in as a sender first and reuse what worked for viewing
+    let mut connection = ServerConnection::connect(&connector)?;
+    let (user, password) = ui::log_in(
+        &mut connection,
+        nickname,
+        password,
+        color,
+        &mut stdout,
+        &mut stderr,
+    )?;
+    let credentials = Credentials {
+        nickname: user.nickname.clone(),
+        password: password.clone(),
+    };
+
+    let (sender_event_tx, sender_event_rx) = flume::bounded(100);
+    let (upload_tx, upload_rx) = flume::bounded(100);
+    let (sender_message_tx, sender_message_rx) = flume::bounded(100);
+    let (sender_connection_event_tx, sender_connection_event_rx) = flume::bounded(100);
+
+    let (server_event_tx, server_event_rx) = flume::bounded(100);
+    let (event_tx, event_rx) = flume::bounded(100);
+    let (history_page_tx, history_page_rx) = flume::bounded(100);
+    let (rooms_tx, rooms_rx) = flume::bounded(100);
+    let (download_message_tx, download_message_rx) = flume::bounded(100);
+    let (connection_event_tx, connection_event_rx) = flume::bounded(100);
+
+    let channels = Channels {
+        server_event_tx,
+        event_tx,
+        history_page_tx,
+        rooms_tx,
+        download_message_tx,
+    };
+
+    thread::spawn({
+        let connector = connector.clone();
+        let user = user.clone();
+
+        move || {
+            sender_connection::maintain_connection(
+                connector,
+                user,
+                password,
+                connection,
+                sender_connection::Channels {
+                    sender_event_rx,
+                    upload_rx,
+                    sender_message_tx,
+                },
+                sender_connection_event_tx,
+            )
+        }
+    });
+
+    thread::spawn(move || {
+        viewer::maintain_connection(connector, credentials, channels, connection_event_tx)
+    });
+
+    // messages go to whichever room we’re looking at
+    if room != DEFAULT_ROOM {
+        sender_event_tx
+            .send(SenderEvent::JoinRoom { room: room.clone() })
+            .unwrap();
+    }
+
+    prompt::enter_raw_mode(&mut stdout)?;
+
+    // the bottom row of the terminal is for typing in
+    let app = {
+        let (_, num_terminal_rows) = terminal::size()?;
+        RefCell::new(App::new(
+            usize::from(num_terminal_rows).saturating_sub(1),
+            room,
+        ))
+    };
+
+    // only present while we’re connected
+    let requester: RefCell<Option<Requester>> = RefCell::new(None);
+
+    let downloads = RefCell::new(Downloads::new(download_dir));
+
+    let input = RefCell::new(Input {
+        history: InputHistory::open_default_or_warn(),
+        ..Input::default()
+    });
+
+    let outbox = Outbox {
+        sender_event_tx,
+        upload_tx,
+    };
+
+    let (ui_event_tx, ui_event_rx) = flume::unbounded();
+
+    thread::spawn(|| {
+        if let Err(e) = listen_for_ui_events(ui_event_tx) {
+            eprintln!("Error: {:#}", e);
+        }
+    });
+
+    loop {
+        queue!(
+            stdout,
+            terminal::Clear(terminal::ClearType::All),
+            cursor::MoveTo(0, 0),
+        )?;
+        app.borrow().render().print(&mut stdout)?;
+        print_input(&input.borrow(), app.borrow().room(), &mut stdout)?;
+        stdout.flush()?;
+
+        let control_flow = Selector::new()
+            .recv(&server_event_rx, |server_event| {
+                let server_event = server_event.unwrap();
+
+                if let ServerEvent {
+                    event: ServerEventKind::Typing(typing_event),
+                    user: typing_user,
+                    room,
+                    ..
+                } = server_event
+                {
+                    let mut app = app.borrow_mut();
+
+                    // we know when we’re typing
+                    if room != app.room() || typing_user.nickname == user.nickname {
+                        return ControlFlow::Continue;
+                    }
+
+                    match typing_event {
+                        TypingEvent::Start => app.start_typing(typing_user),
+                        TypingEvent::Stop => app.stop_typing(&typing_user),
+                    }
+                }
+
+                ControlFlow::Continue
+            })
+            .recv(&event_rx, |event| {
+                app.borrow_mut().handle_event(event.unwrap());
+                ControlFlow::Continue
+            })
+            .recv(&history_page_rx, |page| {
+                let mut app = app.borrow_mut();
+                let query = app.handle_history_page(page.unwrap());
+
+                if let (Some(query), Some(requester)) = (query, requester.borrow_mut().as_mut()) {
+                    if let Err(e) = requester.fetch_history(app.room().to_string(), query) {
+                        app.set_notice(Some(format!("Error: {:#}", e)));
+                    }
+                }
+
+                ControlFlow::Continue
+            })
+            .recv(&rooms_rx, |rooms| {
+                app.borrow_mut().set_rooms(rooms.unwrap());
+                ControlFlow::Continue
+            })
+            .recv(&download_message_rx, |download_message| {
+                let mut app = app.borrow_mut();
+                let mut downloads = downloads.borrow_mut();
+
+                let download_event = app
+                    .handle_download_message(download_message.unwrap())
+                    .and_then(|download_message| downloads.handle_message(download_message));
+
+                if let Some(download_event) = download_event {
+                    app.set_notice(Some(download_event.to_string()));
+                }
+
+                if let Some(requester) = requester.borrow_mut().as_mut() {
+                    if let Err(e) = downloads.send_requests(requester) {
+                        app.set_notice(Some(format!("Error: {:#}", e)));
+                    }
+                }
+
+                ControlFlow::Continue
+            })
+            .recv(&connection_event_rx, |connection_event| {
+                let mut app = app.borrow_mut();
+
+                match connection_event.unwrap() {
+                    ConnectionEvent::Connected(mut new_requester) => {
+                        app.set_connection_state(ConnectionState::Connected);
+
+                        let result = viewer::rejoin(
+                            &mut app,
+                            &mut downloads.borrow_mut(),
+                            &mut new_requester,
+                        );
+                        match result {
+                            Ok(()) => *requester.borrow_mut() = Some(new_requester),
+                            Err(e) => app.set_notice(Some(format!("Error: {:#}", e))),
+                        }
+                    }
+
+                    ConnectionEvent::Disconnected { retry_in, .. } => {
+                        app.set_connection_state(ConnectionState::Reconnecting { retry_in });
+                        *requester.borrow_mut() = None;
+                    }
+
+                    ConnectionEvent::LoginFailed => return ControlFlow::LoginFailed,
+                }
+
+                ControlFlow::Continue
+            })
+            .recv(&sender_message_rx, |sender_message| {
+                let mut app = app.borrow_mut();
+
+                match sender_message.unwrap() {
+                    SenderMessage::Rooms(rooms) => app.set_rooms(rooms),
+                    SenderMessage::NoSuchUser { nickname } => {
+                        app.set_notice(Some(format!("‘{}’ is not logged in.", nickname)))
+                    }
+                    // we never ask to change nickname,
+                    // and the connection deals with uploads and heartbeats itself,
+                    // so there’s nothing to show if the server sends these anyway
+                    SenderMessage::NicknameChange { .. }
+                    | SenderMessage::UploadProgress { .. }
+                    | SenderMessage::UploadFinished { .. }
+                    | SenderMessage::UploadFailed { .. }
+                    | SenderMessage::Heartbeat => {}
+                }
+
+                ControlFlow::Continue
+            })
+            .recv(&sender_connection_event_rx, |connection_event| {
+                let notice = match connection_event.unwrap() {
+                    sender_connection::ConnectionEvent::Reconnected => "Reconnected.".to_string(),
+                    sender_connection::ConnectionEvent::Disconnected { error, retry_in } => {
+                        format!(
+                            "Can’t send messages ({:#}), reconnecting in {}s…",
+                            error,
+                            retry_in.as_secs_f32().ceil()
+                        )
+                    }
+                    sender_connection::ConnectionEvent::UploadProgress { filename, percent } => {
+                        format!("Uploading {}… {}%", filename, percent)
+                    }
+                    sender_connection::ConnectionEvent::UploadFinished { filename } => {
+                        format!("Uploaded {}.", filename)
+                    }
+                    sender_connection::ConnectionEvent::UploadFailed { filename, reason } => {
+                        format!("Couldn’t upload {} ({}).", filename, reason)
+                    }
+                };
+                app.borrow_mut().set_notice(Some(notice));
+
+                ControlFlow::Continue
+            })
+            .recv(&ui_event_rx, |ui_event| {
+                let mut app = app.borrow_mut();
+
+                match ui_event.unwrap() {
+                    UiEvent::Key { code, modifiers } => {
+                        let result = handle_key(
+                            code,
+                            modifiers,
+                            &mut input.borrow_mut(),
+                            &mut app,
+                            &mut downloads.borrow_mut(),
+                            requester.borrow_mut().as_mut(),
+                            &outbox,
+                        );
+
+                        match result {
+                            Ok(control_flow) => return control_flow,
+                            Err(e) => app.set_notice(Some(format!("Error: {:#}", e))),
+                        }
+                    }
+
+                    UiEvent::Paste(text) => {
+                        let mut input = input.borrow_mut();
+                        input.history.stop_searching();
+                        input.edit_buffer.paste(&text);
+                        report_key_press(&mut input, &outbox.sender_event_tx);
+                    }
+
+                    UiEvent::Resize { height } => app.resize(height.saturating_sub(1)),
+                }
+
+                ControlFlow::Continue
+            })
+            .wait();
+
+        match control_flow {
+            ControlFlow::Continue => {}
+            ControlFlow::Break => break,
+            ControlFlow::LoginFailed => {
+                prompt::leave_raw_mode(&mut stdout)?;
+                anyhow::bail!("incorrect nickname or password");
+            }
+        }
+    }
+
+    prompt::leave_raw_mode(&mut stdout)?;
+
+    Ok(())
+}
+
+enum ControlFlow {
+    Continue,
+    Break,
+    LoginFailed,
+}
+
+/// What the input line is being used for.
+#[derive(Default)]
+enum Prompt {
+    #[default]
+    Message,
+    FileToUpload,
+    Room,
+    DirectMessageRecipient,
+    DirectMessage {
+        to: String,
+    },
+}
+
+/// Where everything we send goes.
+struct Outbox {
+    sender_event_tx: Sender<SenderEvent>,
+    upload_tx: Sender<Upload>,
+}
+
+#[derive(Default)]
+struct Input {
+    prompt: Prompt,
+    edit_buffer: EditBuffer,
+    // of messages, since other prompts are rarely answered the same way twice
+    history: InputHistory,
+    // only present while a message is being typed
+    typing_detector: Option<TypingDetector>,
+}
+
+impl Input {
+    fn set_prompt(&mut self, prompt: Prompt) {
+        self.prompt = prompt;
+        self.edit_buffer.take();
+        self.history.reset();
+        self.stop_typing();
+    }
+
+    fn stop_typing(&mut self) {
+        if let Some(typing_detector) = self.typing_detector.take() {
+            typing_detector.finish();
+        }
+    }
+}
+
+fn handle_key(
+    code: event::KeyCode,
+    modifiers: event::KeyModifiers,
+    input: &mut Input,
+    app: &mut App,
+    downloads: &mut Downloads,
+    requester: Option<&mut Requester>,
+    outbox: &Outbox,
+) -> anyhow::Result<ControlFlow> {
+    // this comes first so that searching the history gets every key it needs
+    if let Prompt::Message = input.prompt {
+        if input
+            .history
+            .handle_key(&mut input.edit_buffer, code, modifiers)
+        {
+            return Ok(ControlFlow::Continue);
+        }
+    }
+
+    match (code, modifiers) {
+        (event::KeyCode::Char('c'), event::KeyModifiers::CONTROL) => return Ok(ControlFlow::Break),
+
+        (event::KeyCode::Char('u'), event::KeyModifiers::CONTROL) => {
+            input.set_prompt(Prompt::FileToUpload)
+        }
+        (event::KeyCode::Char('o'), event::KeyModifiers::CONTROL) => input.set_prompt(Prompt::Room),
+        (event::KeyCode::Char('p'), event::KeyModifiers::CONTROL) => {
+            input.set_prompt(Prompt::DirectMessageRecipient)
+        }
+        (event::KeyCode::Char('l'), event::KeyModifiers::CONTROL) => {
+            if let Some(requester) = requester {
+                requester.list_rooms()?;
+            }
+        }
+        (event::KeyCode::Esc, _) => {
+            input.set_prompt(Prompt::Message);
+
+            // a preview is closed before the selection is cleared
+            if !app.close_preview() {
+                app.clear_selection();
+            }
+        }
+
+        (event::KeyCode::Up, event::KeyModifiers::ALT) => {
+            viewer::select_file(app, App::select_older_file)
+        }
+        (event::KeyCode::Down, event::KeyModifiers::ALT) => {
+            viewer::select_file(app, App::select_newer_file)
+        }
+        (event::KeyCode::Char('v'), event::KeyModifiers::CONTROL) => {
+            viewer::preview_selected_file(app, requester)?
+        }
+        (event::KeyCode::Char('s'), event::KeyModifiers::CONTROL) => {
+            viewer::save_selected_file(app, downloads, requester)?
+        }
+
+        (event::KeyCode::PageUp, _) => {
+            app.scroll_up();
+
+            if let (Some(before), Some(requester)) = (app.older_history_to_fetch(), requester) {
+                requester.fetch_history_before(
+                    app.room().to_string(),
+                    before,
+                    viewer::HISTORY_PAGE_SIZE,
+                )?;
+            }
+        }
+        (event::KeyCode::PageDown, _) => app.scroll_down(),
+        (event::KeyCode::Tab, _) => {
+            let old_room = app.room().to_string();
+            switch_room(
+                old_room,
+                app.next_room(),
+                requester,
+                &outbox.sender_event_tx,
+            )?;
+        }
+        (event::KeyCode::BackTab, _) => {
+            let old_room = app.room().to_string();
+            switch_room(
+                old_room,
+                app.previous_room(),
+                requester,
+                &outbox.sender_event_tx,
+            )?;
+        }
+
+        // with shift or alt it starts a new line instead
+        (event::KeyCode::Enter, event::KeyModifiers::NONE) => {
+            submit(input, app, requester, outbox)?
+        }
+
+        _ => {
+            if input.edit_buffer.handle_key(code, modifiers) {
+                report_key_press(input, &outbox.sender_event_tx);
+            }
+        }
+    }
+
+    Ok(ControlFlow::Continue)
+}
+
+/// Lets everyone else know we’re typing a message,
+/// but not when we’re typing anything else.
+fn report_key_press(input: &mut Input, sender_event_tx: &Sender<SenderEvent>) {
+    if let Prompt::Message = input.prompt {
+        input
+            .typing_detector
+            .get_or_insert_with(|| TypingDetector::spawn(sender_event_tx.clone()))
+            .key_pressed();
+    }
+}
+
+fn submit(
+    input: &mut Input,
+    app: &mut App,
+    requester: Option<&mut Requester>,
+    outbox: &Outbox,
+) -> anyhow::Result<()> {
+    let line = input.edit_buffer.take();
+    input.stop_typing();
+    app.set_notice(None);
+
+    match mem::take(&mut input.prompt) {
+        Prompt::Message => match line {
+            Some(body) => {
+                outbox
+                    .sender_event_tx
+                    .send(SenderEvent::Message(Message::Text { body: body.clone() }))
+                    .unwrap();
+                input.history.add(&body)?;
+            }
+            None => input.history.reset(),
+        },
+
+        Prompt::FileToUpload => {
+            if let Some(path) = line {
+                match Upload::open(Path::new(&path)) {
+                    Ok(upload) => outbox.upload_tx.send(upload).unwrap(),
+                    Err(e) => app.set_notice(Some(format!("Error: {:#}", e))),
+                }
+            }
+        }
+
+        Prompt::Room => {
+            let new_room = match line {
+                Some(new_room) => new_room.trim_start_matches('#').to_string(),
+                None => DEFAULT_ROOM.to_string(),
+            };
+
+            let old_room = app.room().to_string();
+            switch_room(
+                old_room,
+                app.join_room(new_room),
+                requester,
+                &outbox.sender_event_tx,
+            )?;
+        }
+
+        Prompt::DirectMessageRecipient => {
+            if let Some(to) = line {
+                input.prompt = Prompt::DirectMessage { to };
+            }
+        }
+
+        Prompt::DirectMessage { to } => {
+            if let Some(body) = line {
+                outbox
+                    .sender_event_tx
+                    .send(SenderEvent::DirectMessage {
+                        to,
+                        message: Message::Text { body },
+                    })
+                    .unwrap();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn switch_room(
+    old_room: String,
+    new_room: Option<String>,
+    requester: Option<&mut Requester>,
+    sender_event_tx: &Sender<SenderEvent>,
+) -> anyhow::Result<()> {
+    let new_room = match new_room {
+        Some(new_room) => new_room,
+        None => return Ok(()),
+    };
+
+    sender_event_tx
+        .send(SenderEvent::JoinRoom {
+            room: new_room.clone(),
+        })
+        .unwrap();
+
+    viewer::switch_room(old_room, Some(new_room), requester)
+}
+
+enum UiEvent {
+    Key {
+        code: event::KeyCode,
+        modifiers: event::KeyModifiers,
+    },
+    Paste(String),
+    Resize {
+        height: usize,
+    },
+}
+
+fn listen_for_ui_events(ui_event_tx: Sender<UiEvent>) -> anyhow::Result<()> {
+    loop {
+        let ui_event = match event::read()? {
+            event::Event::Key(event::KeyEvent {
+                code, modifiers, ..
+            }) => UiEvent::Key { code, modifiers },
+            event::Event::Paste(text) => UiEvent::Paste(text),
+            event::Event::Resize(_, height) => UiEvent::Resize {
+                height: usize::from(height),
+            },
+            _ => continue,
+        };
+
+        if ui_event_tx.send(ui_event).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn print_input(input: &Input, room: &str, stdout: &mut io::Stdout) -> anyhow::Result<()> {
+    let prompt = match input.prompt {
+        Prompt::Message => match input.history.search_prompt() {
+            Some(search_prompt) => search_prompt,
+            None => format!("Type a message in #{}", room),
+        },
+        Prompt::FileToUpload => "Choose a file to upload".to_string(),
+        Prompt::Room => format!(
+            "Choose a room to join (leave empty to return to #{})",
+            DEFAULT_ROOM
+        ),
+        Prompt::DirectMessageRecipient => "Choose who to message privately".to_string(),
+        Prompt::DirectMessage { ref to } => format!("Type a private message to {}", to),
+    };
+
+    write!(stdout, "\r\n")?;
+    ui::print_line(&prompt, &input.edit_buffer, stdout)
+}
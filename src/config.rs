@@ -0,0 +1,295 @@
+//! Settings shared by all the binaries.
+//!
+//! Each setting is taken from the command line if given there,
+//! then from its environment variable,
+//! then from the config file,
+//! and finally falls back to a default.
+
+use crate::stream::Connector;
+use crate::{tls, Color};
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 9999;
+pub const DEFAULT_HEARTBEAT_INTERVAL: u64 = 10;
+pub const DEFAULT_HEARTBEAT_TIMEOUT: u64 = 30;
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub client: ClientConfig,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub history: Option<PathBuf>,
+    pub direct_messages: Option<PathBuf>,
+    pub log_level: Option<log::LevelFilter>,
+    pub log_file: Option<PathBuf>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub accounts: Option<PathBuf>,
+    pub grace_period: Option<u64>,
+    pub handshake_timeout: Option<u64>,
+    pub heartbeat_interval: Option<u64>,
+    pub heartbeat_timeout: Option<u64>,
+    pub max_upload_size: Option<u64>,
+    pub files: Option<PathBuf>,
+    pub fsync: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub nickname: Option<String>,
+    pub password: Option<String>,
+    pub color: Option<String>,
+    pub room: Option<String>,
+    pub tls: Option<bool>,
+    pub ca: Option<PathBuf>,
+    pub heartbeat_interval: Option<u64>,
+    pub heartbeat_timeout: Option<u64>,
+    pub download_dir: Option<PathBuf>,
+}
+
+impl Config {
+    /// Reads the config file at `path`,
+    /// or the one in the user’s config directory if no path is given.
+    ///
+    /// It’s fine for the latter not to exist.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let (path, must_exist) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !must_exist => {
+                return Ok(Self::default())
+            }
+            Err(e) => {
+                return Err(anyhow::Error::new(e)
+                    .context(format!("failed to read config file {}", path.display())))
+            }
+        };
+
+        Self::parse(&contents)
+            .map_err(|e| e.context(format!("invalid config file {}", path.display())))
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("nunitius").join("config.toml"))
+}
+
+/// How the clients find the server.
+#[derive(Debug, clap::Args)]
+pub struct ConnectionArgs {
+    /// Config file to use instead of the one in your config directory
+    #[arg(long, env = "NUNITIUS_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Host name or IP address of the server
+    #[arg(long, env = "NUNITIUS_ADDRESS")]
+    pub address: Option<String>,
+
+    /// Port the server is listening on
+    #[arg(long, env = "NUNITIUS_PORT")]
+    pub port: Option<u16>,
+
+    /// Connect to the server using TLS
+    #[arg(long, env = "NUNITIUS_TLS")]
+    pub tls: bool,
+
+    /// Certificate authority to trust instead of the usual public ones;
+    /// implies --tls
+    #[arg(long, env = "NUNITIUS_CA")]
+    pub ca: Option<PathBuf>,
+
+    /// Seconds of quiet after which to remind the server we’re still here
+    #[arg(long, env = "NUNITIUS_HEARTBEAT_INTERVAL")]
+    pub heartbeat_interval: Option<u64>,
+
+    /// Seconds without hearing from the server before reconnecting
+    #[arg(long, env = "NUNITIUS_HEARTBEAT_TIMEOUT")]
+    pub heartbeat_timeout: Option<u64>,
+}
+
+impl ConnectionArgs {
+    /// Loads the config file and works out how to reach the server.
+    pub fn resolve(self) -> anyhow::Result<(Config, Connector)> {
+        let config = Config::load(self.config.as_deref())?;
+
+        let host = self
+            .address
+            .or_else(|| config.client.address.clone())
+            .unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+        let port = self.port.or(config.client.port).unwrap_or(DEFAULT_PORT);
+
+        let ca = self.ca.or_else(|| config.client.ca.clone());
+        let use_tls = self.tls || config.client.tls.unwrap_or(false) || ca.is_some();
+        let tls = if use_tls {
+            Some(tls::client_config(ca.as_deref())?)
+        } else {
+            None
+        };
+
+        let heartbeat = Heartbeat::resolve(
+            self.heartbeat_interval.or(config.client.heartbeat_interval),
+            self.heartbeat_timeout.or(config.client.heartbeat_timeout),
+        )?;
+
+        Ok((
+            config,
+            Connector {
+                host,
+                port,
+                tls,
+                heartbeat,
+            },
+        ))
+    }
+}
+
+/// How often each end of a connection sends a heartbeat
+/// when it has nothing else to send,
+/// and how long it waits to hear anything from the other end
+/// before deciding the connection is dead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Heartbeat {
+    /// Takes both settings in seconds, using the defaults for any that are missing.
+    pub fn resolve(interval: Option<u64>, timeout: Option<u64>) -> anyhow::Result<Self> {
+        let interval = interval.unwrap_or(DEFAULT_HEARTBEAT_INTERVAL);
+        let timeout = timeout.unwrap_or(DEFAULT_HEARTBEAT_TIMEOUT);
+
+        anyhow::ensure!(interval > 0, "the heartbeat interval can’t be zero");
+        // otherwise a connection could time out
+        // while the other end is just waiting to send its next heartbeat
+        anyhow::ensure!(
+            interval < timeout,
+            "the heartbeat timeout must be longer than the heartbeat interval"
+        );
+
+        Ok(Self {
+            interval: Duration::from_secs(interval),
+            timeout: Duration::from_secs(timeout),
+        })
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(DEFAULT_HEARTBEAT_INTERVAL),
+            timeout: Duration::from_secs(DEFAULT_HEARTBEAT_TIMEOUT),
+        }
+    }
+}
+
+/// The color from the command line or, failing that, the config file.
+pub fn resolve_color(color: Option<Color>, config: &ClientConfig) -> anyhow::Result<Option<Color>> {
+    match (color, &config.color) {
+        (Some(color), _) => Ok(Some(color)),
+        (None, Some(color)) => Ok(Some(color.parse()?)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Saved files go in the user’s downloads directory unless told otherwise,
+/// or the current directory if they don’t have one.
+pub fn resolve_download_dir(download_dir: Option<PathBuf>, config: &ClientConfig) -> PathBuf {
+    download_dir
+        .or_else(|| config.download_dir.clone())
+        .or_else(dirs::download_dir)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn parse_full_config() {
+        let config = Config::parse(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 1234
+            history = "history.jsonl"
+            log_level = "warn"
+            fsync = "never"
+
+            [client]
+            address = "chat.example.com"
+            nickname = "luna"
+            color = "cyan"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.address.as_deref(), Some("0.0.0.0"));
+        assert_eq!(config.server.port, Some(1234));
+        assert_eq!(
+            config.server.history.as_deref(),
+            Some(Path::new("history.jsonl"))
+        );
+        assert_eq!(config.server.log_level, Some(log::LevelFilter::Warn));
+        assert_eq!(config.server.fsync.as_deref(), Some("never"));
+        assert_eq!(config.client.address.as_deref(), Some("chat.example.com"));
+        assert_eq!(config.client.port, None);
+        assert_eq!(
+            resolve_color(None, &config.client).unwrap(),
+            Some(Color::Cyan)
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(Config::parse("[client]\nnick = \"luna\"").is_err());
+    }
+
+    #[test]
+    fn heartbeat_timeout_must_exceed_interval() {
+        assert_eq!(
+            Heartbeat::resolve(None, None).unwrap(),
+            Heartbeat::default()
+        );
+        assert!(Heartbeat::resolve(Some(5), Some(15)).is_ok());
+        assert!(Heartbeat::resolve(Some(30), Some(30)).is_err());
+        assert!(Heartbeat::resolve(Some(0), None).is_err());
+    }
+
+    #[test]
+    fn missing_explicit_config_file_is_an_error() {
+        let path = std::env::temp_dir().join("nunitius-config-that-does-not-exist.toml");
+        assert!(Config::load(Some(&path)).is_err());
+    }
+}
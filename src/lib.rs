@@ -1,29 +1,166 @@
+pub mod backoff;
+pub mod config;
+pub mod prompt;
 pub mod sender;
 pub mod server;
+pub mod stream;
+pub mod tls;
 pub mod viewer;
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// The version of the protocol spoken by this build of the clients and server.
+///
+/// It only changes when old clients and servers can no longer understand new ones;
+/// smaller additions are made through [`Capability`] instead.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The room every user is in when they first log in.
+pub const DEFAULT_ROOM: &str = "general";
+
+fn default_room() -> String {
+    DEFAULT_ROOM.to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Event {
+    /// Stamped by the server before the event is sent anywhere,
+    /// and strictly increasing in the order events are sent to viewers.
+    #[serde(default)]
+    pub seq: u64,
+    /// Stamped by the server alongside `seq`.
+    #[serde(default = "Uuid::nil")]
+    pub id: Uuid,
     pub event: EventKind,
     pub user: User,
+    // events from before rooms existed were all in the default room
+    #[serde(default = "default_room")]
+    pub room: String,
     pub time_occurred: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum EventKind {
     Message(Message),
     Login,
     Logout,
     Typing(TypingEvent),
+    JoinRoom,
+    LeaveRoom,
+    /// A message only visible to its sender and the user it was sent to.
+    DirectMessage {
+        to: String,
+        message: Message,
+    },
+    /// The user was known as `old_nickname` until now.
+    NicknameChange {
+        old_nickname: String,
+    },
+}
+
+impl EventKind {
+    pub fn is_direct_message(&self) -> bool {
+        matches!(self, Self::DirectMessage { .. })
+    }
+
+    /// What a viewer has to support to be sent this event.
+    pub fn required_capability(&self) -> Option<Capability> {
+        match self {
+            Self::Typing(_) => Some(Capability::Typing),
+            // viewers that support actions support direct messages too,
+            // since they came first
+            Self::Message(Message::Action { .. })
+            | Self::DirectMessage {
+                message: Message::Action { .. },
+                ..
+            } => Some(Capability::Actions),
+            Self::DirectMessage { .. } => Some(Capability::DirectMessages),
+            Self::NicknameChange { .. } => Some(Capability::NicknameChanges),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SenderEvent {
     Message(Message),
     Typing(TypingEvent),
+    DirectMessage {
+        to: String,
+        message: Message,
+    },
+    /// Moves the sender into a different room.
+    JoinRoom {
+        room: String,
+    },
+    /// Moves the sender back into the default room.
+    LeaveRoom,
+    ListRooms,
+    /// Switches to the account with this nickname,
+    /// which the server answers with [`SenderMessage::NicknameChange`].
+    ChangeNickname {
+        nickname: String,
+        password: String,
+    },
+    ChangeColor {
+        color: Option<Color>,
+    },
+    /// Starts sending a file in chunks,
+    /// which the server answers with [`SenderMessage::UploadProgress`]
+    /// once it’s ready for them.
+    ///
+    /// Only one file can be uploaded at a time,
+    /// so starting another upload abandons the last one.
+    StartUpload {
+        id: Uuid,
+        metadata: FileMetadata,
+    },
+    /// Part of a file being uploaded,
+    /// which has to start where the last chunk ended.
+    UploadChunk {
+        id: Uuid,
+        offset: u64,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// Sent when the sender has had nothing else to send for a while,
+    /// so the server can tell it apart from a dead connection.
+    Heartbeat,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SenderMessage {
+    Rooms(Vec<String>),
+    /// A direct message was sent to someone who isn’t logged in.
+    NoSuchUser {
+        nickname: String,
+    },
+    /// The answer to [`SenderEvent::ChangeNickname`],
+    /// where [`LoginResponse::LoggedIn`] means the nickname was changed.
+    NicknameChange {
+        nickname: String,
+        response: LoginResponse,
+    },
+    /// How much of an upload the server has received,
+    /// sent when it starts and after every chunk.
+    UploadProgress {
+        id: Uuid,
+        received: u64,
+    },
+    /// The whole file arrived and has been sent as a message.
+    UploadFinished {
+        id: Uuid,
+    },
+    /// The server has given up on an upload, for a reason meant for the user.
+    UploadFailed {
+        id: Uuid,
+        reason: String,
+    },
+    /// Sent when the server has had nothing else to send for a while.
+    Heartbeat,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -34,13 +171,80 @@ pub enum TypingEvent {
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Message {
-    Text { body: String },
-    File { contents: Vec<u8> },
+    Text {
+        body: String,
+    },
+    /// Something the user is doing, written in the third person (`/me waves`).
+    Action {
+        body: String,
+    },
+    /// A file kept by the server,
+    /// which viewers download with [`ViewerRequest::Download`].
+    File {
+        // files sent before this was added only have their contents
+        #[serde(default)]
+        metadata: Option<FileMetadata>,
+        // only files sent before the server stored them have their contents here
+        #[serde(default)]
+        contents: Vec<u8>,
+    },
+}
+
+/// Writes bytes as base64 rather than serde’s default array of numbers,
+/// which takes up around four times as much space in JSON.
+mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+/// What a viewer can show about a file without looking inside it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    /// The file’s name on the sender’s computer, without the directory it was in.
+    pub filename: String,
+    pub size: u64,
+    pub mime_type: String,
+    /// The SHA-256 hash of the contents, in lowercase hex.
+    pub sha256: String,
+}
+
+impl FileMetadata {
+    /// Whether `sha256` really is a hash,
+    /// which has to be checked before it’s used to name anything on disk
+    /// since it comes from whoever sent the file.
+    pub fn has_valid_hash(&self) -> bool {
+        is_sha256(&self.sha256)
+    }
+}
+
+/// Whether `s` looks like a SHA-256 hash in lowercase hex.
+pub fn is_sha256(s: &str) -> bool {
+    s.len() == 64
+        && s.bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Login {
     pub user: User,
+    pub password: String,
+}
+
+/// What a viewer sends to log in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Credentials {
+    pub nickname: String,
+    pub password: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -59,9 +263,103 @@ pub enum Color {
     Cyan,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct LoginResponse {
-    pub nickname_taken: bool,
+impl FromStr for Color {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "red" => Ok(Self::Red),
+            "green" => Ok(Self::Green),
+            "yellow" => Ok(Self::Yellow),
+            "blue" => Ok(Self::Blue),
+            "magenta" => Ok(Self::Magenta),
+            "cyan" => Ok(Self::Cyan),
+            _ => Err(anyhow::anyhow!("‘{}’ is an invalid color.", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LoginResponse {
+    LoggedIn,
+    /// Someone is already logged in with this nickname.
+    NicknameTaken,
+    /// There’s no account with this nickname, or the password is wrong.
+    BadCredentials,
+}
+
+/// The first thing a client sends,
+/// before even its [`ConnectionKind`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    /// Shows up in the server’s logs, e.g. `nunitius-viewer 0.1.0`.
+    pub client_name: String,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Hello {
+    /// Says hello on behalf of a client built alongside this crate.
+    pub fn new(client_name: &str) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: format!("{} {}", client_name, env!("CARGO_PKG_VERSION")),
+            capabilities: Capability::SUPPORTED.to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum HelloResponse {
+    /// The client can go on to send its connection kind.
+    Welcome {
+        protocol_version: u32,
+        /// The capabilities both ends support, which are the only ones in use.
+        capabilities: Vec<Capability>,
+    },
+    /// The server can’t talk to the client, for a reason meant for its user.
+    Rejected { reason: String },
+}
+
+impl HelloResponse {
+    /// The capabilities in use, or why the server turned us away.
+    pub fn into_capabilities(self) -> anyhow::Result<Vec<Capability>> {
+        match self {
+            Self::Welcome { capabilities, .. } => Ok(capabilities),
+            Self::Rejected { reason } => anyhow::bail!("the server turned us away: {}", reason),
+        }
+    }
+}
+
+/// An optional part of the protocol.
+///
+/// Clients are only sent things they’ve said they support,
+/// so new features can be added without breaking older clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Capability {
+    Typing,
+    DirectMessages,
+    Actions,
+    NicknameChanges,
+    /// Senders can upload files in chunks.
+    Uploads,
+    /// Viewers can download files, rather than them coming with their events.
+    Downloads,
+    /// Something a newer client supports that we don’t know about.
+    #[serde(other)]
+    Unknown,
+}
+
+impl Capability {
+    /// Everything this build supports.
+    pub const SUPPORTED: &'static [Self] = &[
+        Self::Typing,
+        Self::DirectMessages,
+        Self::Actions,
+        Self::NicknameChanges,
+        Self::Uploads,
+        Self::Downloads,
+    ];
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -69,3 +367,86 @@ pub enum ConnectionKind {
     Sender,
     Viewer,
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ViewerRequest {
+    /// Starts receiving the events of a room,
+    /// after first receiving the part of its history given by `history`.
+    JoinRoom {
+        room: String,
+        history: HistoryQuery,
+    },
+    LeaveRoom {
+        room: String,
+    },
+    ListRooms,
+    History {
+        room: String,
+        query: HistoryQuery,
+    },
+    /// Asks for up to `len` bytes of a file, starting at `offset`,
+    /// which the server answers with [`ViewerMessage::FileChunk`]
+    /// or [`ViewerMessage::DownloadFailed`].
+    Download {
+        sha256: String,
+        offset: u64,
+        len: u64,
+    },
+    /// Lets the server know the viewer is still there.
+    Heartbeat,
+}
+
+/// Which part of a room’s history a viewer would like to receive.
+///
+/// Events are identified by their index in the room’s history,
+/// which for each viewer includes the direct messages it sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum HistoryQuery {
+    /// The most recent `count` events.
+    Latest { count: usize },
+    /// Up to `count` events immediately preceding the event at index `before`.
+    Before { before: usize, count: usize },
+    /// The events with sequence numbers greater than `seq`,
+    /// as many of them as fit in one reply.
+    After { seq: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ViewerMessage {
+    Event(Event),
+    HistoryPage(HistoryPage),
+    Rooms(Vec<String>),
+    /// Part of a file, which is only shorter than was asked for
+    /// if it reaches the end of the file.
+    FileChunk {
+        sha256: String,
+        offset: u64,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// The server couldn’t send part of a file, for a reason meant for the user.
+    DownloadFailed {
+        sha256: String,
+        reason: String,
+    },
+    /// Sent regularly so the viewer knows the server is still there.
+    Heartbeat,
+}
+
+/// One page of the server’s reply to a [`HistoryQuery`].
+///
+/// Large replies are split into several pages,
+/// which are sent oldest first.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub room: String,
+    pub events: Vec<Event>,
+    /// The index in the history of the first event in this page.
+    pub first_index: usize,
+    /// Whether this is the final page in reply to the query.
+    pub is_last: bool,
+    /// Set on the final page if the query matched more events than one reply holds,
+    /// in which case the rest are asked for with [`HistoryQuery::After`] this sequence number.
+    #[serde(default)]
+    pub more_after: Option<u64>,
+}
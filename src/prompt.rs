@@ -0,0 +1,199 @@
+//! Asking the user for things in the terminal,
+//! for the clients and the server alike.
+
+use crate::sender::edit_buffer::EditBuffer;
+use crossterm::{cursor, event, execute, queue, terminal};
+use std::io::{self, Write};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Reads a line without showing what’s typed.
+pub fn read_password(prompt: &str, stdout: &mut io::Stdout) -> anyhow::Result<Option<String>> {
+    let mut edit_buffer = EditBuffer::default();
+
+    enter_raw_mode(stdout)?;
+
+    loop {
+        // every asterisk is one byte, so the count is also where to split
+        let (masked, cursor) = edit_buffer.masked();
+        let (before_cursor, after_cursor) = masked.split_at(cursor);
+        print_text(prompt, before_cursor, after_cursor, stdout)?;
+
+        let (code, modifiers) = match event::read()? {
+            event::Event::Key(event::KeyEvent {
+                code, modifiers, ..
+            }) => (code, modifiers),
+            // so that passwords can come from a password manager
+            event::Event::Paste(text) => {
+                edit_buffer.paste(&text);
+                continue;
+            }
+            _ => continue,
+        };
+
+        match (code, modifiers) {
+            (event::KeyCode::Char('c'), event::KeyModifiers::CONTROL) => {
+                leave_raw_mode(stdout)?;
+                std::process::exit(1);
+            }
+            (event::KeyCode::Enter, _) => break,
+            _ => {
+                edit_buffer.handle_key(code, modifiers);
+            }
+        }
+    }
+
+    leave_raw_mode(stdout)?;
+    writeln!(stdout)?;
+
+    // unlike other input, spaces at either end of a password matter
+    let password = edit_buffer.text();
+
+    Ok(if password.is_empty() {
+        None
+    } else {
+        Some(password.to_string())
+    })
+}
+
+/// Switches the terminal to handing us every key press as it happens,
+/// and pasted text all at once rather than as separate key presses.
+pub fn enter_raw_mode(stdout: &mut io::Stdout) -> anyhow::Result<()> {
+    terminal::enable_raw_mode()?;
+    execute!(stdout, event::EnableBracketedPaste)?;
+
+    Ok(())
+}
+
+pub fn leave_raw_mode(stdout: &mut io::Stdout) -> anyhow::Result<()> {
+    execute!(stdout, event::DisableBracketedPaste)?;
+    terminal::disable_raw_mode()?;
+
+    Ok(())
+}
+
+/// Shows `prompt` followed by the text being typed,
+/// with the terminal’s cursor between `before_cursor` and `after_cursor`.
+///
+/// Text too wide for the terminal is scrolled rather than wrapped,
+/// since a wrapped line couldn’t be cleared and redrawn in place.
+pub fn print_text(
+    prompt: &str,
+    before_cursor: &str,
+    after_cursor: &str,
+    stdout: &mut io::Stdout,
+) -> anyhow::Result<()> {
+    let (columns, _) = terminal::size()?;
+    let (line, column) = layout(prompt, before_cursor, after_cursor, columns.into());
+
+    queue!(stdout, terminal::Clear(terminal::ClearType::CurrentLine))?;
+    write!(stdout, "\r{}\r", line)?;
+
+    // moving right by zero columns moves by one in most terminals
+    if column > 0 {
+        queue!(stdout, cursor::MoveRight(column as u16))?;
+    }
+
+    stdout.flush()?;
+
+    Ok(())
+}
+
+/// The line to show for the prompt and the text around the cursor
+/// in a terminal `columns` wide, and which column the cursor goes in.
+fn layout(
+    prompt: &str,
+    before_cursor: &str,
+    after_cursor: &str,
+    columns: usize,
+) -> (String, usize) {
+    let prompt = format!("{} > ", prompt);
+
+    // the last column is left empty so the cursor can go after the text
+    // without the terminal wrapping onto the next row
+    let available = columns.saturating_sub(prompt.width() + 1);
+
+    let before_cursor = fitting_suffix(before_cursor, available);
+    let after_cursor = fitting_prefix(after_cursor, available - before_cursor.width());
+
+    (
+        format!("{}{}{}", prompt, before_cursor, after_cursor),
+        prompt.width() + before_cursor.width(),
+    )
+}
+
+/// The most graphemes from the end of `text` that fit in `width` columns.
+fn fitting_suffix(text: &str, width: usize) -> &str {
+    let mut used = 0;
+    let start = text
+        .grapheme_indices(true)
+        .rev()
+        .take_while(|(_, grapheme)| {
+            used += grapheme.width();
+            used <= width
+        })
+        .last()
+        .map_or(text.len(), |(i, _)| i);
+
+    &text[start..]
+}
+
+/// The most graphemes from the start of `text` that fit in `width` columns.
+fn fitting_prefix(text: &str, width: usize) -> &str {
+    let mut used = 0;
+    let end = text
+        .grapheme_indices(true)
+        .find(|(_, grapheme)| {
+            used += grapheme.width();
+            used > width
+        })
+        .map_or(text.len(), |(i, _)| i);
+
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_text_is_shown_whole() {
+        assert_eq!(
+            layout("luna", "hi", " there", 80),
+            ("luna > hi there".to_string(), 9)
+        );
+    }
+
+    #[test]
+    fn cursor_goes_after_wide_characters() {
+        // each of these takes up two columns
+        assert_eq!(layout("", "日本", "語", 80), (" > 日本語".to_string(), 7));
+        assert_eq!(layout("", "👍🏽👍", "", 80), (" > 👍🏽👍".to_string(), 7));
+    }
+
+    #[test]
+    fn long_text_scrolls_to_keep_the_cursor_in_view() {
+        // 3 columns for the prompt leaves 6 for the text and 1 for the cursor
+        assert_eq!(layout("", "abcdefgh", "", 10), (" > cdefgh".to_string(), 9));
+        assert_eq!(layout("", "", "abcdefgh", 10), (" > abcdef".to_string(), 3));
+        assert_eq!(layout("", "abc", "defgh", 10), (" > abcdef".to_string(), 6));
+    }
+
+    #[test]
+    fn wide_characters_are_never_cut_in_half() {
+        assert_eq!(
+            layout("", "日本語一二", "", 10),
+            (" > 語一二".to_string(), 9)
+        );
+        assert_eq!(
+            layout("", "日", "本語一二", 10),
+            (" > 日本語".to_string(), 5)
+        );
+        assert_eq!(layout("", "a", "😀😀😀", 10), (" > a😀😀".to_string(), 4));
+    }
+
+    #[test]
+    fn narrow_terminals_still_show_the_prompt() {
+        assert_eq!(layout("luna", "hi", "", 4), ("luna > ".to_string(), 7));
+    }
+}
@@ -1 +1,6 @@
+pub mod command;
+pub mod connection;
+pub mod edit_buffer;
+pub mod input_history;
 pub mod ui;
+pub mod upload;
@@ -0,0 +1,165 @@
+use crate::Color;
+use std::path::PathBuf;
+
+/// What to show for `/help`.
+pub const HELP: &[&str] = &[
+    "/nick <nickname>     switch to another account",
+    "/color [color]       change the color of your nickname, or remove it",
+    "/me <action>         say what you’re doing, e.g. /me waves",
+    "/msg <user> <text>   send someone a private message",
+    "/upload <path>       send a file",
+    "/quit                log out",
+    "/help                show this list",
+    "Start a message with // to send it starting with a single /.",
+];
+
+/// A line typed into the sender.
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    Message(String),
+    Command(Command),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Nick { nickname: String },
+    Color { color: Option<Color> },
+    Me { action: String },
+    Msg { to: String, body: String },
+    Upload { path: PathBuf },
+    Quit,
+    Help,
+}
+
+/// Works out whether a line is a message or a command,
+/// failing with a message meant for the user if it’s a command used wrongly.
+pub fn parse(line: &str) -> anyhow::Result<Input> {
+    let command_line = match line.strip_prefix('/') {
+        // a doubled slash is how to send a message that starts with one
+        Some(command_line) if !command_line.starts_with('/') => command_line,
+        Some(message) => return Ok(Input::Message(message.to_string())),
+        None => return Ok(Input::Message(line.to_string())),
+    };
+
+    let (name, args) = match command_line.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (command_line, ""),
+    };
+
+    let command = match name {
+        "nick" => match args {
+            "" => anyhow::bail!("usage: /nick <nickname>"),
+            nickname if nickname.contains(char::is_whitespace) => {
+                anyhow::bail!("nicknames can’t contain spaces")
+            }
+            nickname => Command::Nick {
+                nickname: nickname.to_string(),
+            },
+        },
+
+        "color" => Command::Color {
+            color: match args {
+                "" => None,
+                color => Some(color.parse()?),
+            },
+        },
+
+        "me" => match args {
+            "" => anyhow::bail!("usage: /me <action>"),
+            action => Command::Me {
+                action: action.to_string(),
+            },
+        },
+
+        "msg" => match args.split_once(char::is_whitespace) {
+            Some((to, body)) => Command::Msg {
+                to: to.to_string(),
+                body: body.trim().to_string(),
+            },
+            None => anyhow::bail!("usage: /msg <user> <text>"),
+        },
+
+        "upload" => match args {
+            "" => anyhow::bail!("usage: /upload <path>"),
+            path => Command::Upload {
+                path: PathBuf::from(path),
+            },
+        },
+
+        "quit" => Command::Quit,
+        "help" => Command::Help,
+
+        _ => anyhow::bail!("there’s no /{} command; type /help for a list", name),
+    };
+
+    Ok(Input::Command(command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(line: &str) -> Command {
+        match parse(line).unwrap() {
+            Input::Command(command) => command,
+            Input::Message(message) => panic!("‘{}’ was parsed as a message", message),
+        }
+    }
+
+    fn error(line: &str) -> String {
+        parse(line).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn lines_without_a_slash_are_messages() {
+        assert_eq!(
+            parse("hello / there").unwrap(),
+            Input::Message("hello / there".to_string())
+        );
+        assert_eq!(
+            parse("//shrug").unwrap(),
+            Input::Message("/shrug".to_string())
+        );
+    }
+
+    #[test]
+    fn commands_take_their_arguments() {
+        assert_eq!(
+            command("/msg luna  see you\nsoon "),
+            Command::Msg {
+                to: "luna".to_string(),
+                body: "see you\nsoon".to_string()
+            }
+        );
+        assert_eq!(
+            command("/me waves"),
+            Command::Me {
+                action: "waves".to_string()
+            }
+        );
+        assert_eq!(
+            command("/color cyan"),
+            Command::Color {
+                color: Some(Color::Cyan)
+            }
+        );
+        assert_eq!(command("/color"), Command::Color { color: None });
+        assert_eq!(
+            command("/upload my notes.txt"),
+            Command::Upload {
+                path: PathBuf::from("my notes.txt")
+            }
+        );
+    }
+
+    #[test]
+    fn misused_commands_explain_themselves() {
+        assert_eq!(error("/msg luna"), "usage: /msg <user> <text>");
+        assert_eq!(error("/nick two words"), "nicknames can’t contain spaces");
+        assert_eq!(error("/color plaid"), "‘plaid’ is an invalid color.");
+        assert_eq!(
+            error("/dance"),
+            "there’s no /dance command; type /help for a list"
+        );
+    }
+}
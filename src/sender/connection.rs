@@ -0,0 +1,465 @@
+use super::upload::Upload;
+use crate::backoff::Backoff;
+use crate::stream::{self, Connector, Stream};
+use crate::{
+    Capability, ConnectionKind, Hello, HelloResponse, Login, LoginResponse, SenderEvent,
+    SenderMessage, User, DEFAULT_ROOM,
+};
+use flume::{Receiver, Selector, Sender};
+use std::collections::VecDeque;
+use std::io;
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+pub enum ConnectionEvent {
+    /// We’ve reconnected and logged back in,
+    /// and are in the same room as before.
+    Reconnected,
+    Disconnected {
+        error: anyhow::Error,
+        retry_in: Duration,
+    },
+    /// The server has received more of a file we’re uploading.
+    UploadProgress {
+        filename: String,
+        percent: u64,
+    },
+    UploadFinished {
+        filename: String,
+    },
+    UploadFailed {
+        filename: String,
+        reason: String,
+    },
+}
+
+pub struct ServerConnection {
+    reader: io::BufReader<Stream>,
+    writer: Stream,
+    capabilities: Vec<Capability>,
+}
+
+impl ServerConnection {
+    pub fn connect(connector: &Connector) -> anyhow::Result<Self> {
+        let writer = connector.connect()?;
+        let reader = io::BufReader::new(writer.try_clone()?);
+        let mut connection = Self {
+            reader,
+            writer,
+            capabilities: Vec::new(),
+        };
+
+        jsonl::write(&mut connection.writer, &Hello::new("nunitius-sender"))?;
+        connection.capabilities =
+            jsonl::read::<_, HelloResponse>(&mut connection.reader)?.into_capabilities()?;
+
+        jsonl::write(&mut connection.writer, &ConnectionKind::Sender)?;
+
+        Ok(connection)
+    }
+
+    pub fn log_in(&mut self, user: User, password: String) -> anyhow::Result<LoginResponse> {
+        jsonl::write(&mut self.writer, &Login { user, password })?;
+        Ok(jsonl::read(&mut self.reader)?)
+    }
+}
+
+/// Everything needed to carry on where we left off after reconnecting,
+/// which changes as we send events.
+struct Session {
+    user: User,
+    password: String,
+    room: String,
+    /// The password for a nickname change the server hasn’t answered yet.
+    new_password: Option<String>,
+    /// Files waiting to be uploaded one after the other,
+    /// starting with the one being uploaded now.
+    uploads: VecDeque<Upload>,
+}
+
+/// What there is to send, and where what the server sends us ends up.
+pub struct Channels {
+    pub sender_event_rx: Receiver<SenderEvent>,
+    /// Files to upload, which are sent whenever there’s nothing else to send
+    /// so that they don’t hold up messages.
+    pub upload_rx: Receiver<Upload>,
+    pub sender_message_tx: Sender<SenderMessage>,
+}
+
+/// Sends sender events to the server for as long as the program runs,
+/// reconnecting with exponential backoff whenever the connection is lost.
+///
+/// `connection` must already be logged in as `user` with `password`.
+pub fn maintain_connection(
+    connector: Connector,
+    user: User,
+    password: String,
+    connection: ServerConnection,
+    channels: Channels,
+    connection_event_tx: Sender<ConnectionEvent>,
+) {
+    let mut backoff = Backoff::default();
+    let mut session = Session {
+        user,
+        password,
+        room: DEFAULT_ROOM.to_string(),
+        new_password: None,
+        uploads: VecDeque::new(),
+    };
+
+    // the event we were sending when the connection was lost
+    let mut unsent_event = None;
+
+    let mut connection = Ok(connection);
+
+    loop {
+        let error = match connection {
+            Ok(connection) => match send_events(
+                connection,
+                connector.heartbeat.interval,
+                &mut session,
+                &mut unsent_event,
+                &channels,
+                &connection_event_tx,
+            ) {
+                // everyone sending us events has gone away,
+                // so the program is exiting
+                Ok(()) => return,
+                Err(e) => e,
+            },
+            Err(e) => e,
+        };
+
+        let retry_in = backoff.next_delay();
+
+        if connection_event_tx
+            .send(ConnectionEvent::Disconnected { error, retry_in })
+            .is_err()
+        {
+            return;
+        }
+
+        thread::sleep(retry_in);
+
+        connection = reconnect(&connector, &session);
+
+        if connection.is_ok() {
+            backoff.reset();
+
+            if connection_event_tx
+                .send(ConnectionEvent::Reconnected)
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+fn reconnect(connector: &Connector, session: &Session) -> anyhow::Result<ServerConnection> {
+    let mut connection = ServerConnection::connect(connector)?;
+    let nickname = &session.user.nickname;
+
+    match connection.log_in(session.user.clone(), session.password.clone())? {
+        LoginResponse::LoggedIn => {}
+        // the server might not have noticed our old connection is gone yet
+        LoginResponse::NicknameTaken => anyhow::bail!("nickname ‘{}’ is still taken", nickname),
+        LoginResponse::BadCredentials => anyhow::bail!("password is no longer accepted"),
+    }
+
+    if session.room != DEFAULT_ROOM {
+        jsonl::write(
+            &mut connection.writer,
+            &SenderEvent::JoinRoom {
+                room: session.room.clone(),
+            },
+        )?;
+    }
+
+    Ok(connection)
+}
+
+fn send_events(
+    connection: ServerConnection,
+    heartbeat_interval: Duration,
+    session: &mut Session,
+    unsent_event: &mut Option<SenderEvent>,
+    channels: &Channels,
+    connection_event_tx: &Sender<ConnectionEvent>,
+) -> anyhow::Result<()> {
+    let ServerConnection {
+        reader,
+        mut writer,
+        capabilities,
+    } = connection;
+    let (disconnected_tx, disconnected_rx) = flume::bounded(1);
+    let (nickname_change_tx, nickname_change_rx) = flume::bounded(1);
+    let (upload_message_tx, upload_message_rx) = flume::unbounded();
+
+    thread::spawn({
+        let sender_message_tx = channels.sender_message_tx.clone();
+        move || {
+            read_sender_messages(
+                reader,
+                sender_message_tx,
+                nickname_change_tx,
+                upload_message_tx,
+                disconnected_tx,
+            )
+        }
+    });
+
+    // uploads are started again on each connection,
+    // and the server says how much of them it already has
+    let mut started_upload: Option<Uuid> = None;
+
+    let result = (|| {
+        if let Some(sender_event) = unsent_event.take() {
+            send_event(&mut writer, sender_event, session, unsent_event)?;
+        }
+
+        loop {
+            if let Some(upload) = session.uploads.front_mut() {
+                if started_upload != Some(upload.id()) {
+                    started_upload = Some(upload.id());
+
+                    if capabilities.contains(&Capability::Uploads) {
+                        jsonl::write(&mut writer, &upload.start())?;
+                    } else {
+                        let connection_event = upload_failed(
+                            &mut session.uploads,
+                            "the server doesn’t support uploading files".to_string(),
+                        );
+
+                        if connection_event_tx.send(connection_event).is_err() {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            let selector = Selector::new()
+                .recv(
+                    &channels.sender_event_rx,
+                    |sender_event| match sender_event {
+                        Ok(sender_event) => Next::Send(sender_event),
+                        Err(_) => Next::Finish,
+                    },
+                )
+                .recv(&channels.upload_rx, |upload| match upload {
+                    Ok(upload) => Next::Upload(upload),
+                    Err(_) => Next::Finish,
+                })
+                .recv(
+                    &nickname_change_rx,
+                    |nickname_change| match nickname_change {
+                        Ok(nickname_change) => Next::NicknameChange(nickname_change),
+                        // the reader has stopped, and will have said why
+                        Err(_) => Next::Disconnected(disconnected_rx.recv().unwrap()),
+                    },
+                )
+                .recv(&upload_message_rx, |upload_message| match upload_message {
+                    Ok(upload_message) => Next::UploadMessage(upload_message),
+                    Err(_) => Next::Disconnected(disconnected_rx.recv().unwrap()),
+                })
+                .recv(&disconnected_rx, |error| Next::Disconnected(error.unwrap()));
+
+            let next = if session.uploads.front().is_some_and(Upload::has_chunk_ready) {
+                // chunks only go out when there’s nothing else to send
+                selector
+                    .wait_timeout(Duration::ZERO)
+                    .unwrap_or(Next::SendChunk)
+            } else {
+                selector
+                    .wait_timeout(heartbeat_interval)
+                    // nothing has happened for a while,
+                    // so remind the server we’re still here
+                    .unwrap_or(Next::Send(SenderEvent::Heartbeat))
+            };
+
+            match next {
+                Next::Send(sender_event) => {
+                    send_event(&mut writer, sender_event, session, unsent_event)?
+                }
+                Next::NicknameChange(sender_message) => {
+                    if let SenderMessage::NicknameChange {
+                        ref nickname,
+                        response,
+                    } = sender_message
+                    {
+                        let new_password = session.new_password.take();
+
+                        if let (LoginResponse::LoggedIn, Some(new_password)) =
+                            (response, new_password)
+                        {
+                            session.user.nickname = nickname.clone();
+                            session.password = new_password;
+                        }
+                    }
+
+                    if channels.sender_message_tx.send(sender_message).is_err() {
+                        return Ok(());
+                    }
+                }
+                Next::Upload(upload) => session.uploads.push_back(upload),
+                Next::SendChunk => {
+                    let upload = session.uploads.front_mut().unwrap();
+
+                    match upload.next_chunk() {
+                        // like the start of the upload, a chunk that fails to send
+                        // is sent again when the upload carries on after reconnecting
+                        Ok(chunk) => jsonl::write(&mut writer, &chunk)?,
+                        Err(e) => {
+                            let connection_event =
+                                upload_failed(&mut session.uploads, format!("{:#}", e));
+
+                            if connection_event_tx.send(connection_event).is_err() {
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+                Next::UploadMessage(upload_message) => {
+                    let connection_event =
+                        handle_upload_message(&mut session.uploads, upload_message);
+
+                    if let Some(connection_event) = connection_event {
+                        if connection_event_tx.send(connection_event).is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+                Next::Disconnected(error) => return Err(error),
+                // everyone sending us events has gone away
+                Next::Finish => return Ok(()),
+            }
+        }
+    })();
+
+    // make sure the reader thread stops too
+    let _ = writer.shutdown();
+
+    result
+}
+
+enum Next {
+    Send(SenderEvent),
+    NicknameChange(SenderMessage),
+    Upload(Upload),
+    SendChunk,
+    UploadMessage(SenderMessage),
+    Disconnected(anyhow::Error),
+    Finish,
+}
+
+fn send_event(
+    writer: &mut Stream,
+    sender_event: SenderEvent,
+    session: &mut Session,
+    unsent_event: &mut Option<SenderEvent>,
+) -> anyhow::Result<()> {
+    if let Err(e) = jsonl::write(writer, &sender_event) {
+        *unsent_event = Some(sender_event);
+        return Err(e.into());
+    }
+
+    // keep track of who we are and where
+    // so we can carry on the same after reconnecting
+    match sender_event {
+        SenderEvent::JoinRoom { room } => session.room = room,
+        SenderEvent::LeaveRoom => session.room = DEFAULT_ROOM.to_string(),
+        SenderEvent::ChangeNickname { password, .. } => session.new_password = Some(password),
+        SenderEvent::ChangeColor { color } => session.user.color = color,
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Works out what to tell the user about an upload from what the server said about it.
+fn handle_upload_message(
+    uploads: &mut VecDeque<Upload>,
+    upload_message: SenderMessage,
+) -> Option<ConnectionEvent> {
+    match upload_message {
+        SenderMessage::UploadProgress { id, received } => {
+            let upload = uploads.front_mut().filter(|upload| upload.id() == id)?;
+            let percent = upload.acknowledge(received)?;
+
+            Some(ConnectionEvent::UploadProgress {
+                filename: upload.metadata().filename.clone(),
+                percent,
+            })
+        }
+        SenderMessage::UploadFinished { id } => {
+            uploads.front().filter(|upload| upload.id() == id)?;
+            let upload = uploads.pop_front().unwrap();
+
+            Some(ConnectionEvent::UploadFinished {
+                filename: upload.metadata().filename.clone(),
+            })
+        }
+        SenderMessage::UploadFailed { id, reason } => {
+            uploads.front().filter(|upload| upload.id() == id)?;
+            Some(upload_failed(uploads, reason))
+        }
+        // nothing else is about an upload
+        _ => None,
+    }
+}
+
+/// Gives up on the upload in progress.
+fn upload_failed(uploads: &mut VecDeque<Upload>, reason: String) -> ConnectionEvent {
+    let upload = uploads.pop_front().unwrap();
+
+    ConnectionEvent::UploadFailed {
+        filename: upload.metadata().filename.clone(),
+        reason,
+    }
+}
+
+/// Passes on what the server sends us,
+/// apart from answers to nickname changes,
+/// which go through the writer first so it knows who we are,
+/// and news of uploads, which the writer is in charge of.
+fn read_sender_messages(
+    mut reader: io::BufReader<Stream>,
+    sender_message_tx: Sender<SenderMessage>,
+    nickname_change_tx: Sender<SenderMessage>,
+    upload_message_tx: Sender<SenderMessage>,
+    disconnected_tx: Sender<anyhow::Error>,
+) {
+    let error = loop {
+        match jsonl::read(&mut reader) {
+            Ok(SenderMessage::Heartbeat) => {}
+            Ok(nickname_change @ SenderMessage::NicknameChange { .. }) => {
+                if nickname_change_tx.send(nickname_change).is_err() {
+                    return;
+                }
+            }
+            Ok(
+                upload_message @ (SenderMessage::UploadProgress { .. }
+                | SenderMessage::UploadFinished { .. }
+                | SenderMessage::UploadFailed { .. }),
+            ) => {
+                if upload_message_tx.send(upload_message).is_err() {
+                    return;
+                }
+            }
+            Ok(sender_message) => {
+                if sender_message_tx.send(sender_message).is_err() {
+                    return;
+                }
+            }
+            Err(jsonl::ReadError::Eof) => break anyhow::anyhow!("server closed the connection"),
+            Err(jsonl::ReadError::Io(e)) if stream::is_timeout(&e) => {
+                break anyhow::anyhow!("server stopped responding")
+            }
+            Err(e) => break e.into(),
+        }
+    };
+
+    let _ = disconnected_tx.send(error);
+}
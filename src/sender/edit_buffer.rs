@@ -0,0 +1,328 @@
+use crossterm::event::{KeyCode, KeyModifiers};
+use std::fmt;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// A line being typed, with a cursor that can be moved around it
+/// and readline-style editing commands.
+///
+/// The cursor moves a grapheme at a time,
+/// so that a character and any accents combined with it are treated as one.
+#[derive(Default)]
+pub struct EditBuffer {
+    text: String,
+    /// A byte offset into `text`, always on a grapheme boundary.
+    cursor: usize,
+    /// Whatever was deleted last by a word or line deletion,
+    /// ready to be yanked back.
+    killed: String,
+}
+
+impl EditBuffer {
+    /// Applies the editing command bound to a key,
+    /// returning whether there was one.
+    pub fn handle_key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        match (code, modifiers) {
+            (KeyCode::Char(c), KeyModifiers::SHIFT) => {
+                self.insert_str(&c.to_uppercase().to_string())
+            }
+            (KeyCode::Char(c), KeyModifiers::NONE) => self.insert(c),
+            (KeyCode::Enter, KeyModifiers::SHIFT) => self.insert('\n'),
+            (KeyCode::Enter, KeyModifiers::ALT) => self.insert('\n'),
+
+            (KeyCode::Left, KeyModifiers::NONE) => self.move_left(),
+            (KeyCode::Char('b'), KeyModifiers::CONTROL) => self.move_left(),
+            (KeyCode::Right, KeyModifiers::NONE) => self.move_right(),
+            (KeyCode::Char('f'), KeyModifiers::CONTROL) => self.move_right(),
+            (KeyCode::Left, KeyModifiers::CONTROL) => self.move_word_left(),
+            (KeyCode::Char('b'), KeyModifiers::ALT) => self.move_word_left(),
+            (KeyCode::Right, KeyModifiers::CONTROL) => self.move_word_right(),
+            (KeyCode::Char('f'), KeyModifiers::ALT) => self.move_word_right(),
+            (KeyCode::Home, _) => self.move_to_start(),
+            (KeyCode::Char('a'), KeyModifiers::CONTROL) => self.move_to_start(),
+            (KeyCode::End, _) => self.move_to_end(),
+            (KeyCode::Char('e'), KeyModifiers::CONTROL) => self.move_to_end(),
+
+            (KeyCode::Backspace, KeyModifiers::ALT) => self.delete_word_before(),
+            (KeyCode::Backspace, _) => self.backspace(),
+            (KeyCode::Char('h'), KeyModifiers::CONTROL) => self.backspace(),
+            (KeyCode::Delete, _) => self.delete(),
+            (KeyCode::Char('d'), KeyModifiers::CONTROL) => self.delete(),
+            (KeyCode::Char('w'), KeyModifiers::CONTROL) => self.delete_word_before(),
+            (KeyCode::Char('d'), KeyModifiers::ALT) => self.delete_word_after(),
+            (KeyCode::Char('k'), KeyModifiers::CONTROL) => self.kill_to_end(),
+            (KeyCode::Char('y'), KeyModifiers::CONTROL) => self.yank(),
+
+            _ => return false,
+        }
+
+        true
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    pub fn insert_str(&mut self, s: &str) {
+        self.text.insert_str(self.cursor, s);
+        self.cursor += s.len();
+    }
+
+    /// Inserts pasted text,
+    /// with its line breaks made the same whichever system they came from.
+    pub fn paste(&mut self, text: &str) {
+        self.insert_str(&text.replace("\r\n", "\n").replace('\r', "\n"));
+    }
+
+    pub fn backspace(&mut self) {
+        let start = self.previous_boundary();
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    pub fn delete(&mut self) {
+        let end = self.next_boundary();
+        self.text.replace_range(self.cursor..end, "");
+    }
+
+    pub fn move_left(&mut self) {
+        self.cursor = self.previous_boundary();
+    }
+
+    pub fn move_right(&mut self) {
+        self.cursor = self.next_boundary();
+    }
+
+    pub fn move_word_left(&mut self) {
+        self.cursor = self.previous_word_start(is_word_char);
+    }
+
+    pub fn move_word_right(&mut self) {
+        self.cursor = self.next_word_end();
+    }
+
+    pub fn move_to_start(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn move_to_end(&mut self) {
+        self.cursor = self.text.len();
+    }
+
+    /// Deletes back to the start of the whitespace-separated word before the cursor,
+    /// like Ctrl-W in a shell.
+    pub fn delete_word_before(&mut self) {
+        let start = self.previous_word_start(|c| !c.is_whitespace());
+        self.kill(start..self.cursor);
+        self.cursor = start;
+    }
+
+    pub fn delete_word_after(&mut self) {
+        let end = self.next_word_end();
+        self.kill(self.cursor..end);
+    }
+
+    pub fn kill_to_end(&mut self) {
+        self.kill(self.cursor..self.text.len());
+    }
+
+    /// Inserts whatever was deleted last.
+    pub fn yank(&mut self) {
+        let killed = self.killed.clone();
+        self.insert_str(&killed);
+    }
+
+    /// Empties the buffer, returning what was typed
+    /// unless it was only whitespace.
+    ///
+    /// Only line breaks at the end are removed,
+    /// so that the indentation of pasted code is kept.
+    pub fn take(&mut self) -> Option<String> {
+        let text = std::mem::take(&mut self.text);
+        self.cursor = 0;
+
+        if text.trim().is_empty() {
+            None
+        } else {
+            Some(text.trim_end_matches('\n').to_string())
+        }
+    }
+
+    /// Replaces everything that’s been typed with `text`,
+    /// leaving the cursor at the end.
+    pub fn replace(&mut self, text: String) {
+        self.cursor = text.len();
+        self.text = text;
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The text before and after the cursor.
+    pub fn split_at_cursor(&self) -> (&str, &str) {
+        self.text.split_at(self.cursor)
+    }
+
+    /// The text with every grapheme replaced by an asterisk,
+    /// and how far into it the cursor is.
+    pub fn masked(&self) -> (String, usize) {
+        let before_cursor = self.text[..self.cursor].graphemes(true).count();
+        let after_cursor = self.text[self.cursor..].graphemes(true).count();
+
+        ("*".repeat(before_cursor + after_cursor), before_cursor)
+    }
+
+    fn kill(&mut self, range: std::ops::Range<usize>) {
+        // deleting nothing shouldn’t lose what was deleted before
+        if !range.is_empty() {
+            self.killed = self.text[range.clone()].to_string();
+            self.text.replace_range(range, "");
+        }
+    }
+
+    fn previous_boundary(&self) -> usize {
+        self.text[..self.cursor]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn next_boundary(&self) -> usize {
+        self.text[self.cursor..]
+            .graphemes(true)
+            .next()
+            .map_or(self.cursor, |grapheme| self.cursor + grapheme.len())
+    }
+
+    /// Skips back over anything that isn’t part of a word
+    /// and then to the start of the word before it.
+    fn previous_word_start(&self, is_word_char: impl Fn(char) -> bool) -> usize {
+        let is_word = |grapheme: &str| grapheme.chars().next().is_some_and(&is_word_char);
+
+        self.text[..self.cursor]
+            .grapheme_indices(true)
+            .rev()
+            .skip_while(|(_, grapheme)| !is_word(grapheme))
+            .take_while(|(_, grapheme)| is_word(grapheme))
+            .last()
+            .map_or(0, |(i, _)| i)
+    }
+
+    /// Skips forward over anything that isn’t part of a word
+    /// and then to the end of the word after it.
+    fn next_word_end(&self) -> usize {
+        let is_word = |grapheme: &str| grapheme.chars().next().is_some_and(is_word_char);
+
+        self.text[self.cursor..]
+            .grapheme_indices(true)
+            .skip_while(|(_, grapheme)| !is_word(grapheme))
+            .find(|(_, grapheme)| !is_word(grapheme))
+            .map_or(self.text.len(), |(i, _)| self.cursor + i)
+    }
+}
+
+impl fmt::Display for EditBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unicode_width::UnicodeWidthStr;
+
+    /// A buffer containing `text` with the cursor at the `|`.
+    fn buffer(text: &str) -> EditBuffer {
+        let cursor = text.find('|').unwrap();
+
+        EditBuffer {
+            text: text.replace('|', ""),
+            cursor,
+            killed: String::new(),
+        }
+    }
+
+    fn contents(buffer: &EditBuffer) -> String {
+        let mut text = buffer.text.clone();
+        text.insert(buffer.cursor, '|');
+        text
+    }
+
+    #[test]
+    fn insert_in_middle() {
+        let mut buffer = buffer("helo| world");
+        buffer.move_left();
+        buffer.insert('l');
+        assert_eq!(contents(&buffer), "hell|o world");
+    }
+
+    #[test]
+    fn combining_characters_move_together() {
+        // an e followed by a combining acute accent
+        let mut buffer = buffer("cafe\u{301}|");
+        buffer.move_left();
+        assert_eq!(contents(&buffer), "caf|e\u{301}");
+
+        buffer.delete();
+        assert_eq!(contents(&buffer), "caf|");
+    }
+
+    #[test]
+    fn word_motion_skips_punctuation() {
+        let mut buffer = buffer("hello, world|");
+        buffer.move_word_left();
+        assert_eq!(contents(&buffer), "hello, |world");
+        buffer.move_word_left();
+        assert_eq!(contents(&buffer), "|hello, world");
+        buffer.move_word_right();
+        assert_eq!(contents(&buffer), "hello|, world");
+    }
+
+    #[test]
+    fn killed_text_can_be_yanked() {
+        let mut buffer = buffer("one two| three");
+        buffer.delete_word_before();
+        assert_eq!(contents(&buffer), "one | three");
+
+        buffer.kill_to_end();
+        assert_eq!(contents(&buffer), "one |");
+
+        buffer.move_to_start();
+        buffer.yank();
+        assert_eq!(contents(&buffer), " three|one ");
+    }
+
+    #[test]
+    fn pasted_line_breaks_are_normalised() {
+        let mut buffer = buffer("|");
+        buffer.paste("fn main() {\r\n}\r");
+        assert_eq!(contents(&buffer), "fn main() {\n}\n|");
+    }
+
+    #[test]
+    fn taking_keeps_indentation() {
+        let mut buffer = buffer("|");
+        buffer.paste("    if x {\n        y();\n    }\n\n");
+        assert_eq!(
+            buffer.take().as_deref(),
+            Some("    if x {\n        y();\n    }")
+        );
+        assert_eq!(contents(&buffer), "|");
+
+        buffer.paste(" \n\t\n");
+        assert_eq!(buffer.take(), None);
+    }
+
+    #[test]
+    fn wide_characters_take_up_two_columns() {
+        let buffer = buffer("日本|語");
+        assert_eq!(buffer.split_at_cursor().0.width(), 4);
+        assert_eq!(buffer.masked(), ("***".to_string(), 2));
+    }
+}
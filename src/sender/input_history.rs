@@ -0,0 +1,470 @@
+use super::edit_buffer::EditBuffer;
+use anyhow::Context;
+use crossterm::event::{KeyCode, KeyModifiers};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+/// How many lines are remembered;
+/// older ones are forgotten the next time the history is loaded.
+const MAX_ENTRIES: usize = 1000;
+
+/// Lines that have been typed before,
+/// which can be brought back with the up and down arrows or searched with Ctrl-R.
+///
+/// The history is kept on disk as JSON Lines, one string per line.
+#[derive(Default)]
+pub struct InputHistory {
+    entries: Vec<String>,
+    // None if the history is only kept in memory
+    file: Option<File>,
+    // the entry being shown, or None while typing a new line
+    position: Option<usize>,
+    // what was being typed before going back through the history
+    draft: String,
+    search: Option<Search>,
+}
+
+struct Search {
+    query: String,
+    // the entry the query was last found in
+    found: Option<usize>,
+    failed: bool,
+    // what was being typed before searching, for if the search is cancelled
+    original: String,
+}
+
+impl InputHistory {
+    /// Opens (or creates) the history at `path`.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create directory {}", dir.display()))?;
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("failed to open input history at {}", path.display()))?;
+
+        let mut entries = read_entries(&file)
+            .with_context(|| format!("failed to read input history at {}", path.display()))?;
+
+        let file = if entries.len() > MAX_ENTRIES {
+            entries.drain(..entries.len() - MAX_ENTRIES);
+            rewrite(path, &entries)
+                .with_context(|| format!("failed to rewrite input history at {}", path.display()))?
+        } else {
+            file
+        };
+
+        Ok(Self {
+            entries,
+            file: Some(file),
+            ..Self::default()
+        })
+    }
+
+    /// Opens the history in the user’s data directory,
+    /// or one only kept in memory if they don’t have one.
+    pub fn open_default() -> anyhow::Result<Self> {
+        match default_path() {
+            Some(path) => Self::open(path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Like [`InputHistory::open_default`],
+    /// but falls back to a history only kept in memory after warning the user,
+    /// since losing the history isn’t worth refusing to chat over.
+    pub fn open_default_or_warn() -> Self {
+        Self::open_default().unwrap_or_else(|e| {
+            eprintln!("Warning: {:#}; messages you send won’t be remembered", e);
+            Self::default()
+        })
+    }
+
+    /// Remembers a line that’s just been entered,
+    /// and goes back to typing a new one.
+    pub fn add(&mut self, line: &str) -> anyhow::Result<()> {
+        self.reset();
+
+        // holding down enter on the same line shouldn’t fill up the history
+        if self.entries.last().is_some_and(|last| last == line) {
+            return Ok(());
+        }
+
+        self.entries.push(line.to_string());
+
+        if let Some(ref mut file) = self.file {
+            jsonl::write(file, &line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Goes back to typing a new line,
+    /// forgetting any search or position in the history.
+    pub fn reset(&mut self) {
+        self.position = None;
+        self.draft.clear();
+        self.search = None;
+    }
+
+    /// Applies the history command bound to a key to `edit_buffer`,
+    /// returning whether there was one.
+    ///
+    /// While searching, keys that don’t affect the search
+    /// end it and are left for the caller to handle.
+    pub fn handle_key(
+        &mut self,
+        edit_buffer: &mut EditBuffer,
+        code: KeyCode,
+        modifiers: KeyModifiers,
+    ) -> bool {
+        if self.search.is_some() {
+            return self.handle_search_key(edit_buffer, code, modifiers);
+        }
+
+        match (code, modifiers) {
+            (KeyCode::Up, KeyModifiers::NONE) => self.previous(edit_buffer),
+            (KeyCode::Down, KeyModifiers::NONE) => self.next(edit_buffer),
+            (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    found: None,
+                    failed: false,
+                    original: edit_buffer.text().to_string(),
+                });
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// What to show instead of the usual prompt while searching.
+    pub fn search_prompt(&self) -> Option<String> {
+        self.search.as_ref().map(|search| {
+            if search.failed {
+                format!("(failed reverse search) ‘{}’", search.query)
+            } else {
+                format!("(reverse search) ‘{}’", search.query)
+            }
+        })
+    }
+
+    fn previous(&mut self, edit_buffer: &mut EditBuffer) {
+        let position = match self.position {
+            Some(0) => return,
+            Some(position) => position - 1,
+            None if self.entries.is_empty() => return,
+            None => {
+                self.draft = edit_buffer.text().to_string();
+                self.entries.len() - 1
+            }
+        };
+
+        self.position = Some(position);
+        edit_buffer.replace(self.entries[position].clone());
+    }
+
+    fn next(&mut self, edit_buffer: &mut EditBuffer) {
+        match self.position {
+            Some(position) if position + 1 < self.entries.len() => {
+                self.position = Some(position + 1);
+                edit_buffer.replace(self.entries[position + 1].clone());
+            }
+            Some(_) => {
+                self.position = None;
+                edit_buffer.replace(std::mem::take(&mut self.draft));
+            }
+            None => {}
+        }
+    }
+
+    fn handle_search_key(
+        &mut self,
+        edit_buffer: &mut EditBuffer,
+        code: KeyCode,
+        modifiers: KeyModifiers,
+    ) -> bool {
+        let search = self.search.as_mut().unwrap();
+
+        match (code, modifiers) {
+            (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                // look further back for the same query
+                let before = search.found.unwrap_or(self.entries.len());
+                self.find(before, edit_buffer);
+            }
+            (KeyCode::Char(c), KeyModifiers::SHIFT) => {
+                search.query.extend(c.to_uppercase());
+                self.refine(edit_buffer);
+            }
+            (KeyCode::Char(c), KeyModifiers::NONE) => {
+                search.query.push(c);
+                self.refine(edit_buffer);
+            }
+            (KeyCode::Backspace, _) => {
+                search.query.pop();
+                self.find(self.entries.len(), edit_buffer);
+            }
+            (KeyCode::Char('g'), KeyModifiers::CONTROL) => {
+                let search = self.search.take().unwrap();
+                edit_buffer.replace(search.original);
+            }
+            (KeyCode::Esc, _) => self.stop_searching(),
+            _ => {
+                self.stop_searching();
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Finds the longer query,
+    /// which the entry already found may still contain.
+    fn refine(&mut self, edit_buffer: &mut EditBuffer) {
+        let before = self
+            .search
+            .as_ref()
+            .and_then(|search| search.found)
+            .map_or(self.entries.len(), |found| found + 1);
+
+        self.find(before, edit_buffer);
+    }
+
+    /// Shows the most recent entry before `before` containing the query,
+    /// leaving the last one found if there isn’t one.
+    fn find(&mut self, before: usize, edit_buffer: &mut EditBuffer) {
+        let search = self.search.as_mut().unwrap();
+
+        let found = self.entries[..before]
+            .iter()
+            .rposition(|entry| entry.contains(&search.query));
+
+        search.failed = found.is_none();
+
+        if let Some(found) = found {
+            search.found = Some(found);
+            edit_buffer.replace(self.entries[found].clone());
+        }
+    }
+
+    /// Keeps whatever was found,
+    /// so that the arrows carry on from there.
+    pub fn stop_searching(&mut self) {
+        if let Some(Search {
+            found: Some(found),
+            original,
+            ..
+        }) = self.search.take()
+        {
+            self.position = Some(found);
+            self.draft = original;
+        }
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("nunitius").join("input-history.jsonl"))
+}
+
+fn read_entries(file: &File) -> anyhow::Result<Vec<String>> {
+    let mut entries = Vec::new();
+
+    for line in io::BufReader::new(file).lines() {
+        let line = line?;
+
+        // a line cut short by a crash isn’t worth refusing to start over
+        if let Ok(entry) = serde_json::from_str(&line) {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+fn rewrite(path: &Path, entries: &[String]) -> anyhow::Result<File> {
+    let tmp_path = path.with_extension("jsonl.tmp");
+
+    {
+        let mut tmp_file = File::create(&tmp_path)?;
+        for entry in entries {
+            jsonl::write(&mut tmp_file, entry)?;
+        }
+        tmp_file.sync_all()?;
+    }
+
+    fs::rename(&tmp_path, path)?;
+
+    Ok(OpenOptions::new().read(true).append(true).open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(entries: &[&str]) -> InputHistory {
+        InputHistory {
+            entries: entries.iter().map(|entry| entry.to_string()).collect(),
+            ..InputHistory::default()
+        }
+    }
+
+    fn press(
+        history: &mut InputHistory,
+        edit_buffer: &mut EditBuffer,
+        code: KeyCode,
+        modifiers: KeyModifiers,
+    ) -> bool {
+        history.handle_key(edit_buffer, code, modifiers)
+    }
+
+    #[test]
+    fn arrows_go_through_history_and_back_to_draft() {
+        let mut history = history(&["one", "two"]);
+        let mut edit_buffer = EditBuffer::default();
+        edit_buffer.insert_str("thr");
+
+        press(
+            &mut history,
+            &mut edit_buffer,
+            KeyCode::Up,
+            KeyModifiers::NONE,
+        );
+        assert_eq!(edit_buffer.text(), "two");
+        press(
+            &mut history,
+            &mut edit_buffer,
+            KeyCode::Up,
+            KeyModifiers::NONE,
+        );
+        press(
+            &mut history,
+            &mut edit_buffer,
+            KeyCode::Up,
+            KeyModifiers::NONE,
+        );
+        assert_eq!(edit_buffer.text(), "one");
+
+        press(
+            &mut history,
+            &mut edit_buffer,
+            KeyCode::Down,
+            KeyModifiers::NONE,
+        );
+        press(
+            &mut history,
+            &mut edit_buffer,
+            KeyCode::Down,
+            KeyModifiers::NONE,
+        );
+        assert_eq!(edit_buffer.text(), "thr");
+    }
+
+    #[test]
+    fn reverse_search_finds_older_matches() {
+        let mut history = history(&["hello there", "goodbye", "hello again"]);
+        let mut edit_buffer = EditBuffer::default();
+
+        press(
+            &mut history,
+            &mut edit_buffer,
+            KeyCode::Char('r'),
+            KeyModifiers::CONTROL,
+        );
+        for c in "hel".chars() {
+            press(
+                &mut history,
+                &mut edit_buffer,
+                KeyCode::Char(c),
+                KeyModifiers::NONE,
+            );
+        }
+        assert_eq!(edit_buffer.text(), "hello again");
+
+        press(
+            &mut history,
+            &mut edit_buffer,
+            KeyCode::Char('r'),
+            KeyModifiers::CONTROL,
+        );
+        assert_eq!(edit_buffer.text(), "hello there");
+
+        press(
+            &mut history,
+            &mut edit_buffer,
+            KeyCode::Char('r'),
+            KeyModifiers::CONTROL,
+        );
+        assert_eq!(
+            history.search_prompt().as_deref(),
+            Some("(failed reverse search) ‘hel’")
+        );
+
+        // keys that aren’t part of searching end it, keeping what was found
+        assert!(!press(
+            &mut history,
+            &mut edit_buffer,
+            KeyCode::Enter,
+            KeyModifiers::NONE
+        ));
+        assert_eq!(history.search_prompt(), None);
+        assert_eq!(edit_buffer.text(), "hello there");
+    }
+
+    #[test]
+    fn cancelled_search_restores_line() {
+        let mut history = history(&["hello"]);
+        let mut edit_buffer = EditBuffer::default();
+        edit_buffer.insert_str("draft");
+
+        press(
+            &mut history,
+            &mut edit_buffer,
+            KeyCode::Char('r'),
+            KeyModifiers::CONTROL,
+        );
+        press(
+            &mut history,
+            &mut edit_buffer,
+            KeyCode::Char('h'),
+            KeyModifiers::NONE,
+        );
+        assert_eq!(edit_buffer.text(), "hello");
+
+        press(
+            &mut history,
+            &mut edit_buffer,
+            KeyCode::Char('g'),
+            KeyModifiers::CONTROL,
+        );
+        assert_eq!(edit_buffer.text(), "draft");
+    }
+
+    #[test]
+    fn history_is_kept_on_disk() {
+        let path = std::env::temp_dir().join(format!(
+            "nunitius-input-history-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        let mut history = InputHistory::open(&path).unwrap();
+        history.add("first").unwrap();
+        history.add("second\nline").unwrap();
+        history.add("second\nline").unwrap();
+        drop(history);
+
+        let history = InputHistory::open(&path).unwrap();
+        assert_eq!(history.entries, ["first", "second\nline"]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,138 +1,265 @@
-use crate::TypingEvent;
-use crossterm::{event, queue, terminal};
+use super::connection::ServerConnection;
+use super::edit_buffer::EditBuffer;
+use super::input_history::InputHistory;
+use crate::prompt::{enter_raw_mode, leave_raw_mode, print_text, read_password};
+use crate::{Color, LoginResponse, SenderEvent, TypingEvent, User};
+use crossterm::{cursor, event, execute, terminal};
 use flume::{RecvTimeoutError, Sender};
 use std::io::{self, Write};
+use std::thread;
 use std::time::Duration;
-use std::{fmt, thread};
 
 pub fn read_input(prompt: &str, stdout: &mut io::Stdout) -> anyhow::Result<Option<String>> {
-    let (typing_event_tx, _typing_event_rx) = flume::unbounded();
-    read_input_evented(prompt, stdout, typing_event_tx, |_, _| Ok(()))
+    let (sender_event_tx, _sender_event_rx) = flume::unbounded();
+    read_input_evented(
+        prompt,
+        stdout,
+        &mut InputHistory::default(),
+        sender_event_tx,
+        |_, _| Ok(()),
+    )
 }
 
+/// Reads a line, which is added to `history` once it’s entered.
 pub fn read_input_evented(
     prompt: &str,
     stdout: &mut io::Stdout,
-    typing_event_tx: Sender<TypingEvent>,
+    history: &mut InputHistory,
+    sender_event_tx: Sender<SenderEvent>,
     mut unknown_key_event_handler: impl FnMut(event::KeyCode, event::KeyModifiers) -> anyhow::Result<()>,
 ) -> anyhow::Result<Option<String>> {
-    let (pressed_key_tx, pressed_key_rx) = flume::bounded(0);
-
-    let handle = thread::spawn(move || {
-        let mut current_state = TypingEvent::Stop;
-        let mut finished = false;
-
-        while !finished {
-            let new_state = match pressed_key_rx.recv_timeout(Duration::from_millis(1000)) {
-                Ok(()) => {
-                    // a key was pressed before the timeout
-                    TypingEvent::Start
-                }
-                Err(RecvTimeoutError::Timeout) => {
-                    // no key was pressed before the timeout
-                    TypingEvent::Stop
-                }
-                Err(RecvTimeoutError::Disconnected) => {
-                    // the line has been read,
-                    // which means the user cannot type anything more,
-                    // so we send one more ‘stopped typing’ event
-                    // before returning
-                    finished = true;
-                    TypingEvent::Stop
-                }
-            };
-
-            if new_state != current_state {
-                current_state = new_state;
-                typing_event_tx.send(current_state).unwrap();
-            }
-        }
-    });
+    let typing_detector = TypingDetector::spawn(sender_event_tx);
 
     let mut edit_buffer = EditBuffer::default();
 
-    terminal::enable_raw_mode()?;
+    enter_raw_mode(stdout)?;
 
     loop {
-        print_line(prompt, &edit_buffer, stdout)?;
+        match history.search_prompt() {
+            Some(search_prompt) => print_line(&search_prompt, &edit_buffer, stdout)?,
+            None => print_line(prompt, &edit_buffer, stdout)?,
+        }
 
-        let event::KeyEvent { code, modifiers } =
-            if let event::Event::Key(key_event) = event::read()? {
-                key_event
-            } else {
+        let (code, modifiers) = match event::read()? {
+            event::Event::Key(event::KeyEvent {
+                code, modifiers, ..
+            }) => (code, modifiers),
+            event::Event::Paste(text) => {
+                history.stop_searching();
+                edit_buffer.paste(&text);
+                typing_detector.key_pressed();
                 continue;
-            };
+            }
+            _ => continue,
+        };
 
         match (code, modifiers) {
+            // this comes first so that searching the history gets every key it needs
+            _ if history.handle_key(&mut edit_buffer, code, modifiers) => {}
             (event::KeyCode::Char('c'), event::KeyModifiers::CONTROL) => {
-                terminal::disable_raw_mode()?;
+                leave_raw_mode(stdout)?;
                 std::process::exit(1);
             }
-            (event::KeyCode::Char(c), event::KeyModifiers::SHIFT) => {
-                edit_buffer.add_multiple(c.to_uppercase());
-            }
-            (event::KeyCode::Char(c), event::KeyModifiers::NONE) => edit_buffer.add(c),
-            (event::KeyCode::Enter, _) => {
-                drop(pressed_key_tx);
-                handle.join().unwrap();
+            // with shift or alt it starts a new line instead
+            (event::KeyCode::Enter, event::KeyModifiers::NONE) => {
+                typing_detector.finish();
                 break;
             }
-            (event::KeyCode::Backspace, _) => edit_buffer.backspace(),
-            _ => {
-                unknown_key_event_handler(code, modifiers)?;
-                continue;
-            }
+            _ if edit_buffer.handle_key(code, modifiers) => typing_detector.key_pressed(),
+            _ => unknown_key_event_handler(code, modifiers)?,
         }
-
-        pressed_key_tx.send(()).unwrap();
     }
 
-    terminal::disable_raw_mode()?;
+    leave_raw_mode(stdout)?;
     writeln!(stdout)?;
 
-    let s = edit_buffer.to_string();
-    let s = s.trim().to_string();
+    let line = edit_buffer.take();
 
-    Ok(if s.is_empty() { None } else { Some(s) })
-}
+    match line {
+        Some(ref line) => history.add(line)?,
+        None => history.reset(),
+    }
 
-#[derive(Default)]
-struct EditBuffer {
-    buffer: Vec<char>,
+    Ok(line)
 }
 
-impl EditBuffer {
-    fn add(&mut self, c: char) {
-        self.buffer.push(c);
-    }
+/// Asks for whatever we weren’t given until the server lets us in.
+pub fn log_in(
+    connection: &mut ServerConnection,
+    mut nickname: Option<String>,
+    mut password: Option<String>,
+    color: Option<Color>,
+    stdout: &mut io::Stdout,
+    stderr: &mut io::Stderr,
+) -> anyhow::Result<(User, String)> {
+    loop {
+        // only ask for a nickname and password if we weren’t given them
+        // or they didn’t work
+        let nickname = match nickname.take() {
+            Some(nickname) => nickname,
+            None => match read_and_clear("Nickname", stdout)? {
+                Some(nickname) => nickname,
+                None => continue,
+            },
+        };
+
+        let password = match password.take() {
+            Some(password) => password,
+            None => read_password_and_clear("Password", stdout)?.unwrap_or_default(),
+        };
+
+        let color = match color {
+            Some(ref color) => Some(color.clone()),
+            None => read_color(stdout, stderr)?,
+        };
 
-    fn add_multiple(&mut self, cs: impl Iterator<Item = char>) {
-        self.buffer.extend(cs);
+        let user = User {
+            nickname: nickname.clone(),
+            color,
+        };
+
+        match connection.log_in(user.clone(), password.clone())? {
+            LoginResponse::LoggedIn => return Ok((user, password)),
+            LoginResponse::NicknameTaken => {
+                writeln!(stderr, "‘{}’ is already logged in elsewhere.", nickname)?
+            }
+            LoginResponse::BadCredentials => writeln!(stderr, "Incorrect nickname or password.")?,
+        }
     }
+}
 
-    fn backspace(&mut self) {
-        self.buffer.pop();
+pub fn read_color(
+    stdout: &mut io::Stdout,
+    stderr: &mut io::Stderr,
+) -> anyhow::Result<Option<Color>> {
+    loop {
+        let color = if let Some(s) = read_and_clear("Choose a color", stdout)? {
+            s
+        } else {
+            return Ok(None);
+        };
+
+        match color.parse() {
+            Ok(color) => return Ok(Some(color)),
+            Err(e) => writeln!(stderr, "{}", e)?,
+        }
     }
 }
 
-impl fmt::Display for EditBuffer {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for c in &self.buffer {
-            write!(f, "{}", c)?;
+/// Like [`read_input`], but leaves no trace of the prompt behind.
+pub fn read_and_clear(prompt: &str, stdout: &mut io::Stdout) -> anyhow::Result<Option<String>> {
+    let output = read_input(prompt, stdout)?;
+
+    execute!(
+        stdout,
+        cursor::MoveUp(1),
+        terminal::Clear(terminal::ClearType::CurrentLine),
+    )?;
+
+    Ok(output)
+}
+
+pub fn read_password_and_clear(
+    prompt: &str,
+    stdout: &mut io::Stdout,
+) -> anyhow::Result<Option<String>> {
+    let output = read_password(prompt, stdout)?;
+
+    execute!(
+        stdout,
+        cursor::MoveUp(1),
+        terminal::Clear(terminal::ClearType::CurrentLine),
+    )?;
+
+    Ok(output)
+}
+
+/// Works out whether someone is typing from their key presses,
+/// sending a typing event whenever that changes.
+///
+/// The events go out alongside everything else that’s sent,
+/// so that they reach the server in the order they happened.
+pub struct TypingDetector {
+    pressed_key_tx: Sender<()>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl TypingDetector {
+    pub fn spawn(sender_event_tx: Sender<SenderEvent>) -> Self {
+        let (pressed_key_tx, pressed_key_rx) = flume::bounded(0);
+
+        let handle = thread::spawn(move || {
+            let mut current_state = TypingEvent::Stop;
+            let mut finished = false;
+
+            while !finished {
+                let new_state = match pressed_key_rx.recv_timeout(Duration::from_millis(1000)) {
+                    Ok(()) => {
+                        // a key was pressed before the timeout
+                        TypingEvent::Start
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        // no key was pressed before the timeout
+                        TypingEvent::Stop
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        // the line has been read,
+                        // which means the user cannot type anything more,
+                        // so we send one more ‘stopped typing’ event
+                        // before returning
+                        finished = true;
+                        TypingEvent::Stop
+                    }
+                };
+
+                if new_state != current_state {
+                    current_state = new_state;
+                    sender_event_tx
+                        .send(SenderEvent::Typing(current_state))
+                        .unwrap();
+                }
+            }
+        });
+
+        Self {
+            pressed_key_tx,
+            handle,
         }
+    }
+
+    pub fn key_pressed(&self) {
+        self.pressed_key_tx.send(()).unwrap();
+    }
 
-        Ok(())
+    /// Stops typing, returning once that’s been queued to send,
+    /// so it can’t arrive after the finished line if that’s sent next.
+    pub fn finish(self) {
+        drop(self.pressed_key_tx);
+        self.handle.join().unwrap();
     }
 }
 
-fn print_line(
+/// Shows `prompt` and what’s been typed so far,
+/// with the terminal’s cursor where the edit buffer’s is.
+pub fn print_line(
     prompt: &str,
-    current_input: &impl fmt::Display,
+    edit_buffer: &EditBuffer,
     stdout: &mut io::Stdout,
 ) -> anyhow::Result<()> {
-    queue!(stdout, terminal::Clear(terminal::ClearType::CurrentLine))?;
-    write!(stdout, "\r{} > {}", prompt, current_input)?;
-    stdout.flush()?;
+    let (before_cursor, after_cursor) = edit_buffer.split_at_cursor();
+
+    print_text(prompt, &shown(before_cursor), &shown(after_cursor), stdout)
+}
 
-    Ok(())
+/// Makes text fit on the single row the input line has,
+/// with the cursor still in the right place.
+fn shown(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\n' => '↵',
+            // how wide a tab is depends on where it is
+            '\t' => ' ',
+            c => c,
+        })
+        .collect()
 }
@@ -0,0 +1,260 @@
+use crate::{FileMetadata, SenderEvent};
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use uuid::Uuid;
+
+/// The most to send in one go,
+/// so that messages sent during an upload don’t wait long behind it.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How much to send before hearing back from the server,
+/// so there’s always something on its way without the server falling far behind.
+const WINDOW_SIZE: u64 = 4 * CHUNK_SIZE as u64;
+
+/// How much of the file to look at to work out what kind it is.
+const SNIFF_SIZE: usize = 8 * 1024;
+
+/// A file being sent to the server a chunk at a time.
+pub struct Upload {
+    id: Uuid,
+    metadata: FileMetadata,
+    file: File,
+    /// How much of the file the server has, once it’s ready for more.
+    received: Option<u64>,
+    sent: u64,
+    /// The percentage last reported to the user.
+    reported_percent: Option<u64>,
+}
+
+impl Upload {
+    /// Reads through the file once to describe it,
+    /// failing with a message meant for the user.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let open = || -> io::Result<_> {
+            let mut file = File::open(path)?;
+            let (size, sha256, start) = hash(&mut file)?;
+            file.rewind()?;
+            Ok((file, size, sha256, start))
+        };
+        let (file, size, sha256, start) =
+            open().with_context(|| format!("couldn’t read ‘{}’", path.display()))?;
+
+        let filename = match path.file_name() {
+            Some(filename) => filename.to_string_lossy().into_owned(),
+            None => path.display().to_string(),
+        };
+
+        Ok(Self {
+            id: Uuid::new_v4(),
+            metadata: FileMetadata {
+                filename,
+                size,
+                mime_type: detect_mime_type(path, &start),
+                sha256,
+            },
+            file,
+            received: None,
+            sent: 0,
+            reported_percent: None,
+        })
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn metadata(&self) -> &FileMetadata {
+        &self.metadata
+    }
+
+    /// Asks the server to get ready for the file on a new connection,
+    /// which carries on from however much of it the server says it has.
+    pub(super) fn start(&mut self) -> SenderEvent {
+        self.received = None;
+        self.sent = 0;
+
+        SenderEvent::StartUpload {
+            id: self.id,
+            metadata: self.metadata.clone(),
+        }
+    }
+
+    /// Whether there’s more to send that the server has room for.
+    pub(super) fn has_chunk_ready(&self) -> bool {
+        match self.received {
+            Some(received) => {
+                self.sent < self.metadata.size && self.sent.saturating_sub(received) < WINDOW_SIZE
+            }
+            None => false,
+        }
+    }
+
+    pub(super) fn next_chunk(&mut self) -> anyhow::Result<SenderEvent> {
+        let len = CHUNK_SIZE.min((self.metadata.size - self.sent) as usize);
+        let mut data = vec![0; len];
+
+        self.file.seek(SeekFrom::Start(self.sent))?;
+        self.file.read_exact(&mut data).with_context(|| {
+            format!(
+                "‘{}’ changed while it was being uploaded",
+                self.metadata.filename
+            )
+        })?;
+
+        let offset = self.sent;
+        self.sent += len as u64;
+
+        Ok(SenderEvent::UploadChunk {
+            id: self.id,
+            offset,
+            data,
+        })
+    }
+
+    /// Notes how much the server has received,
+    /// returning the percentage of the file that is
+    /// if it’s changed since it was last reported.
+    pub(super) fn acknowledge(&mut self, received: u64) -> Option<u64> {
+        self.received = Some(received);
+        // the server may already have had some or all of the file
+        self.sent = self.sent.max(received);
+
+        let percent = match self.metadata.size {
+            0 => 100,
+            size => received * 100 / size,
+        };
+
+        if self.reported_percent == Some(percent) {
+            None
+        } else {
+            self.reported_percent = Some(percent);
+            Some(percent)
+        }
+    }
+}
+
+/// Returns the size and hash of what’s left in the file,
+/// along with enough of its start to tell what kind of file it is.
+fn hash(file: &mut File) -> io::Result<(u64, String, Vec<u8>)> {
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut start = Vec::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+
+    loop {
+        let len = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        hasher.update(&buf[..len]);
+        size += len as u64;
+
+        if start.len() < SNIFF_SIZE {
+            let wanted = (SNIFF_SIZE - start.len()).min(len);
+            start.extend_from_slice(&buf[..wanted]);
+        }
+    }
+
+    Ok((size, format!("{:x}", hasher.finalize()), start))
+}
+
+/// Trusts what the contents look like over the file’s extension,
+/// though plain text only has the extension to go on.
+fn detect_mime_type(path: &Path, start: &[u8]) -> String {
+    if let Some(kind) = infer::get(start) {
+        return kind.mime_type().to_string();
+    }
+
+    mime_guess::from_path(path)
+        .first_raw()
+        .unwrap_or("application/octet-stream")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nunitius-upload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn files_are_described_by_their_contents_and_name() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+        assert_eq!(
+            Upload::open(&temp_file("notes.txt", b"hello\n"))
+                .unwrap()
+                .metadata,
+            FileMetadata {
+                filename: "notes.txt".to_string(),
+                size: 6,
+                mime_type: "text/plain".to_string(),
+                sha256: "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03"
+                    .to_string(),
+            }
+        );
+        assert_eq!(
+            Upload::open(&temp_file("screenshot.txt", png))
+                .unwrap()
+                .metadata
+                .mime_type,
+            "image/png"
+        );
+        assert_eq!(
+            Upload::open(&temp_file("data", b"\x01\x02"))
+                .unwrap()
+                .metadata
+                .mime_type,
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn chunks_are_only_sent_once_the_server_has_room() {
+        let contents: Vec<u8> = (0..WINDOW_SIZE + 10).map(|i| i as u8).collect();
+        let mut upload = Upload::open(&temp_file("big", &contents)).unwrap();
+
+        upload.start();
+        assert!(!upload.has_chunk_ready());
+        assert_eq!(upload.acknowledge(0), Some(0));
+
+        let mut sent = Vec::new();
+        while upload.has_chunk_ready() {
+            match upload.next_chunk().unwrap() {
+                SenderEvent::UploadChunk { offset, data, .. } => {
+                    assert_eq!(offset, sent.len() as u64);
+                    sent.extend(data);
+                }
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(sent.len() as u64, WINDOW_SIZE);
+
+        // the percentage is only reported when it changes
+        assert_eq!(upload.acknowledge(CHUNK_SIZE as u64), Some(24));
+        assert_eq!(upload.acknowledge(CHUNK_SIZE as u64 + 1), None);
+        assert!(upload.has_chunk_ready());
+        match upload.next_chunk().unwrap() {
+            SenderEvent::UploadChunk { data, .. } => sent.extend(data),
+            _ => unreachable!(),
+        }
+
+        assert_eq!(sent, contents);
+        assert!(!upload.has_chunk_ready());
+    }
+}
@@ -1,28 +1,93 @@
+mod accounts;
 mod connection_handler;
+mod event_log;
+mod file_store;
 mod history_handler;
+mod lines;
 mod nickname_handler;
 mod sender_handler;
+mod sequence_handler;
+mod upload;
 mod viewer_handler;
 
+pub use accounts::Accounts;
 pub use connection_handler::handle_connection;
+pub use event_log::{EventLog, FsyncPolicy};
+pub use file_store::FileStore;
 pub use history_handler::history_handler;
 pub use nickname_handler::nickname_handler;
 pub use sender_handler::sender_handler;
+pub use sequence_handler::sequence_handler;
+pub use upload::UploadSettings;
 pub use viewer_handler::viewer_handler;
 
-use crate::Event;
+use crate::stream::ServerStream;
+use crate::{Capability, HistoryPage, HistoryQuery, User};
 use flume::Sender;
+use std::collections::HashSet;
+use tokio::io::{BufReader, ReadHalf, WriteHalf};
+
+/// The halves of a connection
+/// that the connection handler passes on to the handler for its kind.
+pub type Reader = BufReader<ReadHalf<ServerStream>>;
+pub type Writer = WriteHalf<ServerStream>;
+
+/// A client that has said hello and told us what kind it is.
+pub struct Connection {
+    pub reader: Reader,
+    pub writer: Writer,
+    /// What both the client and the server support.
+    pub capabilities: HashSet<Capability>,
+}
 
 pub enum NicknameEvent {
     Login {
         nickname: String,
-        is_taken_tx: Sender<bool>,
+        status_tx: Sender<NicknameStatus>,
     },
     Logout {
         nickname: String,
     },
+    /// A logged-in sender would like to be known by a different nickname,
+    /// which is answered with [`NicknameStatus::Available`] if they now are
+    /// or [`NicknameStatus::Taken`] if it’s in use.
+    Rename {
+        old_nickname: String,
+        new_nickname: String,
+        status_tx: Sender<NicknameStatus>,
+    },
+    /// The sender’s connection died without them logging out.
+    Disconnected {
+        user: User,
+        room: String,
+    },
+    IsLoggedIn {
+        nickname: String,
+        is_logged_in_tx: Sender<bool>,
+    },
 }
 
-pub struct HistoryRequest {
-    history_tx: Sender<Vec<Event>>,
+#[derive(Debug, Clone, PartialEq)]
+pub enum NicknameStatus {
+    Available,
+    Taken,
+    /// The nickname belonged to someone whose connection died recently,
+    /// and they’ve now reconnected; they were last in `room`.
+    Reclaimed {
+        room: String,
+    },
+}
+
+pub enum HistoryRequest {
+    /// The room’s history, with the direct messages `nickname` sent or received
+    /// mixed in where they happened.
+    Events {
+        room: String,
+        nickname: String,
+        query: HistoryQuery,
+        pages_tx: Sender<Vec<HistoryPage>>,
+    },
+    Rooms {
+        rooms_tx: Sender<Vec<String>>,
+    },
 }
@@ -0,0 +1,318 @@
+use anyhow::Context;
+use argon2::password_hash::phc::PasswordHash;
+use argon2::password_hash::{PasswordHasher, PasswordVerifier};
+use argon2::Argon2;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Semaphore;
+
+/// How many wrong passwords a connection can give before it’s closed.
+pub const MAX_FAILED_LOGINS: u32 = 5;
+
+/// How long to wait before answering the `num_failures`th wrong password on a connection,
+/// doubling each time so that passwords can’t be guessed quickly.
+pub fn failed_login_delay(num_failures: u32) -> Duration {
+    Duration::from_millis(500) * 2_u32.pow(num_failures.clamp(1, MAX_FAILED_LOGINS) - 1)
+}
+
+/// The registered users who are allowed to log in,
+/// stored on disk as JSON Lines.
+///
+/// Only an Argon2 hash of each password is kept.
+/// The file is read again whenever it changes,
+/// so passwords set while the server is running take effect straight away.
+pub struct Accounts {
+    path: PathBuf,
+    loaded: RwLock<Loaded>,
+    /// Checked against when there’s no account with the nickname given,
+    /// so that how long checking takes doesn’t give away which accounts exist.
+    dummy_hash: String,
+    hasher: Argon2<'static>,
+    /// Limits how many passwords are checked at once,
+    /// since each check takes a lot of memory.
+    verifying: Semaphore,
+}
+
+/// The accounts as they were when the file was last read.
+struct Loaded {
+    password_hashes: BTreeMap<String, String>,
+    /// The file’s modification time and size then,
+    /// which is how we tell it’s changed.
+    version: Option<(SystemTime, u64)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Account {
+    nickname: String,
+    password_hash: String,
+}
+
+impl Accounts {
+    /// Reads the accounts stored at `path`;
+    /// there are none if the file doesn’t exist yet.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+
+        let loaded = Loaded::read(path)?;
+
+        let hasher = Argon2::default();
+        let dummy_hash = hasher
+            .hash_password("not anyone’s password".as_bytes())
+            .map_err(|e| anyhow::anyhow!("failed to hash password: {}", e))?
+            .to_string();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            loaded: RwLock::new(loaded),
+            dummy_hash,
+            hasher,
+            verifying: Semaphore::new(
+                thread::available_parallelism().map_or(1, |parallelism| parallelism.get()),
+            ),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.loaded.read().unwrap().password_hashes.is_empty()
+    }
+
+    pub fn verify(&self, nickname: &str, password: &str) -> bool {
+        self.reload_if_changed();
+
+        let password_hash = self
+            .loaded
+            .read()
+            .unwrap()
+            .password_hashes
+            .get(nickname)
+            .cloned();
+        let is_registered = password_hash.is_some();
+        let password_hash = password_hash.as_deref().unwrap_or(&self.dummy_hash);
+
+        let is_correct = match PasswordHash::new(password_hash) {
+            Ok(password_hash) => self
+                .hasher
+                .verify_password(password.as_bytes(), &password_hash)
+                .is_ok(),
+            Err(_) => false,
+        };
+
+        is_correct && is_registered
+    }
+
+    /// Like [`Accounts::verify`], but runs on a thread where blocking is fine,
+    /// because hashing is deliberately slow.
+    pub async fn verify_async(self: Arc<Self>, nickname: String, password: String) -> bool {
+        let _permit = self.verifying.acquire().await.unwrap();
+
+        let accounts = Arc::clone(&self);
+        tokio::task::spawn_blocking(move || accounts.verify(&nickname, &password))
+            .await
+            .unwrap()
+    }
+
+    /// Registers a new account or changes the password of an existing one,
+    /// saving the change to disk straight away.
+    pub fn set_password(&mut self, nickname: &str, password: &str) -> anyhow::Result<()> {
+        let password_hash = self
+            .hasher
+            .hash_password(password.as_bytes())
+            .map_err(|e| anyhow::anyhow!("failed to hash password: {}", e))?
+            .to_string();
+
+        self.reload_if_changed();
+        self.loaded
+            .get_mut()
+            .unwrap()
+            .password_hashes
+            .insert(nickname.to_string(), password_hash);
+
+        self.save()
+            .with_context(|| format!("failed to save accounts to {}", self.path.display()))
+    }
+
+    /// Reads the file again if it’s changed since it was last read,
+    /// keeping the accounts we have if it can’t be.
+    fn reload_if_changed(&self) {
+        if version(&self.path) == self.loaded.read().unwrap().version {
+            return;
+        }
+
+        match Loaded::read(&self.path) {
+            Ok(loaded) => *self.loaded.write().unwrap() = loaded,
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+
+    fn save(&mut self) -> anyhow::Result<()> {
+        let loaded = self.loaded.get_mut().unwrap();
+
+        let mut buf = Vec::new();
+        for (nickname, password_hash) in &loaded.password_hashes {
+            jsonl::write(
+                &mut buf,
+                &Account {
+                    nickname: nickname.clone(),
+                    password_hash: password_hash.clone(),
+                },
+            )?;
+        }
+
+        // write to a temporary file first
+        // so a crash can’t leave us with half the accounts missing
+        let temp_path = self.path.with_extension("tmp");
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(&buf)?;
+        temp_file.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+
+        // we already know what’s in it
+        loaded.version = version(&self.path);
+
+        Ok(())
+    }
+}
+
+impl Loaded {
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        // taken first, so that a change made while we’re reading is noticed next time
+        let version = version(path);
+
+        let password_hashes = read_accounts(path)
+            .with_context(|| format!("failed to read accounts from {}", path.display()))?;
+
+        info!("loaded {} accounts", password_hashes.len());
+
+        Ok(Self {
+            password_hashes,
+            version,
+        })
+    }
+}
+
+/// Identifies what’s in the file at `path` without reading it,
+/// or `None` if there’s no file.
+fn version(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+fn read_accounts(path: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut reader = io::BufReader::new(file);
+    let mut password_hashes = BTreeMap::new();
+
+    loop {
+        match jsonl::read(&mut reader) {
+            Ok(Account {
+                nickname,
+                password_hash,
+            }) => {
+                password_hashes.insert(nickname, password_hash);
+            }
+            Err(jsonl::ReadError::Eof) => return Ok(password_hashes),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::{Algorithm, Params, Version};
+
+    // the default parameters are deliberately slow,
+    // which adds up in unoptimised test builds
+    fn open_for_test(name: &str) -> Accounts {
+        let path = std::env::temp_dir().join(format!(
+            "nunitius-accounts-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        let mut accounts = Accounts::open(path).unwrap();
+        accounts.hasher = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(Params::MIN_M_COST, Params::MIN_T_COST, 1, None).unwrap(),
+        );
+
+        accounts
+    }
+
+    #[test]
+    fn missing_file_has_no_accounts() {
+        assert!(open_for_test("missing").is_empty());
+    }
+
+    #[test]
+    fn only_correct_password_is_accepted() {
+        let mut accounts = open_for_test("verify");
+        accounts.set_password("luna", "hunter2").unwrap();
+
+        assert!(accounts.verify("luna", "hunter2"));
+        assert!(!accounts.verify("luna", "hunter3"));
+        assert!(!accounts.verify("someone-else", "hunter2"));
+    }
+
+    #[test]
+    fn unknown_nicknames_are_checked_like_any_other() {
+        let accounts = open_for_test("dummy");
+
+        assert!(PasswordHash::new(&accounts.dummy_hash).is_ok());
+        assert!(!accounts.verify("someone", "not anyone’s password"));
+    }
+
+    #[test]
+    fn failed_logins_are_answered_more_and_more_slowly() {
+        assert_eq!(failed_login_delay(1), Duration::from_millis(500));
+        assert_eq!(failed_login_delay(2), Duration::from_secs(1));
+        assert_eq!(
+            failed_login_delay(MAX_FAILED_LOGINS + 10),
+            failed_login_delay(MAX_FAILED_LOGINS)
+        );
+    }
+
+    #[test]
+    fn passwords_are_hashed_and_persisted() {
+        let mut accounts = open_for_test("persist");
+        accounts.set_password("luna", "hunter2").unwrap();
+
+        let contents = fs::read_to_string(&accounts.path).unwrap();
+        assert!(!contents.contains("hunter2"));
+        assert!(contents.contains("$argon2id$"));
+
+        let accounts = Accounts::open(&accounts.path).unwrap();
+        assert!(accounts.verify("luna", "hunter2"));
+    }
+
+    #[test]
+    fn passwords_set_elsewhere_are_picked_up() {
+        let running = open_for_test("reload");
+        assert!(running.is_empty());
+
+        // as if by `nunitius-server set-password` while the server is running
+        let mut accounts = open_for_test("reload");
+        accounts.set_password("luna", "hunter2").unwrap();
+
+        assert!(running.verify("luna", "hunter2"));
+        assert!(!running.is_empty());
+
+        accounts.set_password("luna", "hunter3").unwrap();
+        assert!(!running.verify("luna", "hunter2"));
+        assert!(running.verify("luna", "hunter3"));
+    }
+}
@@ -1,24 +1,289 @@
-use crate::ConnectionKind;
+use super::{lines, Connection, Writer};
+use crate::stream::Acceptor;
+use crate::{Capability, ConnectionKind, Hello, HelloResponse, PROTOCOL_VERSION};
+use anyhow::Context;
 use flume::Sender;
-use log::info;
-use std::io;
-use std::net::TcpStream;
-
-pub fn handle_connection(
-    stream: TcpStream,
-    sender_tx: Sender<TcpStream>,
-    viewer_tx: Sender<TcpStream>,
+use log::{info, warn};
+use std::time::Duration;
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// Greets a new client, works out whether it’s a sender or a viewer
+/// and passes it on to the right handler.
+///
+/// Clients that don’t identify themselves within `handshake_timeout`
+/// are disconnected.
+pub async fn handle_connection(
+    socket: TcpStream,
+    acceptor: Acceptor,
+    sender_tx: Sender<Connection>,
+    viewer_tx: Sender<Connection>,
+    handshake_timeout: Duration,
 ) -> anyhow::Result<()> {
-    let mut stream = io::BufReader::new(stream);
-    let connection_kind = jsonl::read(&mut stream)?;
-    let stream = stream.into_inner();
+    let peer = socket.peer_addr()?;
+
+    let identified = match time::timeout(handshake_timeout, identify(socket, &acceptor)).await {
+        Ok(identified) => identified.with_context(|| format!("handshake with {} failed", peer))?,
+        Err(_) => {
+            warn!("{} never said whether it’s a sender or a viewer", peer);
+            return Ok(());
+        }
+    };
+
+    let Some((connection_kind, connection)) = identified else {
+        warn!(
+            "{} disconnected without saying whether it’s a sender or a viewer",
+            peer
+        );
+        return Ok(());
+    };
 
     info!("connection kind: {:?}", connection_kind);
 
     match connection_kind {
-        ConnectionKind::Sender => sender_tx.send(stream).unwrap(),
-        ConnectionKind::Viewer => viewer_tx.send(stream).unwrap(),
+        ConnectionKind::Sender => sender_tx.send_async(connection).await.unwrap(),
+        ConnectionKind::Viewer => viewer_tx.send_async(connection).await.unwrap(),
     }
 
     Ok(())
 }
+
+/// Exchanges hellos and reads the connection kind,
+/// or returns `None` if the client hangs up first.
+async fn identify(
+    socket: TcpStream,
+    acceptor: &Acceptor,
+) -> anyhow::Result<Option<(ConnectionKind, Connection)>> {
+    let stream = acceptor.accept(socket).await?;
+    let (reader, mut writer) = tokio::io::split(stream);
+
+    // the reader is handed on rather than unwrapped
+    // so that anything the client sent straight after the connection kind
+    // isn’t lost in its buffer
+    let mut reader = BufReader::new(reader);
+
+    let hello: Hello = match lines::read(&mut reader).await {
+        Ok(hello) => hello,
+        Err(jsonl::ReadError::Eof) => return Ok(None),
+        // clients from before hellos existed start with their connection kind
+        Err(jsonl::ReadError::Deserialize(_)) => {
+            let reason = "this client is too old for the server, please upgrade it".to_string();
+            reject(&mut writer, reason.clone()).await?;
+            anyhow::bail!("client didn’t say hello: {}", reason);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    info!(
+        "hello from {} speaking protocol version {}",
+        hello.client_name, hello.protocol_version
+    );
+
+    if let Err(reason) = check_protocol_version(&hello) {
+        reject(&mut writer, reason.clone()).await?;
+        anyhow::bail!("rejected {}: {}", hello.client_name, reason);
+    }
+
+    // we can’t use anything the client hasn’t heard of,
+    // and it can’t use anything we haven’t
+    let capabilities = hello
+        .capabilities
+        .into_iter()
+        .filter(|capability| Capability::SUPPORTED.contains(capability))
+        .collect();
+
+    let mut connection = Connection {
+        reader,
+        writer,
+        capabilities,
+    };
+
+    lines::write(
+        &mut connection.writer,
+        &HelloResponse::Welcome {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: connection.capabilities.iter().copied().collect(),
+        },
+    )
+    .await?;
+
+    match lines::read(&mut connection.reader).await {
+        Ok(connection_kind) => Ok(Some((connection_kind, connection))),
+        Err(jsonl::ReadError::Eof) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Explains to the client’s user why we can’t talk to it.
+fn check_protocol_version(hello: &Hello) -> Result<(), String> {
+    if hello.protocol_version < PROTOCOL_VERSION {
+        Err(format!(
+            "{} speaks version {} of the protocol, but the server needs version {}; \
+             please upgrade it",
+            hello.client_name, hello.protocol_version, PROTOCOL_VERSION
+        ))
+    } else if hello.protocol_version > PROTOCOL_VERSION {
+        Err(format!(
+            "{} speaks version {} of the protocol, which is newer than the server’s version {}",
+            hello.client_name, hello.protocol_version, PROTOCOL_VERSION
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+async fn reject(writer: &mut Writer, reason: String) -> anyhow::Result<()> {
+    lines::write(writer, &HelloResponse::Rejected { reason }).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Returns the client’s end of a new connection and the server’s.
+    async fn connect() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        (client, socket)
+    }
+
+    /// A client that has said hello and what kind it is, and nothing else,
+    /// and the connection the server has for it.
+    pub(in crate::server) async fn identified_client(
+        connection_kind: ConnectionKind,
+    ) -> (TcpStream, Connection) {
+        let (mut client, socket) = connect().await;
+        let (sender_tx, sender_rx) = flume::unbounded();
+        let (viewer_tx, viewer_rx) = flume::unbounded();
+
+        lines::write(&mut client, &Hello::new("nunitius-test"))
+            .await
+            .unwrap();
+        lines::write(&mut client, &connection_kind).await.unwrap();
+
+        handle_connection(
+            socket,
+            Acceptor { tls: None },
+            sender_tx,
+            viewer_tx,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+
+        let connection = match connection_kind {
+            ConnectionKind::Sender => sender_rx.try_recv().unwrap(),
+            ConnectionKind::Viewer => viewer_rx.try_recv().unwrap(),
+        };
+
+        (client, connection)
+    }
+
+    /// Waits for the server to close the client’s connection,
+    /// failing if it takes too long.
+    pub(in crate::server) async fn assert_closed(mut client: TcpStream) {
+        let mut received = Vec::new();
+        time::timeout(Duration::from_secs(5), client.read_to_end(&mut received))
+            .await
+            .expect("connection was left open")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn silent_client_is_disconnected() {
+        let (mut client, socket) = connect().await;
+        let (sender_tx, sender_rx) = flume::unbounded();
+        let (viewer_tx, viewer_rx) = flume::unbounded();
+
+        handle_connection(
+            socket,
+            Acceptor { tls: None },
+            sender_tx,
+            viewer_tx,
+            Duration::from_millis(50),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(client.read(&mut [0; 1]).await.unwrap(), 0);
+        assert!(sender_rx.is_empty());
+        assert!(viewer_rx.is_empty());
+    }
+
+    #[tokio::test]
+    async fn client_with_newer_protocol_is_rejected() {
+        let (client, socket) = connect().await;
+        let (mut reader, mut writer) = client.into_split();
+        let (sender_tx, _sender_rx) = flume::unbounded();
+        let (viewer_tx, _viewer_rx) = flume::unbounded();
+
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..Hello::new("nunitius-viewer")
+        };
+        lines::write(&mut writer, &hello).await.unwrap();
+
+        let result = handle_connection(
+            socket,
+            Acceptor { tls: None },
+            sender_tx,
+            viewer_tx,
+            Duration::from_secs(5),
+        )
+        .await;
+        assert!(result.is_err());
+
+        let response: HelloResponse = lines::read(&mut BufReader::new(&mut reader)).await.unwrap();
+        assert!(matches!(response, HelloResponse::Rejected { .. }));
+    }
+
+    #[tokio::test]
+    async fn unknown_capabilities_are_ignored() {
+        let (client, socket) = connect().await;
+        let (mut reader, mut writer) = client.into_split();
+        let (sender_tx, _sender_rx) = flume::unbounded();
+        let (viewer_tx, viewer_rx) = flume::unbounded();
+
+        // as sent by some future client
+        writer
+            .write_all(
+                b"{\"protocol_version\":1,\"client_name\":\"nunitius-viewer 9.0.0\",\
+                  \"capabilities\":[\"Typing\",\"Reactions\"]}\n\"Viewer\"\n",
+            )
+            .await
+            .unwrap();
+
+        handle_connection(
+            socket,
+            Acceptor { tls: None },
+            sender_tx,
+            viewer_tx,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+
+        let response: HelloResponse = lines::read(&mut BufReader::new(&mut reader)).await.unwrap();
+        assert_eq!(
+            response,
+            HelloResponse::Welcome {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: vec![Capability::Typing],
+            }
+        );
+
+        let connection = viewer_rx.try_recv().unwrap();
+        assert_eq!(
+            connection.capabilities,
+            std::iter::once(Capability::Typing).collect()
+        );
+    }
+}
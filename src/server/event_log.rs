@@ -0,0 +1,394 @@
+use crate::Event;
+use anyhow::Context;
+use log::{info, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// How often the event log is flushed all the way to disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    /// Sync after every event, so nothing is lost if the machine crashes.
+    Always,
+    /// Sync after every `n` events.
+    EveryNEvents(usize),
+    /// Leave syncing up to the operating system.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    /// Parses `always`, `never`, or how many events to sync after.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => match s.parse() {
+                Ok(0) | Err(_) => Err(anyhow::anyhow!(
+                    "‘{}’ is an invalid fsync policy; use always, never or a number of events",
+                    s
+                )),
+                Ok(n) => Ok(Self::EveryNEvents(n)),
+            },
+        }
+    }
+}
+
+/// An append-only log of every event the server has seen,
+/// stored on disk as JSON Lines.
+pub struct EventLog {
+    path: PathBuf,
+    file: File,
+    fsync_policy: FsyncPolicy,
+    num_unsynced_events: usize,
+}
+
+impl EventLog {
+    /// Opens (or creates) the event log at `path`,
+    /// returning it along with all the events it already contains.
+    ///
+    /// If the server crashed while writing an event
+    /// the final line of the log may be incomplete;
+    /// that line is discarded and the file is truncated to the last complete event.
+    pub fn open(
+        path: impl AsRef<Path>,
+        fsync_policy: FsyncPolicy,
+    ) -> anyhow::Result<(Self, Vec<Event>)> {
+        let path = path.as_ref();
+
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("failed to open event log at {}", path.display()))?;
+
+        let (mut events, valid_len) = read_events(&file)
+            .with_context(|| format!("failed to read event log at {}", path.display()))?;
+
+        if valid_len != file.metadata()?.len() {
+            warn!("discarding truncated final line of event log");
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+
+        // events logged before sequence numbers existed have a sequence number of zero
+        let file = if events.iter().any(|event| event.seq == 0) {
+            info!("assigning sequence numbers to old events in event log");
+
+            for (idx, event) in events.iter_mut().enumerate() {
+                event.seq = idx as u64 + 1;
+                event.id = Uuid::new_v4();
+            }
+
+            rewrite(path, &events)
+                .with_context(|| format!("failed to rewrite event log at {}", path.display()))?
+        } else {
+            file
+        };
+
+        info!("loaded {} events from event log", events.len());
+
+        Ok((
+            Self {
+                path: path.to_path_buf(),
+                file,
+                fsync_policy,
+                num_unsynced_events: 0,
+            },
+            events,
+        ))
+    }
+
+    /// Atomically replaces everything in the log with `events`,
+    /// for when old events need updating.
+    pub fn replace_events(&mut self, events: &[Event]) -> anyhow::Result<()> {
+        self.file = rewrite(&self.path, events)
+            .with_context(|| format!("failed to rewrite event log at {}", self.path.display()))?;
+        self.num_unsynced_events = 0;
+
+        Ok(())
+    }
+
+    pub fn append(&mut self, event: &Event) -> anyhow::Result<()> {
+        // serialize into a buffer first
+        // so that the line reaches the file in a single write
+        let mut buf = Vec::new();
+        jsonl::write(&mut buf, event)?;
+        self.file.write_all(&buf)?;
+
+        self.num_unsynced_events += 1;
+
+        let should_sync = match self.fsync_policy {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryNEvents(n) => self.num_unsynced_events >= n,
+            FsyncPolicy::Never => false,
+        };
+
+        if should_sync {
+            self.file.sync_data()?;
+            self.num_unsynced_events = 0;
+        }
+
+        Ok(())
+    }
+}
+
+/// Atomically replaces the log at `path` with `events`,
+/// returning the new file opened for appending.
+fn rewrite(path: &Path, events: &[Event]) -> anyhow::Result<File> {
+    let temp_path = path.with_extension("tmp");
+
+    let mut buf = Vec::new();
+    for event in events {
+        jsonl::write(&mut buf, event)?;
+    }
+
+    let mut temp_file = File::create(&temp_path)?;
+    temp_file.write_all(&buf)?;
+    temp_file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+
+    Ok(OpenOptions::new().read(true).append(true).open(path)?)
+}
+
+/// Reads every complete event from the log,
+/// returning them along with the length in bytes of the valid prefix of the file.
+fn read_events(file: &File) -> anyhow::Result<(Vec<Event>, u64)> {
+    let mut reader = io::BufReader::new(file);
+    let mut events = Vec::new();
+    let mut valid_len = 0;
+    // read as bytes, since a crash can cut the last line off partway through a character
+    let mut line = Vec::new();
+    let mut line_number = 0;
+
+    loop {
+        line.clear();
+        let num_bytes_read = reader.read_until(b'\n', &mut line)?;
+        line_number += 1;
+
+        if num_bytes_read == 0 {
+            break;
+        }
+
+        // only the final line can be missing its newline,
+        // and then it was only partially written
+        if !line.ends_with(b"\n") {
+            break;
+        }
+
+        // whereas a whole line that can’t be read is corrupt wherever it is
+        match jsonl::read(&line[..]) {
+            Ok(event) => events.push(event),
+            Err(e) => {
+                return Err(anyhow::Error::new(e)
+                    .context(format!("event log is corrupt at line {}", line_number)));
+            }
+        }
+
+        valid_len += num_bytes_read as u64;
+    }
+
+    Ok((events, valid_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EventKind, User, DEFAULT_ROOM};
+    use chrono::Utc;
+    use std::path::PathBuf;
+
+    fn temp_log_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "nunitius-event-log-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn login_event(seq: u64, nickname: &str) -> Event {
+        Event {
+            seq,
+            id: Uuid::new_v4(),
+            event: EventKind::Login,
+            user: User {
+                nickname: nickname.to_string(),
+                color: None,
+            },
+            room: DEFAULT_ROOM.to_string(),
+            time_occurred: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn new_log_is_empty() {
+        let path = temp_log_path("new");
+
+        let (_, events) = EventLog::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(events, []);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn appended_events_are_loaded_on_reopen() {
+        let path = temp_log_path("reopen");
+        let event_1 = login_event(1, "alice");
+        let event_2 = login_event(2, "bob");
+
+        {
+            let (mut log, _) = EventLog::open(&path, FsyncPolicy::Always).unwrap();
+            log.append(&event_1).unwrap();
+            log.append(&event_2).unwrap();
+        }
+
+        let (_, events) = EventLog::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(events, [event_1, event_2]);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn truncated_final_line_is_discarded() {
+        let path = temp_log_path("truncated");
+        let event_1 = login_event(1, "alice");
+        let event_2 = login_event(2, "bob");
+
+        {
+            let (mut log, _) = EventLog::open(&path, FsyncPolicy::Always).unwrap();
+            log.append(&event_1).unwrap();
+        }
+
+        let mut half_written = Vec::new();
+        jsonl::write(&mut half_written, &event_2).unwrap();
+        half_written.truncate(half_written.len() / 2);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&half_written)
+            .unwrap();
+
+        {
+            let (mut log, events) = EventLog::open(&path, FsyncPolicy::Always).unwrap();
+            assert_eq!(events, std::slice::from_ref(&event_1));
+            log.append(&event_2).unwrap();
+        }
+
+        let (_, events) = EventLog::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(events, [event_1, event_2]);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn final_line_cut_off_inside_a_character_is_discarded() {
+        let path = temp_log_path("truncated-utf8");
+        let event_1 = login_event(1, "alice");
+        let event_2 = login_event(2, "zoë");
+
+        let mut contents = Vec::new();
+        jsonl::write(&mut contents, &event_1).unwrap();
+        jsonl::write(&mut contents, &event_2).unwrap();
+        let cut = contents
+            .windows(2)
+            .rposition(|bytes| bytes == "ë".as_bytes())
+            .unwrap();
+        contents.truncate(cut + 1);
+        fs::write(&path, contents).unwrap();
+
+        let (_, events) = EventLog::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(events, [event_1]);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn events_logged_before_rooms_existed_are_in_the_default_room() {
+        let path = temp_log_path("roomless");
+
+        fs::write(
+            &path,
+            r#"{"event":"Login","user":{"nickname":"alice","color":null},"time_occurred":"2021-04-01T12:00:00Z"}"#.to_string() + "\n",
+        )
+        .unwrap();
+
+        let (_, events) = EventLog::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(events[0].room, DEFAULT_ROOM);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn events_logged_before_sequence_numbers_existed_are_assigned_them() {
+        let path = temp_log_path("unsequenced");
+
+        let mut old_event = login_event(0, "alice");
+        old_event.id = Uuid::nil();
+        let mut contents = Vec::new();
+        jsonl::write(&mut contents, &old_event).unwrap();
+        jsonl::write(&mut contents, &old_event).unwrap();
+        fs::write(&path, contents).unwrap();
+
+        let (_, events) = EventLog::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(events[0].seq, 1);
+        assert_eq!(events[1].seq, 2);
+        assert_ne!(events[0].id, events[1].id);
+
+        // the new sequence numbers and IDs are stable across restarts
+        let (_, reopened_events) = EventLog::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(reopened_events, events);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corruption_before_final_line_is_an_error() {
+        let path = temp_log_path("corrupt");
+
+        let mut contents = b"not an event\n".to_vec();
+        jsonl::write(&mut contents, &login_event(1, "alice")).unwrap();
+        fs::write(&path, contents).unwrap();
+
+        assert!(EventLog::open(&path, FsyncPolicy::Always).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn complete_final_line_that_isnt_an_event_is_an_error() {
+        let path = temp_log_path("corrupt-final");
+
+        let mut contents = Vec::new();
+        jsonl::write(&mut contents, &login_event(1, "alice")).unwrap();
+        contents.extend(b"not an event\n");
+        fs::write(&path, &contents).unwrap();
+
+        assert!(EventLog::open(&path, FsyncPolicy::Always).is_err());
+        // and nothing was thrown away
+        assert_eq!(fs::read(&path).unwrap(), contents);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn fsync_policies_are_parsed() {
+        assert_eq!(
+            "always".parse::<FsyncPolicy>().unwrap(),
+            FsyncPolicy::Always
+        );
+        assert_eq!("never".parse::<FsyncPolicy>().unwrap(), FsyncPolicy::Never);
+        assert_eq!(
+            "100".parse::<FsyncPolicy>().unwrap(),
+            FsyncPolicy::EveryNEvents(100)
+        );
+        assert!("0".parse::<FsyncPolicy>().is_err());
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use crate::{Event, EventKind, FileMetadata, Message};
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// The files senders have uploaded,
+/// each stored once under the SHA-256 hash of its contents
+/// no matter how many times it’s been sent.
+///
+/// Uploads are kept apart until they’ve been checked,
+/// so everything in the store is complete.
+/// They’re also kept when a sender’s connection is lost,
+/// so the sender can carry on where it left off.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Opens (or creates) the store in `dir`,
+    /// throwing away any uploads that were cut off when the server last stopped.
+    pub fn open(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        let store = Self {
+            dir: dir.to_path_buf(),
+        };
+
+        let open = || -> io::Result<()> {
+            match fs::remove_dir_all(store.partial_dir()) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            fs::create_dir_all(store.partial_dir())
+        };
+        open().with_context(|| format!("failed to open file store at {}", dir.display()))?;
+
+        Ok(store)
+    }
+
+    /// Runs `f` on a thread where blocking is fine,
+    /// so that a slow disk doesn’t hold up other connections.
+    pub async fn run<T: Send + 'static>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&Self) -> T + Send + 'static,
+    ) -> T {
+        let store = Arc::clone(self);
+        tokio::task::spawn_blocking(move || f(&store))
+            .await
+            .unwrap()
+    }
+
+    pub fn contains(&self, sha256: &str) -> bool {
+        self.path(sha256).is_some_and(|path| path.is_file())
+    }
+
+    /// How big the file with this hash is, if the store has it.
+    pub fn size(&self, sha256: &str) -> Option<u64> {
+        let metadata = fs::metadata(self.path(sha256)?).ok()?;
+        metadata.is_file().then_some(metadata.len())
+    }
+
+    /// Reads up to `len` bytes of a file, starting at `offset`,
+    /// failing with a reason meant for the user.
+    pub fn read(&self, sha256: &str, offset: u64, len: u64) -> anyhow::Result<Vec<u8>> {
+        let mut file = match self.path(sha256).map(File::open) {
+            Some(Ok(file)) => file,
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => anyhow::bail!("the server doesn’t have that file"),
+        };
+
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut data = Vec::new();
+        file.take(len).read_to_end(&mut data)?;
+
+        Ok(data)
+    }
+
+    /// Starts writing an upload, or carries on with one that was cut off,
+    /// returning how much of it there already is and the hash of that much.
+    ///
+    /// Anything bigger than the file it’s meant to be starts again from scratch.
+    pub fn resume_partial(&self, id: Uuid, size: u64) -> io::Result<(u64, Sha256)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.partial_path(id))?;
+
+        let mut hasher = Sha256::new();
+        let received = io::copy(&mut file, &mut hasher)?;
+
+        if received > size {
+            file.set_len(0)?;
+            return Ok((0, Sha256::new()));
+        }
+
+        Ok((received, hasher))
+    }
+
+    pub fn append_to_partial(&self, id: Uuid, data: &[u8]) -> io::Result<()> {
+        OpenOptions::new()
+            .append(true)
+            .open(self.partial_path(id))?
+            .write_all(data)
+    }
+
+    pub fn remove_partial(&self, id: Uuid) -> io::Result<()> {
+        fs::remove_file(self.partial_path(id))
+    }
+
+    /// Moves a finished upload into the store,
+    /// unless the store already has the same file.
+    pub fn commit_partial(&self, id: Uuid, sha256: &str) -> io::Result<()> {
+        let partial_path = self.partial_path(id);
+        let path = self
+            .path(sha256)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid hash"))?;
+
+        if path.is_file() {
+            return fs::remove_file(partial_path);
+        }
+
+        // the file has to be on disk before any event refers to it
+        File::open(&partial_path)?.sync_all()?;
+        fs::rename(partial_path, path)
+    }
+
+    /// Stores a file that arrived whole,
+    /// returning its metadata with the size and hash worked out here.
+    ///
+    /// Files sent before metadata existed are given a generic name.
+    pub fn insert(
+        &self,
+        metadata: Option<FileMetadata>,
+        contents: &[u8],
+    ) -> io::Result<FileMetadata> {
+        let sha256 = format!("{:x}", Sha256::digest(contents));
+
+        if !self.contains(&sha256) {
+            let id = Uuid::new_v4();
+            fs::write(self.partial_path(id), contents)?;
+            self.commit_partial(id, &sha256)?;
+        }
+
+        let (filename, mime_type) = match metadata {
+            Some(metadata) => (metadata.filename, metadata.mime_type),
+            None => (
+                "file".to_string(),
+                infer::get(contents)
+                    .map_or("application/octet-stream", |kind| kind.mime_type())
+                    .to_string(),
+            ),
+        };
+
+        Ok(FileMetadata {
+            filename,
+            size: contents.len() as u64,
+            mime_type,
+            sha256,
+        })
+    }
+
+    /// Moves the contents of files sent before the server stored them
+    /// out of `events` and into the store,
+    /// returning whether there were any.
+    pub fn take_contents(&self, events: &mut [Event]) -> io::Result<bool> {
+        let mut found_contents = false;
+
+        for event in events {
+            let message = match event.event {
+                EventKind::Message(ref mut message)
+                | EventKind::DirectMessage {
+                    ref mut message, ..
+                } => message,
+                _ => continue,
+            };
+
+            if let Message::File { metadata, contents } = message {
+                if !contents.is_empty() {
+                    *metadata = Some(self.insert(metadata.take(), &mem::take(contents))?);
+                    found_contents = true;
+                }
+            }
+        }
+
+        Ok(found_contents)
+    }
+
+    /// Only a hash can name a file,
+    /// so nobody can ask for anything else on disk.
+    fn path(&self, sha256: &str) -> Option<PathBuf> {
+        crate::is_sha256(sha256).then(|| self.dir.join(sha256))
+    }
+
+    fn partial_dir(&self) -> PathBuf {
+        self.dir.join("partial")
+    }
+
+    fn partial_path(&self, id: Uuid) -> PathBuf {
+        self.partial_dir().join(id.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::User;
+    use chrono::Utc;
+
+    fn temp_store(name: &str) -> FileStore {
+        let dir =
+            std::env::temp_dir().join(format!("nunitius-files-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        FileStore::open(dir).unwrap()
+    }
+
+    fn file_event(contents: &[u8]) -> Event {
+        Event {
+            seq: 1,
+            id: Uuid::new_v4(),
+            event: EventKind::Message(Message::File {
+                metadata: None,
+                contents: contents.to_vec(),
+            }),
+            user: User {
+                nickname: "luna".to_string(),
+                color: None,
+            },
+            room: "general".to_string(),
+            time_occurred: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn files_are_stored_once_and_read_back_in_parts() {
+        let store = temp_store("dedup");
+
+        let first = store.insert(None, b"hello\n").unwrap();
+        let second = store.insert(None, b"hello\n").unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.size, 6);
+        assert_eq!(fs::read_dir(&store.dir).unwrap().count(), 2);
+        assert_eq!(store.size(&first.sha256), Some(6));
+        assert_eq!(store.size(&"0".repeat(64)), None);
+
+        assert_eq!(store.read(&first.sha256, 2, 3).unwrap(), b"llo");
+        // only the end of the file cuts a read short
+        assert_eq!(store.read(&first.sha256, 4, 100).unwrap(), b"o\n");
+        assert_eq!(store.read(&first.sha256, 10, 100).unwrap(), b"");
+
+        assert!(store.read(&"0".repeat(64), 0, 1).is_err());
+        assert!(store.read("../partial", 0, 1).is_err());
+    }
+
+    #[test]
+    fn old_events_have_their_contents_moved_into_the_store() {
+        let store = temp_store("migration");
+        let mut events = vec![file_event(b"hello\n"), file_event(b"")];
+
+        assert!(store.take_contents(&mut events).unwrap());
+
+        let metadata = match events[0].event {
+            EventKind::Message(Message::File {
+                metadata: Some(ref metadata),
+                ref contents,
+            }) if contents.is_empty() => metadata.clone(),
+            _ => panic!("contents weren’t moved: {:?}", events[0]),
+        };
+        assert_eq!(metadata.filename, "file");
+        assert_eq!(store.read(&metadata.sha256, 0, 100).unwrap(), b"hello\n");
+
+        // there’s nothing left to move the second time round
+        assert!(!store.take_contents(&mut events).unwrap());
+    }
+}
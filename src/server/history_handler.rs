@@ -1,24 +1,444 @@
-use super::HistoryRequest;
-use crate::Event;
+use super::{EventLog, HistoryRequest};
+use crate::{Event, EventKind, HistoryPage, HistoryQuery, DEFAULT_ROOM};
 use flume::{Receiver, Selector};
-use log::info;
+use log::{error, info};
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Range;
 
-pub fn history_handler(event_rx: Receiver<Event>, request_rx: Receiver<HistoryRequest>) {
-    let events = RefCell::new(Vec::new());
+const PAGE_SIZE: usize = 100;
+const MAX_EVENTS_PER_QUERY: usize = 1000;
+
+pub fn history_handler(
+    event_rx: Receiver<Event>,
+    request_rx: Receiver<HistoryRequest>,
+    mut event_log: EventLog,
+    existing_events: Vec<Event>,
+    mut direct_message_log: EventLog,
+    direct_messages: Vec<Event>,
+) {
+    let mut rooms: HashMap<String, Vec<Event>> = HashMap::new();
+    rooms.insert(DEFAULT_ROOM.to_string(), Vec::new());
+
+    for event in existing_events {
+        rooms.entry(event.room.clone()).or_default().push(event);
+    }
+
+    let rooms = RefCell::new(rooms);
+    let direct_messages = RefCell::new(direct_messages);
 
     loop {
         Selector::new()
             .recv(&event_rx, |event| {
-                events.borrow_mut().push(event.unwrap());
+                let event = event.unwrap();
+
+                // direct messages are kept out of the public history
+                if event.event.is_direct_message() {
+                    if let Err(e) = direct_message_log.append(&event) {
+                        error!("failed to persist direct message: {:#}", e);
+                    }
+
+                    direct_messages.borrow_mut().push(event);
+                    info!("added direct message to log");
+                    return;
+                }
+
+                if let Err(e) = event_log.append(&event) {
+                    error!("failed to persist event: {:#}", e);
+                }
+
+                rooms
+                    .borrow_mut()
+                    .entry(event.room.clone())
+                    .or_default()
+                    .push(event);
                 info!("added event to history");
             })
             .recv(&request_rx, |request| {
-                let HistoryRequest { history_tx } = request.unwrap();
-                let events = events.borrow().clone();
-                history_tx.send(events).unwrap();
+                match request.unwrap() {
+                    HistoryRequest::Events {
+                        room,
+                        nickname,
+                        query,
+                        pages_tx,
+                    } => {
+                        let rooms = rooms.borrow();
+                        let events = rooms.get(&room).map_or(&[][..], |events| events);
+                        let direct_messages: Vec<_> = direct_messages
+                            .borrow()
+                            .iter()
+                            .filter(|event| is_between(event, &nickname))
+                            .cloned()
+                            .collect();
+
+                        let pages = paginate(events, &direct_messages, &room, query);
+                        pages_tx.send(pages).unwrap();
+                    }
+
+                    HistoryRequest::Rooms { rooms_tx } => {
+                        let mut room_names: Vec<_> = rooms.borrow().keys().cloned().collect();
+                        room_names.sort();
+                        rooms_tx.send(room_names).unwrap();
+                    }
+                }
+
                 info!("replied to request for history");
             })
             .wait();
     }
 }
+
+/// Splits the part of the history the query asks for into pages.
+///
+/// The history is the room’s `events` with the viewer’s `direct_messages` mixed in
+/// where they happened, and a page’s indices are into that.
+/// Since both only ever grow at the end an index keeps pointing at the same event,
+/// and direct messages count towards how much is sent like any other event.
+fn paginate(
+    events: &[Event],
+    direct_messages: &[Event],
+    room: &str,
+    query: HistoryQuery,
+) -> Vec<HistoryPage> {
+    let mut history: Vec<_> = events.iter().chain(direct_messages).collect();
+    history.sort_by_key(|event| event.seq);
+
+    let (range, more_after) = match query {
+        HistoryQuery::Latest { count } => {
+            let count = count.min(MAX_EVENTS_PER_QUERY);
+            (history.len().saturating_sub(count)..history.len(), None)
+        }
+        HistoryQuery::Before { before, count } => {
+            let count = count.min(MAX_EVENTS_PER_QUERY);
+            let end = before.min(history.len());
+            (end.saturating_sub(count)..end, None)
+        }
+        HistoryQuery::After { seq } => {
+            let start = history.partition_point(|event| event.seq <= seq);
+            let end = history.len().min(start + MAX_EVENTS_PER_QUERY);
+
+            // a viewer that’s been away for a while pages through what it missed
+            // rather than having all of it sent at once
+            let more_after = (end < history.len()).then(|| history[end - 1].seq);
+
+            (start..end, more_after)
+        }
+    };
+
+    let page = |range: Range<usize>| HistoryPage {
+        room: room.to_string(),
+        events: history[range.clone()].iter().copied().cloned().collect(),
+        first_index: range.start,
+        is_last: false,
+        more_after: None,
+    };
+
+    let mut pages: Vec<_> = (range.start..range.end)
+        .step_by(PAGE_SIZE)
+        .map(|first_index| page(first_index..range.end.min(first_index + PAGE_SIZE)))
+        .collect();
+
+    if pages.is_empty() {
+        pages.push(page(range));
+    }
+
+    let last_page = pages.last_mut().unwrap();
+    last_page.is_last = true;
+    last_page.more_after = more_after;
+
+    pages
+}
+
+/// Whether `event` is a direct message `nickname` sent or received.
+fn is_between(event: &Event, nickname: &str) -> bool {
+    match event.event {
+        EventKind::DirectMessage { ref to, .. } => {
+            event.user.nickname == nickname || to == nickname
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, User};
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    fn events(num_events: usize) -> Vec<Event> {
+        (0..num_events)
+            .map(|i| Event {
+                seq: i as u64 + 1,
+                id: Uuid::nil(),
+                event: EventKind::Login,
+                user: User {
+                    nickname: i.to_string(),
+                    color: None,
+                },
+                room: DEFAULT_ROOM.to_string(),
+                time_occurred: Utc::now(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn empty_history_replies_with_one_empty_page() {
+        let pages = paginate(&[], &[], DEFAULT_ROOM, HistoryQuery::Latest { count: 10 });
+
+        assert_eq!(
+            pages,
+            [HistoryPage {
+                room: DEFAULT_ROOM.to_string(),
+                events: Vec::new(),
+                first_index: 0,
+                is_last: true,
+                more_after: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn latest_returns_the_end_of_history() {
+        let events = events(10);
+        let pages = paginate(
+            &events,
+            &[],
+            DEFAULT_ROOM,
+            HistoryQuery::Latest { count: 3 },
+        );
+
+        assert_eq!(
+            pages,
+            [HistoryPage {
+                room: DEFAULT_ROOM.to_string(),
+                events: events[7..].to_vec(),
+                first_index: 7,
+                is_last: true,
+                more_after: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn before_returns_events_preceding_index() {
+        let events = events(10);
+        let pages = paginate(
+            &events,
+            &[],
+            DEFAULT_ROOM,
+            HistoryQuery::Before {
+                before: 5,
+                count: 3,
+            },
+        );
+
+        assert_eq!(
+            pages,
+            [HistoryPage {
+                room: DEFAULT_ROOM.to_string(),
+                events: events[2..5].to_vec(),
+                first_index: 2,
+                is_last: true,
+                more_after: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn before_stops_at_start_of_history() {
+        let events = events(10);
+        let pages = paginate(
+            &events,
+            &[],
+            DEFAULT_ROOM,
+            HistoryQuery::Before {
+                before: 2,
+                count: 5,
+            },
+        );
+
+        assert_eq!(
+            pages,
+            [HistoryPage {
+                room: DEFAULT_ROOM.to_string(),
+                events: events[..2].to_vec(),
+                first_index: 0,
+                is_last: true,
+                more_after: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn large_replies_are_split_into_pages() {
+        let events = events(PAGE_SIZE * 2 + 1);
+        let pages = paginate(&events, &[], DEFAULT_ROOM, HistoryQuery::After { seq: 0 });
+
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0].events.len(), PAGE_SIZE);
+        assert_eq!(pages[1].first_index, PAGE_SIZE);
+        assert_eq!(pages[2].events, events[PAGE_SIZE * 2..]);
+        assert!(!pages[0].is_last);
+        assert!(!pages[1].is_last);
+        assert!(pages[2].is_last);
+    }
+
+    #[test]
+    fn after_returns_events_with_greater_sequence_numbers() {
+        let events = events(10);
+        let pages = paginate(&events, &[], DEFAULT_ROOM, HistoryQuery::After { seq: 7 });
+
+        assert_eq!(
+            pages,
+            [HistoryPage {
+                room: DEFAULT_ROOM.to_string(),
+                events: events[7..].to_vec(),
+                first_index: 7,
+                is_last: true,
+                more_after: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn latest_is_bounded() {
+        let events = events(MAX_EVENTS_PER_QUERY + 1);
+        let pages = paginate(
+            &events,
+            &[],
+            DEFAULT_ROOM,
+            HistoryQuery::Latest { count: usize::MAX },
+        );
+
+        let num_events: usize = pages.iter().map(|page| page.events.len()).sum();
+        assert_eq!(num_events, MAX_EVENTS_PER_QUERY);
+        assert_eq!(pages[0].first_index, 1);
+    }
+
+    #[test]
+    fn after_is_bounded_and_says_where_to_carry_on_from() {
+        let events = events(MAX_EVENTS_PER_QUERY + 10);
+        let pages = paginate(&events, &[], DEFAULT_ROOM, HistoryQuery::After { seq: 5 });
+
+        let num_events: usize = pages.iter().map(|page| page.events.len()).sum();
+        assert_eq!(num_events, MAX_EVENTS_PER_QUERY);
+        assert!(pages[..pages.len() - 1]
+            .iter()
+            .all(|page| page.more_after.is_none()));
+
+        let more_after = pages.last().unwrap().more_after.unwrap();
+        assert_eq!(more_after, MAX_EVENTS_PER_QUERY as u64 + 5);
+
+        let pages = paginate(
+            &events,
+            &[],
+            DEFAULT_ROOM,
+            HistoryQuery::After { seq: more_after },
+        );
+        assert_eq!(pages.last().unwrap().events.len(), 5);
+        assert_eq!(pages.last().unwrap().more_after, None);
+    }
+
+    #[test]
+    fn direct_messages_are_mixed_in_where_they_happened() {
+        // leaving odd sequence numbers for the direct messages
+        let events: Vec<_> = events(PAGE_SIZE + 10)
+            .into_iter()
+            .map(|event| Event {
+                seq: event.seq * 2,
+                ..event
+            })
+            .collect();
+        let page_size = PAGE_SIZE as u64;
+        let direct_message = |seq, from: &str, to: &str| Event {
+            seq,
+            event: EventKind::DirectMessage {
+                to: to.to_string(),
+                message: Message::Text {
+                    body: "hi".to_string(),
+                },
+            },
+            user: User {
+                nickname: from.to_string(),
+                color: None,
+            },
+            ..events[0].clone()
+        };
+        let direct_messages: Vec<_> = vec![
+            direct_message(5, "luna", "bob"),
+            direct_message(page_size * 2 + 7, "bob", "luna"),
+            direct_message(page_size * 2 + 21, "luna", "bob"),
+            direct_message(7, "alice", "bob"),
+        ]
+        .into_iter()
+        .filter(|event| is_between(event, "luna"))
+        .collect();
+
+        let pages = paginate(
+            &events,
+            &direct_messages,
+            DEFAULT_ROOM,
+            HistoryQuery::After { seq: 0 },
+        );
+        assert_eq!(pages[0].events.len(), PAGE_SIZE);
+        assert_eq!(pages[0].events[2], direct_messages[0]);
+        assert_eq!(pages[1].first_index, PAGE_SIZE);
+        assert_eq!(pages[1].events.len(), 13);
+        assert_eq!(pages[1].events[4], direct_messages[1]);
+        assert_eq!(pages[1].events[12], direct_messages[2]);
+
+        // what the viewer has already seen isn’t sent again
+        let pages = paginate(
+            &events,
+            &direct_messages,
+            DEFAULT_ROOM,
+            HistoryQuery::After {
+                seq: page_size * 2 + 20,
+            },
+        );
+        assert_eq!(pages[0].events, [direct_messages[2].clone()]);
+    }
+
+    #[test]
+    fn direct_messages_count_towards_how_much_is_sent() {
+        let direct_messages: Vec<_> = events(PAGE_SIZE * 2 + 1)
+            .into_iter()
+            .map(|event| Event {
+                event: EventKind::DirectMessage {
+                    to: "luna".to_string(),
+                    message: Message::Text {
+                        body: "hi".to_string(),
+                    },
+                },
+                ..event
+            })
+            .collect();
+
+        // a room nobody has said anything in yet
+        let pages = paginate(
+            &[],
+            &direct_messages,
+            "new",
+            HistoryQuery::Latest { count: PAGE_SIZE },
+        );
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].events, direct_messages[PAGE_SIZE + 1..]);
+        assert_eq!(pages[0].first_index, PAGE_SIZE + 1);
+
+        let pages = paginate(
+            &[],
+            &direct_messages,
+            "new",
+            HistoryQuery::Before {
+                before: PAGE_SIZE + 1,
+                count: PAGE_SIZE,
+            },
+        );
+        assert_eq!(pages[0].events, direct_messages[1..PAGE_SIZE + 1]);
+
+        let pages = paginate(&[], &direct_messages, "new", HistoryQuery::After { seq: 0 });
+        assert_eq!(pages.len(), 3);
+        assert!(pages.iter().all(|page| page.events.len() <= PAGE_SIZE));
+    }
+}
@@ -0,0 +1,96 @@
+//! Asynchronous versions of [`jsonl::read`] and [`jsonl::write`].
+//!
+//! The jsonl crate can be built for either blocking or async IO but not both,
+//! and the clients need the blocking version.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The longest line, newline included, that [`read`] will accept.
+///
+/// This is plenty for anything a client sends besides whole files,
+/// and stops a client from using up the server’s memory
+/// by never ending a line.
+pub const MAX_LINE_LEN: u64 = 1024 * 1024;
+
+pub async fn read<R: AsyncBufRead + Unpin, T: DeserializeOwned>(
+    reader: &mut R,
+) -> Result<T, jsonl::ReadError> {
+    read_limited(reader, MAX_LINE_LEN).await
+}
+
+/// Like [`read`], but for lines of up to `max_len` bytes.
+///
+/// Longer lines are an error,
+/// after which the connection is out of step and should be dropped.
+pub async fn read_limited<R: AsyncBufRead + Unpin, T: DeserializeOwned>(
+    reader: &mut R,
+    max_len: u64,
+) -> Result<T, jsonl::ReadError> {
+    let mut buf = Vec::new();
+    let num_bytes_read = reader.take(max_len).read_until(b'\n', &mut buf).await?;
+
+    if num_bytes_read == 0 {
+        return Err(jsonl::ReadError::Eof);
+    }
+
+    if num_bytes_read as u64 == max_len && buf.last() != Some(&b'\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line is longer than {} bytes", max_len),
+        )
+        .into());
+    }
+
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+pub async fn write<W: AsyncWrite + Unpin, T: Serialize>(
+    writer: &mut W,
+    t: &T,
+) -> Result<(), jsonl::WriteError> {
+    let mut json = serde_json::to_string(t)?;
+    json.push('\n');
+
+    writer.write_all(json.as_bytes()).await?;
+    // otherwise TLS connections hold on to what we’ve written
+    writer.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn line_at_limit_is_read() {
+        let mut reader: &[u8] = b"\"abc\"\n";
+        let s: String = read_limited(&mut reader, 6).await.unwrap();
+        assert_eq!(s, "abc");
+    }
+
+    #[tokio::test]
+    async fn line_over_limit_is_an_error() {
+        let mut reader: &[u8] = b"\"abcd\"\n";
+        let result: Result<String, _> = read_limited(&mut reader, 6).await;
+        assert!(matches!(result, Err(jsonl::ReadError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn unterminated_line_over_limit_is_an_error() {
+        // as sent by a client trying to make the server buffer forever
+        let mut reader = tokio::io::BufReader::new(tokio::io::repeat(b' '));
+        let result: Result<String, _> = read_limited(&mut reader, 1024).await;
+        assert!(matches!(result, Err(jsonl::ReadError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn final_line_without_newline_is_read() {
+        let mut reader: &[u8] = b"\"abc\"";
+        let s: String = read_limited(&mut reader, 6).await.unwrap();
+        assert_eq!(s, "abc");
+    }
+}
@@ -1,44 +1,335 @@
-use super::NicknameEvent;
-use flume::{Receiver, Sender};
-use log::info;
-use std::collections::HashSet;
+use super::{NicknameEvent, NicknameStatus};
+use crate::{Event, EventKind, User};
+use chrono::Utc;
+use flume::{Receiver, RecvTimeoutError, Sender};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
-pub fn nickname_handler(nickname_event_rx: Receiver<NicknameEvent>) {
+/// Someone whose connection died without them logging out.
+struct Disconnected {
+    user: User,
+    room: String,
+    reclaimable_until: Instant,
+}
+
+/// Keeps track of who is logged in.
+///
+/// When a sender’s connection dies unexpectedly
+/// their nickname stays reserved for `grace_period`,
+/// so they can reconnect without everyone seeing them log out and back in;
+/// only once it’s over is their `Logout` event sent.
+pub fn nickname_handler(
+    nickname_event_rx: Receiver<NicknameEvent>,
+    event_tx: Sender<Event>,
+    grace_period: Duration,
+) {
     let mut taken_nicknames = HashSet::new();
+    let mut disconnected: HashMap<String, Disconnected> = HashMap::new();
+
+    loop {
+        let next_expiry = disconnected
+            .values()
+            .map(|disconnected| disconnected.reclaimable_until)
+            .min();
+
+        let nickname_event = match next_expiry {
+            Some(deadline) => match nickname_event_rx.recv_deadline(deadline) {
+                Ok(nickname_event) => nickname_event,
+                Err(RecvTimeoutError::Timeout) => {
+                    expire_grace_periods(&mut disconnected, &event_tx);
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match nickname_event_rx.recv() {
+                Ok(nickname_event) => nickname_event,
+                Err(_) => break,
+            },
+        };
 
-    for nickname_event in nickname_event_rx {
         match nickname_event {
             NicknameEvent::Login {
                 nickname,
-                is_taken_tx,
-            } => handle_login(&mut taken_nicknames, nickname, is_taken_tx),
+                status_tx,
+            } => handle_login(&mut taken_nicknames, &mut disconnected, nickname, status_tx),
 
             NicknameEvent::Logout { ref nickname } => handle_logout(&mut taken_nicknames, nickname),
+
+            NicknameEvent::Rename {
+                ref old_nickname,
+                new_nickname,
+                status_tx,
+            } => handle_rename(
+                &mut taken_nicknames,
+                &disconnected,
+                old_nickname,
+                new_nickname,
+                status_tx,
+            ),
+
+            NicknameEvent::Disconnected { user, room } => {
+                info!("received disconnection");
+
+                // the event comes from a connection closing,
+                // so a mix-up here mustn’t take down every other sender
+                if !taken_nicknames.remove(&user.nickname) {
+                    warn!("disconnected nickname wasn’t taken, ignoring");
+                    continue;
+                }
+
+                disconnected.insert(
+                    user.nickname.clone(),
+                    Disconnected {
+                        user,
+                        room,
+                        reclaimable_until: Instant::now() + grace_period,
+                    },
+                );
+
+                expire_grace_periods(&mut disconnected, &event_tx);
+            }
+
+            // someone in their grace period will probably be back soon
+            NicknameEvent::IsLoggedIn {
+                ref nickname,
+                is_logged_in_tx,
+            } => is_logged_in_tx
+                .send(taken_nicknames.contains(nickname) || disconnected.contains_key(nickname))
+                .unwrap(),
         }
     }
 }
 
 fn handle_login(
     taken_nicknames: &mut HashSet<String>,
+    disconnected: &mut HashMap<String, Disconnected>,
     nickname: String,
-    is_taken_tx: Sender<bool>,
+    status_tx: Sender<NicknameStatus>,
 ) {
     info!("received login");
 
-    let is_nickname_taken = !taken_nicknames.insert(nickname);
-
-    if is_nickname_taken {
-        info!("nickname was taken");
-    } else {
+    let status = if let Some(Disconnected { room, .. }) = disconnected.remove(&nickname) {
+        info!("nickname was reclaimed");
+        taken_nicknames.insert(nickname);
+        NicknameStatus::Reclaimed { room }
+    } else if taken_nicknames.insert(nickname) {
         info!("nickname was not taken");
-    }
+        NicknameStatus::Available
+    } else {
+        info!("nickname was taken");
+        NicknameStatus::Taken
+    };
 
-    is_taken_tx.send(is_nickname_taken).unwrap();
+    status_tx.send(status).unwrap();
 }
 
 fn handle_logout(taken_nicknames: &mut HashSet<String>, nickname: &str) {
     info!("received logout");
 
-    let was_taken = taken_nicknames.remove(nickname);
-    assert!(was_taken);
+    if !taken_nicknames.remove(nickname) {
+        warn!("logged out nickname wasn’t taken, ignoring");
+    }
+}
+
+fn handle_rename(
+    taken_nicknames: &mut HashSet<String>,
+    disconnected: &HashMap<String, Disconnected>,
+    old_nickname: &str,
+    new_nickname: String,
+    status_tx: Sender<NicknameStatus>,
+) {
+    info!("received rename");
+
+    // a nickname in its grace period is kept for whoever was disconnected
+    let status =
+        if taken_nicknames.contains(&new_nickname) || disconnected.contains_key(&new_nickname) {
+            info!("new nickname was taken");
+            NicknameStatus::Taken
+        } else {
+            if !taken_nicknames.remove(old_nickname) {
+                warn!("old nickname wasn’t taken, ignoring");
+            }
+
+            taken_nicknames.insert(new_nickname);
+            NicknameStatus::Available
+        };
+
+    status_tx.send(status).unwrap();
+}
+
+fn expire_grace_periods(
+    disconnected: &mut HashMap<String, Disconnected>,
+    event_tx: &Sender<Event>,
+) {
+    let now = Instant::now();
+
+    let expired: Vec<_> = disconnected
+        .iter()
+        .filter(|(_, disconnected)| disconnected.reclaimable_until <= now)
+        .map(|(nickname, _)| nickname.clone())
+        .collect();
+
+    for nickname in expired {
+        info!("grace period expired, logging out");
+
+        let Disconnected { user, room, .. } = disconnected.remove(&nickname).unwrap();
+
+        event_tx
+            .send(Event {
+                seq: 0,
+                id: Uuid::nil(),
+                event: EventKind::Logout,
+                user,
+                room,
+                time_occurred: Utc::now(),
+            })
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn spawn_nickname_handler(grace_period: Duration) -> (Sender<NicknameEvent>, Receiver<Event>) {
+        let (nickname_event_tx, nickname_event_rx) = flume::unbounded();
+        let (event_tx, event_rx) = flume::unbounded();
+
+        thread::spawn(move || nickname_handler(nickname_event_rx, event_tx, grace_period));
+
+        (nickname_event_tx, event_rx)
+    }
+
+    fn log_in(nickname_event_tx: &Sender<NicknameEvent>, nickname: &str) -> NicknameStatus {
+        let (status_tx, status_rx) = flume::bounded(1);
+
+        nickname_event_tx
+            .send(NicknameEvent::Login {
+                nickname: nickname.to_string(),
+                status_tx,
+            })
+            .unwrap();
+
+        status_rx.recv().unwrap()
+    }
+
+    fn disconnect(nickname_event_tx: &Sender<NicknameEvent>, nickname: &str) {
+        nickname_event_tx
+            .send(NicknameEvent::Disconnected {
+                user: User {
+                    nickname: nickname.to_string(),
+                    color: None,
+                },
+                room: "general".to_string(),
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn nickname_is_taken_until_logout() {
+        let (nickname_event_tx, _event_rx) = spawn_nickname_handler(Duration::ZERO);
+
+        assert_eq!(
+            log_in(&nickname_event_tx, "luna"),
+            NicknameStatus::Available
+        );
+        assert_eq!(log_in(&nickname_event_tx, "luna"), NicknameStatus::Taken);
+
+        nickname_event_tx
+            .send(NicknameEvent::Logout {
+                nickname: "luna".to_string(),
+            })
+            .unwrap();
+
+        assert_eq!(
+            log_in(&nickname_event_tx, "luna"),
+            NicknameStatus::Available
+        );
+    }
+
+    #[test]
+    fn renaming_frees_the_old_nickname() {
+        let (nickname_event_tx, _event_rx) = spawn_nickname_handler(Duration::ZERO);
+
+        log_in(&nickname_event_tx, "luna");
+        log_in(&nickname_event_tx, "sol");
+
+        let rename = |new_nickname: &str| {
+            let (status_tx, status_rx) = flume::bounded(1);
+
+            nickname_event_tx
+                .send(NicknameEvent::Rename {
+                    old_nickname: "luna".to_string(),
+                    new_nickname: new_nickname.to_string(),
+                    status_tx,
+                })
+                .unwrap();
+
+            status_rx.recv().unwrap()
+        };
+
+        assert_eq!(rename("sol"), NicknameStatus::Taken);
+        assert_eq!(rename("selene"), NicknameStatus::Available);
+
+        assert_eq!(log_in(&nickname_event_tx, "selene"), NicknameStatus::Taken);
+        assert_eq!(
+            log_in(&nickname_event_tx, "luna"),
+            NicknameStatus::Available
+        );
+    }
+
+    #[test]
+    fn nickname_can_be_reclaimed_during_grace_period() {
+        let (nickname_event_tx, event_rx) = spawn_nickname_handler(Duration::from_secs(60));
+
+        log_in(&nickname_event_tx, "luna");
+        disconnect(&nickname_event_tx, "luna");
+
+        assert_eq!(
+            log_in(&nickname_event_tx, "luna"),
+            NicknameStatus::Reclaimed {
+                room: "general".to_string()
+            }
+        );
+        assert!(event_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn logout_is_sent_once_grace_period_expires() {
+        let (nickname_event_tx, event_rx) = spawn_nickname_handler(Duration::from_millis(50));
+
+        log_in(&nickname_event_tx, "luna");
+        disconnect(&nickname_event_tx, "luna");
+
+        let event = event_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(event.event, EventKind::Logout);
+        assert_eq!(event.user.nickname, "luna");
+
+        assert_eq!(
+            log_in(&nickname_event_tx, "luna"),
+            NicknameStatus::Available
+        );
+    }
+
+    #[test]
+    fn releasing_a_nickname_that_isnt_taken_is_ignored() {
+        let (nickname_event_tx, event_rx) = spawn_nickname_handler(Duration::ZERO);
+
+        nickname_event_tx
+            .send(NicknameEvent::Logout {
+                nickname: "luna".to_string(),
+            })
+            .unwrap();
+        disconnect(&nickname_event_tx, "luna");
+
+        // the handler is still running, and nobody was logged out
+        assert_eq!(
+            log_in(&nickname_event_tx, "luna"),
+            NicknameStatus::Available
+        );
+        assert!(event_rx.try_recv().is_err());
+    }
 }
@@ -1,79 +1,347 @@
-use super::NicknameEvent;
-use crate::{Event, EventKind, Login, LoginResponse, SenderEvent, User};
+use super::accounts::{self, MAX_FAILED_LOGINS};
+use super::upload::{self, Upload, UploadSettings};
+use super::{
+    lines, Accounts, Connection, HistoryRequest, NicknameEvent, NicknameStatus, Reader, Writer,
+};
+use crate::config::Heartbeat;
+use crate::{
+    Event, EventKind, Login, LoginResponse, SenderEvent, SenderMessage, TypingEvent, User,
+    DEFAULT_ROOM,
+};
 use chrono::Utc;
 use flume::{Receiver, Sender};
 use log::{error, info};
-use std::net::TcpStream;
-use std::{io, thread};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time;
+use uuid::Uuid;
 
-pub fn sender_handler(
-    sender_rx: Receiver<TcpStream>,
+pub async fn sender_handler(
+    sender_rx: Receiver<Connection>,
     nickname_event_tx: Sender<NicknameEvent>,
     event_tx: Sender<Event>,
+    history_request_tx: Sender<HistoryRequest>,
+    accounts: Arc<Accounts>,
+    heartbeat: Heartbeat,
+    upload_settings: UploadSettings,
 ) {
-    for stream in sender_rx {
+    while let Ok(connection) = sender_rx.recv_async().await {
         info!("received new sender");
         let nickname_event_tx = nickname_event_tx.clone();
         let event_tx = event_tx.clone();
+        let history_request_tx = history_request_tx.clone();
+        let accounts = Arc::clone(&accounts);
+        let upload_settings = upload_settings.clone();
 
-        thread::spawn(|| {
-            if let Err(e) = handle_sender(stream, nickname_event_tx, event_tx) {
+        tokio::spawn(async move {
+            if let Err(e) = handle_sender(
+                connection,
+                nickname_event_tx,
+                event_tx,
+                history_request_tx,
+                accounts,
+                heartbeat,
+                upload_settings,
+            )
+            .await
+            {
                 error!("{:#}", e);
             }
         });
     }
 }
 
-fn handle_sender(
-    stream: TcpStream,
+/// A logged-in sender.
+///
+/// Dropping it tells everyone else the sender has gone,
+/// no matter how the connection ended.
+struct Session {
+    user: User,
+    room: String,
+    /// The file the sender is partway through uploading.
+    upload: Option<Upload>,
+    /// How many wrong passwords the sender has given,
+    /// whether logging in or changing nickname.
+    failed_logins: u32,
+    /// Whether the sender last said they’d started typing,
+    /// so the room can be told they’ve stopped if they leave it.
+    typing: bool,
     nickname_event_tx: Sender<NicknameEvent>,
     event_tx: Sender<Event>,
+    /// Whether the sender closed the connection itself,
+    /// rather than it breaking.
+    logged_out: bool,
+}
+
+impl Session {
+    async fn send_event(&self, event: EventKind) {
+        self.event_tx.send_async(self.event(event)).await.unwrap();
+    }
+
+    /// Moves the sender to `room`,
+    /// making sure the room they leave doesn’t think they’re still typing.
+    async fn switch_room(&mut self, room: String) {
+        if let Some(event) = self.stop_typing() {
+            self.event_tx.send_async(event).await.unwrap();
+        }
+
+        self.send_event(EventKind::LeaveRoom).await;
+        self.room = room;
+        self.send_event(EventKind::JoinRoom).await;
+    }
+
+    fn stop_typing(&mut self) -> Option<Event> {
+        if std::mem::take(&mut self.typing) {
+            Some(self.event(EventKind::Typing(TypingEvent::Stop)))
+        } else {
+            None
+        }
+    }
+
+    fn event(&self, event: EventKind) -> Event {
+        Event {
+            seq: 0,
+            id: Uuid::nil(),
+            event,
+            user: self.user.clone(),
+            room: self.room.clone(),
+            time_occurred: Utc::now(),
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let nickname_event_tx = self.nickname_event_tx.clone();
+        let event_tx = self.event_tx.clone();
+
+        // a sender that’s gone can’t be typing, even if they come back
+        let mut events: Vec<Event> = self.stop_typing().into_iter().collect();
+
+        let nickname_event = if self.logged_out {
+            info!("logged out");
+            events.push(self.event(EventKind::Logout));
+
+            NicknameEvent::Logout {
+                nickname: self.user.nickname.clone(),
+            }
+        } else {
+            // the nickname handler will log them out
+            // if they don’t come back soon
+            info!("connection to sender was lost");
+
+            NicknameEvent::Disconnected {
+                user: self.user.clone(),
+                room: self.room.clone(),
+            }
+        };
+
+        // we can’t wait for the channels to have space here,
+        // and blocking would hold up other connections
+        tokio::spawn(async move {
+            let _ = nickname_event_tx.send_async(nickname_event).await;
+
+            for event in events {
+                let _ = event_tx.send_async(event).await;
+            }
+        });
+    }
+}
+
+async fn handle_sender(
+    Connection {
+        mut reader,
+        mut writer,
+        ..
+    }: Connection,
+    nickname_event_tx: Sender<NicknameEvent>,
+    event_tx: Sender<Event>,
+    history_request_tx: Sender<HistoryRequest>,
+    accounts: Arc<Accounts>,
+    heartbeat: Heartbeat,
+    upload_settings: UploadSettings,
 ) -> anyhow::Result<()> {
-    let mut connection = jsonl::Connection::new_from_tcp_stream(stream)?;
-    let user = log_sender_in(&mut connection, &nickname_event_tx, &event_tx)?;
+    let mut session = log_sender_in(
+        &mut reader,
+        &mut writer,
+        nickname_event_tx,
+        event_tx,
+        Arc::clone(&accounts),
+        heartbeat.timeout,
+    )
+    .await?;
+
+    let (sender_message_tx, sender_message_rx) = flume::unbounded();
+    tokio::spawn(write_sender_messages(
+        writer,
+        sender_message_rx,
+        heartbeat.interval,
+    ));
 
     loop {
-        let time_occurred = Utc::now();
-
-        match connection.read() {
-            Ok(sender_event) => {
-                let event = match sender_event {
-                    SenderEvent::Message(message) => {
-                        info!("received message");
-                        EventKind::Message(message)
-                    }
-                    SenderEvent::Typing(event) => {
-                        info!("received typing event");
-                        EventKind::Typing(event)
+        // senders send heartbeats while idle,
+        // so if we don’t hear from one for a while
+        // its connection is probably half-open
+        let sender_event = match time::timeout(
+            heartbeat.timeout,
+            lines::read_limited(&mut reader, upload_settings.max_line_len()),
+        )
+        .await
+        {
+            Ok(sender_event) => sender_event,
+            Err(_) => {
+                info!("sender stopped responding");
+                break;
+            }
+        };
+
+        match sender_event {
+            Ok(SenderEvent::Message(message)) => {
+                info!("received message");
+
+                match upload::check_message(message, &upload_settings).await {
+                    Ok(message) => session.send_event(EventKind::Message(message)).await,
+                    Err(e) => error!("rejected file from sender: {:#}", e),
+                }
+            }
+
+            Ok(SenderEvent::Typing(event)) => {
+                info!("received typing event");
+                session.typing = event == TypingEvent::Start;
+                session.send_event(EventKind::Typing(event)).await;
+            }
+
+            Ok(SenderEvent::DirectMessage { to, message }) => {
+                info!("received direct message");
+
+                let message = match upload::check_message(message, &upload_settings).await {
+                    Ok(message) => message,
+                    Err(e) => {
+                        error!("rejected file from sender: {:#}", e);
+                        continue;
                     }
                 };
 
-                event_tx
-                    .send(Event {
-                        event,
-                        user: user.clone(),
-                        time_occurred,
-                    })
-                    .unwrap();
+                if is_logged_in(to.clone(), &session.nickname_event_tx).await {
+                    session
+                        .send_event(EventKind::DirectMessage { to, message })
+                        .await;
+                } else {
+                    info!("recipient of direct message is not logged in");
+                    let _ = sender_message_tx.send(SenderMessage::NoSuchUser { nickname: to });
+                }
             }
 
-            Err(jsonl::ReadError::Eof) => {
-                info!("logged out");
+            Ok(SenderEvent::JoinRoom { room: new_room }) => {
+                info!("received request to join room");
 
-                nickname_event_tx
-                    .send(NicknameEvent::Logout {
-                        nickname: user.nickname.clone(),
-                    })
-                    .unwrap();
+                if new_room.trim().is_empty() {
+                    error!("sender tried to join a room with an empty name");
+                    continue;
+                }
+
+                if new_room != session.room {
+                    session.switch_room(new_room).await;
+                }
+            }
+
+            Ok(SenderEvent::LeaveRoom) => {
+                info!("received request to leave room");
+
+                if session.room != DEFAULT_ROOM {
+                    session.switch_room(DEFAULT_ROOM.to_string()).await;
+                }
+            }
 
-                event_tx
-                    .send(Event {
-                        event: EventKind::Logout,
-                        user,
-                        time_occurred,
-                    })
+            Ok(SenderEvent::ListRooms) => {
+                info!("received request to list rooms");
+
+                let (rooms_tx, rooms_rx) = flume::bounded(0);
+                history_request_tx
+                    .send_async(HistoryRequest::Rooms { rooms_tx })
+                    .await
                     .unwrap();
+                let rooms = rooms_rx.recv_async().await.unwrap();
+
+                let _ = sender_message_tx.send(SenderMessage::Rooms(rooms));
+            }
+
+            Ok(SenderEvent::ChangeNickname { nickname, password }) => {
+                info!("received request to change nickname");
+
+                let response =
+                    change_nickname(&mut session, nickname.clone(), password, &accounts).await;
+                let _ =
+                    sender_message_tx.send(SenderMessage::NicknameChange { nickname, response });
+
+                if session.failed_logins >= MAX_FAILED_LOGINS {
+                    anyhow::bail!("sender gave too many incorrect passwords");
+                }
+            }
+
+            Ok(SenderEvent::ChangeColor { color }) => {
+                info!("received request to change color");
+                session.user.color = color;
+            }
+
+            Ok(SenderEvent::StartUpload { id, metadata }) => {
+                info!("received request to upload a file");
+
+                let sender_message = match Upload::start(id, metadata, &upload_settings).await {
+                    Ok(upload) => {
+                        let received = upload.received();
+                        session.upload = Some(upload);
+                        SenderMessage::UploadProgress { id, received }
+                    }
+                    Err(e) => SenderMessage::UploadFailed {
+                        id,
+                        reason: format!("{:#}", e),
+                    },
+                };
+                let _ = sender_message_tx.send(sender_message);
+
+                // empty files, and ones the server already has,
+                // are finished as soon as they’ve started
+                if let Some(sender_message) = finish_upload(&mut session).await {
+                    let _ = sender_message_tx.send(sender_message);
+                }
+            }
+
+            Ok(SenderEvent::UploadChunk { id, offset, data }) => {
+                let upload = match session.upload {
+                    Some(ref mut upload) if upload.id() == id => upload,
+                    // the sender must have moved on to another file
+                    _ => continue,
+                };
 
+                let sender_message = match upload.write(offset, data).await {
+                    Ok(()) => SenderMessage::UploadProgress {
+                        id,
+                        received: upload.received(),
+                    },
+                    Err(e) => {
+                        info!("upload failed: {:#}", e);
+                        if let Some(upload) = session.upload.take() {
+                            upload.abandon().await;
+                        }
+                        SenderMessage::UploadFailed {
+                            id,
+                            reason: format!("{:#}", e),
+                        }
+                    }
+                };
+                let _ = sender_message_tx.send(sender_message);
+
+                if let Some(sender_message) = finish_upload(&mut session).await {
+                    let _ = sender_message_tx.send(sender_message);
+                }
+            }
+
+            Ok(SenderEvent::Heartbeat) => {}
+
+            Err(jsonl::ReadError::Eof) => {
+                session.logged_out = true;
                 break;
             }
 
@@ -84,52 +352,325 @@ fn handle_sender(
     Ok(())
 }
 
-fn log_sender_in(
-    connection: &mut TcpConnection,
-    nickname_event_tx: &Sender<NicknameEvent>,
-    event_tx: &Sender<Event>,
-) -> anyhow::Result<User> {
+/// Sends the file being uploaded as a message if all of it has arrived,
+/// returning what to tell the sender.
+async fn finish_upload(session: &mut Session) -> Option<SenderMessage> {
+    if !session.upload.as_ref()?.is_complete() {
+        return None;
+    }
+
+    let upload = session.upload.take().unwrap();
+    let id = upload.id();
+
+    match upload.finish().await {
+        Ok(message) => {
+            info!("received file");
+            session.send_event(EventKind::Message(message)).await;
+            Some(SenderMessage::UploadFinished { id })
+        }
+        Err(e) => {
+            info!("upload failed: {:#}", e);
+            Some(SenderMessage::UploadFailed {
+                id,
+                reason: format!("{:#}", e),
+            })
+        }
+    }
+}
+
+/// Passes on messages for the sender,
+/// filling any silence with heartbeats.
+async fn write_sender_messages(
+    mut writer: Writer,
+    sender_message_rx: Receiver<SenderMessage>,
+    interval: Duration,
+) {
     loop {
-        let login: Login = connection.read()?;
-        info!("read login from sender: {:?}", login);
+        let sender_message = match time::timeout(interval, sender_message_rx.recv_async()).await {
+            Ok(Ok(sender_message)) => sender_message,
+            // the sender’s session has ended
+            Ok(Err(_)) => break,
+            Err(_) => SenderMessage::Heartbeat,
+        };
 
-        let is_nickname_taken =
-            check_if_nickname_is_taken(login.user.nickname.clone(), nickname_event_tx)?;
+        if let Err(e) = lines::write(&mut writer, &sender_message).await {
+            error!("{:#}", anyhow::Error::new(e));
+            break;
+        }
+    }
 
-        connection.write(&LoginResponse {
-            nickname_taken: is_nickname_taken,
-        })?;
+    // make sure the sender notices if we stopped early
+    let _ = writer.shutdown().await;
+}
 
-        if is_nickname_taken {
-            info!("nickname was taken, retrying");
-        } else {
-            info!("logged in with unique nickname");
+async fn log_sender_in(
+    reader: &mut Reader,
+    writer: &mut Writer,
+    nickname_event_tx: Sender<NicknameEvent>,
+    event_tx: Sender<Event>,
+    accounts: Arc<Accounts>,
+    timeout: Duration,
+) -> anyhow::Result<Session> {
+    let mut failed_logins = 0;
+
+    loop {
+        // heartbeats only start once the sender is logged in,
+        // so until then a silent sender has to be caught here
+        let login: Login = time::timeout(timeout, lines::read(reader))
+            .await
+            .map_err(|_| anyhow::anyhow!("sender never sent its login"))??;
+        info!("read login from sender: {:?}", login.user);
+
+        let is_verified = Arc::clone(&accounts)
+            .verify_async(login.user.nickname.clone(), login.password)
+            .await;
+
+        if !is_verified {
+            failed_logins += 1;
+            time::sleep(accounts::failed_login_delay(failed_logins)).await;
+            lines::write(writer, &LoginResponse::BadCredentials).await?;
+
+            anyhow::ensure!(
+                failed_logins < MAX_FAILED_LOGINS,
+                "sender gave too many incorrect passwords"
+            );
+            info!("incorrect credentials, retrying");
+            continue;
+        }
+
+        let (is_new_login, room) =
+            match check_nickname(login.user.nickname.clone(), &nickname_event_tx).await? {
+                NicknameStatus::Taken => {
+                    info!("nickname was taken, retrying");
+                    lines::write(writer, &LoginResponse::NicknameTaken).await?;
+                    continue;
+                }
+                NicknameStatus::Available => (true, DEFAULT_ROOM.to_string()),
+                // they were only gone briefly,
+                // so carry on as if they never left
+                NicknameStatus::Reclaimed { room } => (false, room),
+            };
+
+        let session = Session {
+            user: login.user,
+            room,
+            upload: None,
+            failed_logins,
+            typing: false,
+            nickname_event_tx,
+            event_tx,
+            logged_out: false,
+        };
+
+        // if this fails the session is dropped,
+        // which releases the nickname again
+        lines::write(writer, &LoginResponse::LoggedIn).await?;
+
+        info!("logged in with unique nickname");
+
+        if is_new_login {
+            session.send_event(EventKind::Login).await;
+        }
+
+        return Ok(session);
+    }
+}
+
+/// Switches the session to another account,
+/// which has to be logged into like any other.
+async fn change_nickname(
+    session: &mut Session,
+    nickname: String,
+    password: String,
+    accounts: &Arc<Accounts>,
+) -> LoginResponse {
+    if nickname == session.user.nickname {
+        return LoginResponse::LoggedIn;
+    }
+
+    if !Arc::clone(accounts)
+        .verify_async(nickname.clone(), password)
+        .await
+    {
+        info!("incorrect credentials for new nickname");
+        session.failed_logins += 1;
+        time::sleep(accounts::failed_login_delay(session.failed_logins)).await;
+        return LoginResponse::BadCredentials;
+    }
 
-            event_tx
-                .send(Event {
-                    event: EventKind::Login,
-                    user: login.user.clone(),
-                    time_occurred: Utc::now(),
-                })
-                .unwrap();
+    let (status_tx, status_rx) = flume::bounded(0);
+    session
+        .nickname_event_tx
+        .send_async(NicknameEvent::Rename {
+            old_nickname: session.user.nickname.clone(),
+            new_nickname: nickname.clone(),
+            status_tx,
+        })
+        .await
+        .unwrap();
 
-            return Ok(login.user);
+    match status_rx.recv_async().await.unwrap() {
+        NicknameStatus::Available => {
+            let old_nickname = std::mem::replace(&mut session.user.nickname, nickname);
+            session
+                .send_event(EventKind::NicknameChange { old_nickname })
+                .await;
+
+            LoginResponse::LoggedIn
+        }
+        _ => {
+            info!("new nickname was taken");
+            LoginResponse::NicknameTaken
         }
     }
 }
 
-fn check_if_nickname_is_taken(
+async fn check_nickname(
     nickname: String,
     nickname_event_tx: &Sender<NicknameEvent>,
-) -> anyhow::Result<bool> {
-    let (is_nickname_taken_tx, is_nickname_taken_rx) = flume::bounded(0);
+) -> anyhow::Result<NicknameStatus> {
+    let (status_tx, status_rx) = flume::bounded(0);
+
+    nickname_event_tx
+        .send_async(NicknameEvent::Login {
+            nickname,
+            status_tx,
+        })
+        .await?;
+
+    Ok(status_rx.recv_async().await.unwrap())
+}
+
+async fn is_logged_in(nickname: String, nickname_event_tx: &Sender<NicknameEvent>) -> bool {
+    let (is_logged_in_tx, is_logged_in_rx) = flume::bounded(0);
 
-    nickname_event_tx.send(NicknameEvent::Login {
-        nickname,
-        is_taken_tx: is_nickname_taken_tx,
-    })?;
+    nickname_event_tx
+        .send_async(NicknameEvent::IsLoggedIn {
+            nickname,
+            is_logged_in_tx,
+        })
+        .await
+        .unwrap();
 
-    Ok(is_nickname_taken_rx.recv().unwrap())
+    is_logged_in_rx.recv_async().await.unwrap()
 }
 
-type TcpConnection = jsonl::Connection<io::BufReader<TcpStream>, TcpStream>;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::connection_handler::tests::{assert_closed, identified_client};
+    use crate::server::FileStore;
+    use crate::ConnectionKind;
+
+    fn session(nickname_event_tx: Sender<NicknameEvent>, event_tx: Sender<Event>) -> Session {
+        Session {
+            user: User {
+                nickname: "luna".to_string(),
+                color: None,
+            },
+            room: DEFAULT_ROOM.to_string(),
+            upload: None,
+            failed_logins: 0,
+            typing: false,
+            nickname_event_tx,
+            event_tx,
+            logged_out: false,
+        }
+    }
+
+    fn received(event_rx: &Receiver<Event>) -> Vec<(String, EventKind)> {
+        event_rx
+            .drain()
+            .map(|event| (event.room, event.event))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn switching_rooms_stops_typing_in_the_old_one() {
+        let (nickname_event_tx, _nickname_event_rx) = flume::unbounded();
+        let (event_tx, event_rx) = flume::unbounded();
+        let mut session = session(nickname_event_tx, event_tx);
+
+        session.typing = true;
+        session.switch_room("rust".to_string()).await;
+        assert_eq!(
+            received(&event_rx),
+            vec![
+                (
+                    DEFAULT_ROOM.to_string(),
+                    EventKind::Typing(TypingEvent::Stop)
+                ),
+                (DEFAULT_ROOM.to_string(), EventKind::LeaveRoom),
+                ("rust".to_string(), EventKind::JoinRoom),
+            ]
+        );
+
+        // nobody needs telling twice
+        session.switch_room(DEFAULT_ROOM.to_string()).await;
+        assert_eq!(
+            received(&event_rx),
+            vec![
+                ("rust".to_string(), EventKind::LeaveRoom),
+                (DEFAULT_ROOM.to_string(), EventKind::JoinRoom),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn logging_out_stops_typing() {
+        let (nickname_event_tx, nickname_event_rx) = flume::unbounded();
+        let (event_tx, event_rx) = flume::unbounded();
+        let mut session = session(nickname_event_tx, event_tx);
+
+        session.room = "rust".to_string();
+        session.typing = true;
+        session.logged_out = true;
+        drop(session);
+
+        nickname_event_rx.recv_async().await.unwrap();
+        assert_eq!(
+            event_rx.recv_async().await.unwrap().event,
+            EventKind::Typing(TypingEvent::Stop)
+        );
+        let logout = event_rx.recv_async().await.unwrap();
+        assert_eq!(
+            (logout.room, logout.event),
+            ("rust".to_string(), EventKind::Logout)
+        );
+    }
+
+    #[tokio::test]
+    async fn sender_that_never_logs_in_is_disconnected() {
+        let (client, connection) = identified_client(ConnectionKind::Sender).await;
+
+        let dir =
+            std::env::temp_dir().join(format!("nunitius-sender-login-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let accounts = Accounts::open(dir.join("accounts.jsonl")).unwrap();
+        let files = FileStore::open(dir.join("files")).unwrap();
+        let (nickname_event_tx, nickname_event_rx) = flume::unbounded();
+        let (event_tx, event_rx) = flume::unbounded();
+        let (history_request_tx, _history_request_rx) = flume::unbounded();
+
+        let handle = tokio::spawn(handle_sender(
+            connection,
+            nickname_event_tx,
+            event_tx,
+            history_request_tx,
+            Arc::new(accounts),
+            Heartbeat {
+                interval: Duration::from_millis(20),
+                timeout: Duration::from_millis(50),
+            },
+            UploadSettings {
+                files: Arc::new(files),
+                max_size: 1024,
+            },
+        ));
+
+        assert_closed(client).await;
+        assert!(handle.await.unwrap().is_err());
+        assert!(nickname_event_rx.is_empty());
+        assert!(event_rx.is_empty());
+    }
+}
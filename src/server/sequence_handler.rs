@@ -0,0 +1,20 @@
+use crate::Event;
+use flume::{Receiver, Sender};
+use uuid::Uuid;
+
+/// Stamps every event with a sequence number and a unique ID
+/// before passing it on to the rest of the server.
+///
+/// `last_seq` is the greatest sequence number that has already been handed out.
+pub fn sequence_handler(
+    event_rx: Receiver<Event>,
+    sequenced_event_tx: Sender<Event>,
+    last_seq: u64,
+) {
+    for (seq, mut event) in (last_seq + 1..).zip(event_rx) {
+        event.seq = seq;
+        event.id = Uuid::new_v4();
+
+        sequenced_event_tx.send(event).unwrap();
+    }
+}
@@ -0,0 +1,352 @@
+use super::{lines, FileStore};
+use crate::{FileMetadata, Message};
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Where uploads go and how big they can be.
+#[derive(Clone)]
+pub struct UploadSettings {
+    pub files: Arc<FileStore>,
+    /// In bytes.
+    pub max_size: u64,
+}
+
+impl UploadSettings {
+    /// The longest line a sender can send,
+    /// which has to fit a whole file from senders that don’t upload in chunks.
+    pub fn max_line_len(&self) -> u64 {
+        // base64 takes four bytes for every three,
+        // and the rest of the message fits in what’s left over
+        self.max_size.div_ceil(3) * 4 + lines::MAX_LINE_LEN
+    }
+}
+
+/// Makes sure a file message only mentions a file the server has,
+/// storing it first if it came whole from a sender that doesn’t upload in chunks,
+/// and returns the message as it should be passed on.
+///
+/// Other messages are passed on as they are.
+pub async fn check_message(message: Message, settings: &UploadSettings) -> anyhow::Result<Message> {
+    let (metadata, contents) = match message {
+        // a file announced without its contents has to have been uploaded already,
+        // otherwise anyone could advertise files that don’t exist
+        Message::File {
+            metadata: Some(metadata),
+            contents,
+        } if contents.is_empty() => {
+            let sha256 = metadata.sha256.clone();
+            let size = settings.files.run(move |files| files.size(&sha256)).await;
+            anyhow::ensure!(
+                size == Some(metadata.size),
+                "the server doesn’t have the file being sent"
+            );
+
+            return Ok(Message::File {
+                metadata: Some(metadata),
+                contents,
+            });
+        }
+        Message::File { metadata, contents } => (metadata, contents),
+        message => return Ok(message),
+    };
+
+    anyhow::ensure!(
+        contents.len() as u64 <= settings.max_size,
+        "the server only accepts files up to {} MB",
+        settings.max_size / 1_000_000
+    );
+
+    let metadata = settings
+        .files
+        .run(move |files| files.insert(metadata, &contents))
+        .await?;
+
+    Ok(Message::File {
+        metadata: Some(metadata),
+        contents: Vec::new(),
+    })
+}
+
+/// A file arriving from a sender a chunk at a time,
+/// which is written to the file store as it comes in.
+///
+/// Starting an upload that was cut off carries on from however much of it had arrived.
+pub struct Upload {
+    id: Uuid,
+    metadata: FileMetadata,
+    received: u64,
+    // hashing as chunks arrive saves going over the whole file at the end
+    hasher: Sha256,
+    /// Whether the store had the file before the upload started,
+    /// in which case there’s nothing to send.
+    is_stored: bool,
+    files: Arc<FileStore>,
+}
+
+impl Upload {
+    /// Fails with a reason meant for the sender
+    /// if the file is too big.
+    pub async fn start(
+        id: Uuid,
+        metadata: FileMetadata,
+        settings: &UploadSettings,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            metadata.size <= settings.max_size,
+            "the server only accepts files up to {} MB",
+            settings.max_size / 1_000_000
+        );
+
+        let files = Arc::clone(&settings.files);
+        let sha256 = metadata.sha256.clone();
+        let is_stored = files.run(move |files| files.size(&sha256)).await == Some(metadata.size);
+
+        let (received, hasher) = if is_stored {
+            (metadata.size, Sha256::new())
+        } else {
+            let size = metadata.size;
+            files
+                .run(move |files| files.resume_partial(id, size))
+                .await?
+        };
+
+        Ok(Self {
+            id,
+            received,
+            metadata,
+            hasher,
+            is_stored,
+            files,
+        })
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received == self.metadata.size
+    }
+
+    pub async fn write(&mut self, offset: u64, data: Vec<u8>) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            offset == self.received,
+            "a chunk started at byte {} rather than {}",
+            offset,
+            self.received
+        );
+        anyhow::ensure!(
+            self.received + data.len() as u64 <= self.metadata.size,
+            "the file is bigger than the {} bytes it was said to be",
+            self.metadata.size
+        );
+
+        self.hasher.update(&data);
+        self.received += data.len() as u64;
+
+        let id = self.id;
+        self.files
+            .run(move |files| files.append_to_partial(id, &data))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Checks the file is what the sender said it would be
+    /// and moves it into the store.
+    pub async fn finish(self) -> anyhow::Result<Message> {
+        anyhow::ensure!(self.is_complete(), "the file is incomplete");
+
+        if !self.is_stored {
+            if format!("{:x}", self.hasher.clone().finalize()) != self.metadata.sha256 {
+                self.abandon().await;
+                anyhow::bail!("the file was corrupted on its way to the server");
+            }
+
+            let (id, sha256) = (self.id, self.metadata.sha256.clone());
+            self.files
+                .run(move |files| files.commit_partial(id, &sha256))
+                .await?;
+        }
+
+        Ok(Message::File {
+            metadata: Some(self.metadata),
+            contents: Vec::new(),
+        })
+    }
+
+    /// Throws away what’s arrived of an upload that can’t be finished,
+    /// rather than letting the sender carry on from it.
+    pub async fn abandon(self) {
+        if self.is_stored {
+            return;
+        }
+
+        let id = self.id;
+        if let Err(e) = self.files.run(move |files| files.remove_partial(id)).await {
+            warn!("failed to remove partial upload: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(contents: &[u8]) -> FileMetadata {
+        FileMetadata {
+            filename: "notes.txt".to_string(),
+            size: contents.len() as u64,
+            mime_type: "text/plain".to_string(),
+            sha256: format!("{:x}", Sha256::digest(contents)),
+        }
+    }
+
+    fn temp_store(name: &str) -> Arc<FileStore> {
+        let dir =
+            std::env::temp_dir().join(format!("nunitius-uploads-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        Arc::new(FileStore::open(dir).unwrap())
+    }
+
+    async fn start(
+        contents: &[u8],
+        max_size: u64,
+        files: &Arc<FileStore>,
+    ) -> anyhow::Result<Upload> {
+        let settings = UploadSettings {
+            files: Arc::clone(files),
+            max_size,
+        };
+
+        Upload::start(Uuid::new_v4(), metadata(contents), &settings).await
+    }
+
+    #[tokio::test]
+    async fn chunks_are_put_back_together() {
+        let files = temp_store("chunks");
+        let mut upload = start(b"hello\n", 100, &files).await.unwrap();
+
+        upload.write(0, b"hel".to_vec()).await.unwrap();
+        assert!(!upload.is_complete());
+        // chunks can’t be skipped or sent twice
+        assert!(upload.write(0, b"hel".to_vec()).await.is_err());
+        assert!(upload.write(4, b"o\n".to_vec()).await.is_err());
+        upload.write(3, b"lo\n".to_vec()).await.unwrap();
+
+        assert!(upload.is_complete());
+        assert_eq!(
+            upload.finish().await.unwrap(),
+            Message::File {
+                metadata: Some(metadata(b"hello\n")),
+                contents: Vec::new(),
+            }
+        );
+        assert_eq!(
+            files.read(&metadata(b"hello\n").sha256, 0, 100).unwrap(),
+            b"hello\n"
+        );
+
+        // the same file doesn’t need sending again
+        let upload = start(b"hello\n", 100, &files).await.unwrap();
+        assert!(upload.is_complete());
+        assert!(upload.finish().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn uploads_carry_on_where_they_were_cut_off() {
+        let files = temp_store("resume");
+        let settings = UploadSettings {
+            files: Arc::clone(&files),
+            max_size: 100,
+        };
+        let id = Uuid::new_v4();
+
+        let mut upload = Upload::start(id, metadata(b"hello\n"), &settings)
+            .await
+            .unwrap();
+        upload.write(0, b"hel".to_vec()).await.unwrap();
+        drop(upload);
+
+        let mut upload = Upload::start(id, metadata(b"hello\n"), &settings)
+            .await
+            .unwrap();
+        assert_eq!(upload.received(), 3);
+        upload.write(3, b"lo\n".to_vec()).await.unwrap();
+        assert!(upload.finish().await.is_ok());
+
+        // a corrupted upload has to start again from scratch
+        let mut upload = Upload::start(id, metadata(b"jello\n"), &settings)
+            .await
+            .unwrap();
+        upload.write(0, b"hello\n".to_vec()).await.unwrap();
+        assert!(upload.finish().await.is_err());
+
+        let upload = Upload::start(id, metadata(b"jello\n"), &settings)
+            .await
+            .unwrap();
+        assert_eq!(upload.received(), 0);
+    }
+
+    #[tokio::test]
+    async fn file_messages_must_name_stored_files() {
+        let files = temp_store("check");
+        let settings = UploadSettings {
+            files: Arc::clone(&files),
+            max_size: 100,
+        };
+        let announce = |metadata| Message::File {
+            metadata: Some(metadata),
+            contents: Vec::new(),
+        };
+
+        assert!(check_message(announce(metadata(b"hello\n")), &settings)
+            .await
+            .is_err());
+
+        // files sent whole are stored and passed on without their contents
+        let whole = Message::File {
+            metadata: None,
+            contents: b"hello\n".to_vec(),
+        };
+        let stored = check_message(whole, &settings).await.unwrap();
+        assert!(matches!(
+            stored,
+            Message::File { ref contents, .. } if contents.is_empty()
+        ));
+
+        assert!(check_message(announce(metadata(b"hello\n")), &settings)
+            .await
+            .is_ok());
+        let forged = FileMetadata {
+            size: 1_000_000,
+            ..metadata(b"hello\n")
+        };
+        assert!(check_message(announce(forged), &settings).await.is_err());
+
+        let too_big = Message::File {
+            metadata: None,
+            contents: vec![0; 101],
+        };
+        assert!(check_message(too_big, &settings).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn files_must_match_their_metadata() {
+        let files = temp_store("metadata");
+        assert!(start(b"hello\n", 5, &files).await.is_err());
+
+        let mut upload = start(b"hello\n", 100, &files).await.unwrap();
+        assert!(upload.write(0, b"hello\n!".to_vec()).await.is_err());
+        upload.write(0, b"jello\n".to_vec()).await.unwrap();
+        assert!(upload.finish().await.is_err());
+        assert!(!files.contains(&metadata(b"hello\n").sha256));
+    }
+}
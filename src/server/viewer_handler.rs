@@ -1,11 +1,19 @@
-use super::HistoryRequest;
-use crate::Event;
-use flume::{Receiver, Selector, Sender};
-use log::{error, info};
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::net::TcpStream;
-use std::{io, mem};
+use super::accounts;
+use super::{lines, Accounts, Connection, FileStore, HistoryRequest, Reader, Writer};
+use crate::config::Heartbeat;
+use crate::{
+    Capability, Credentials, Event, EventKind, HistoryQuery, LoginResponse, ViewerMessage,
+    ViewerRequest,
+};
+use flume::{Receiver, Sender, TrySendError};
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::{self, Instant};
 
 #[derive(Default)]
 struct ViewerIdGenerator {
@@ -22,103 +30,578 @@ impl ViewerIdGenerator {
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
 struct ViewerId(u32);
 
-pub fn viewer_handler(
-    viewer_rx: Receiver<TcpStream>,
+/// How many messages can be waiting to be written to a viewer
+/// before we give up on it.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// The most of a file a viewer can download in one go,
+/// so that a download doesn’t hold up events for long.
+const MAX_CHUNK_SIZE: u64 = 1024 * 1024;
+
+struct Viewer {
+    /// Messages waiting to be written by the viewer’s writer task.
+    queue_tx: Sender<Arc<ViewerMessage>>,
+    /// Never sent on; once the viewer is removed and this is dropped
+    /// its tasks stop and its connection is closed.
+    _connected_tx: Sender<()>,
+    rooms: HashSet<String>,
+    nickname: String,
+    capabilities: HashSet<Capability>,
+}
+
+impl Viewer {
+    fn should_receive(&self, event: &Event) -> bool {
+        if !self.supports(&event.event) {
+            return false;
+        }
+
+        match event.event {
+            EventKind::DirectMessage { ref to, .. } => {
+                self.nickname == event.user.nickname || self.nickname == *to
+            }
+            _ => self.rooms.contains(&event.room),
+        }
+    }
+
+    fn supports(&self, event: &EventKind) -> bool {
+        supports(&self.capabilities, event)
+    }
+
+    /// Queues up `message` without waiting for it to be written.
+    ///
+    /// Returns whether the viewer is still connected;
+    /// if it isn’t it should be removed.
+    fn send(&self, message: Arc<ViewerMessage>) -> bool {
+        queue(&self.queue_tx, message)
+    }
+}
+
+fn supports(capabilities: &HashSet<Capability>, event: &EventKind) -> bool {
+    event
+        .required_capability()
+        .is_none_or(|capability| capabilities.contains(&capability))
+}
+
+/// Like [`Viewer::send`], for when the viewer itself isn’t to hand.
+fn queue(queue_tx: &Sender<Arc<ViewerMessage>>, message: Arc<ViewerMessage>) -> bool {
+    match queue_tx.try_send(message) {
+        Ok(()) => true,
+
+        Err(TrySendError::Full(_)) => {
+            // it can catch up on what it missed once it reconnects
+            warn!("viewer fell too far behind, disconnecting it");
+            false
+        }
+
+        Err(TrySendError::Disconnected(_)) => {
+            info!("found closed viewer");
+            false
+        }
+    }
+}
+
+enum ViewerUpdate {
+    /// The viewer has logged in, so we can start sending it events.
+    LoggedIn {
+        queue_tx: Sender<Arc<ViewerMessage>>,
+        connected_tx: Sender<()>,
+        nickname: String,
+        capabilities: HashSet<Capability>,
+    },
+    Request(ViewerRequest),
+    /// The viewer’s connection has closed,
+    /// though we may have noticed that already.
+    Closed,
+}
+
+pub async fn viewer_handler(
+    viewer_rx: Receiver<Connection>,
     event_rx: Receiver<Event>,
     history_request_tx: Sender<HistoryRequest>,
+    accounts: Arc<Accounts>,
+    heartbeat: Heartbeat,
+    files: Arc<FileStore>,
 ) {
-    let viewers = RefCell::new(HashMap::new());
+    let mut viewers = HashMap::new();
     let mut viewer_id_generator = ViewerIdGenerator::default();
+    let (viewer_update_tx, viewer_update_rx) = flume::unbounded();
+    let mut heartbeats = time::interval_at(Instant::now() + heartbeat.interval, heartbeat.interval);
 
+    // branches are polled in a random order,
+    // so a busy room can’t hold up heartbeats to viewers outside it
     loop {
-        Selector::new()
-            .recv(&viewer_rx, |viewer| {
+        tokio::select! {
+            viewer = viewer_rx.recv_async() => {
                 info!("received new viewer");
 
-                if let Err(e) = handle_new_viewer(
-                    viewer.unwrap(),
-                    &viewers,
-                    &mut viewer_id_generator,
-                    &history_request_tx,
-                ) {
-                    error!("{:#}", e);
+                let connection = match viewer {
+                    Ok(connection) => connection,
+                    Err(_) => break,
+                };
+                let id = viewer_id_generator.next();
+
+                tokio::spawn(serve_viewer(
+                    id,
+                    connection,
+                    Arc::clone(&accounts),
+                    viewer_update_tx.clone(),
+                    heartbeat,
+                    Arc::clone(&files),
+                ));
+            }
+
+            // we hold on to a sender ourselves, so this can’t fail
+            update = viewer_update_rx.recv_async() => match update.unwrap() {
+                (
+                    id,
+                    ViewerUpdate::LoggedIn {
+                        queue_tx,
+                        connected_tx,
+                        nickname,
+                        capabilities,
+                    },
+                ) => {
+                    info!("viewer logged in");
+
+                    viewers.insert(
+                        id,
+                        Viewer {
+                            queue_tx,
+                            _connected_tx: connected_tx,
+                            rooms: HashSet::new(),
+                            nickname,
+                            capabilities,
+                        },
+                    );
                 }
-            })
-            .recv(&event_rx, |event| {
+
+                (id, ViewerUpdate::Request(request)) => {
+                    info!("received request from viewer");
+                    handle_viewer_request(
+                        &mut viewers,
+                        id,
+                        request,
+                        &history_request_tx,
+                        &viewer_update_tx,
+                    );
+                }
+
+                (id, ViewerUpdate::Closed) => {
+                    if viewers.remove(&id).is_some() {
+                        info!("removed closed viewer");
+                    }
+                }
+            },
+
+            event = event_rx.recv_async() => {
                 info!("received event");
-                handle_new_event(&viewers, event.unwrap());
-            })
-            .wait();
+
+                match event {
+                    Ok(event) => handle_new_event(&mut viewers, event),
+                    Err(_) => break,
+                }
+            }
+
+            _ = heartbeats.tick() => send_heartbeats(&mut viewers),
+        }
     }
 }
 
-fn handle_new_viewer(
-    mut viewer: TcpStream,
-    viewers: &RefCell<HashMap<ViewerId, TcpStream>>,
-    viewer_id_generator: &mut ViewerIdGenerator,
-    history_request_tx: &Sender<HistoryRequest>,
-) -> anyhow::Result<()> {
-    send_new_viewer_existing_history(&mut viewer, history_request_tx)?;
+/// Logs the viewer in and then passes on its requests,
+/// until its connection closes.
+async fn serve_viewer(
+    id: ViewerId,
+    Connection {
+        mut reader,
+        mut writer,
+        capabilities,
+    }: Connection,
+    accounts: Arc<Accounts>,
+    viewer_update_tx: Sender<(ViewerId, ViewerUpdate)>,
+    heartbeat: Heartbeat,
+    files: Arc<FileStore>,
+) {
+    let nickname = match log_viewer_in(&mut reader, &mut writer, accounts, heartbeat.timeout).await
+    {
+        Ok(Some(nickname)) => nickname,
+        Ok(None) => {
+            info!("viewer gave incorrect credentials");
+            return;
+        }
+        Err(e) => {
+            error!("{:#}", e);
+            return;
+        }
+    };
+
+    let (queue_tx, queue_rx) = flume::bounded(QUEUE_CAPACITY);
+    let (connected_tx, connected_rx) = flume::bounded(0);
 
-    let next_id = viewer_id_generator.next();
-    viewers.borrow_mut().insert(next_id, viewer);
+    tokio::spawn(write_viewer_messages(
+        writer,
+        queue_rx,
+        connected_rx.clone(),
+        heartbeat.timeout,
+    ));
+
+    viewer_update_tx
+        .send((
+            id,
+            ViewerUpdate::LoggedIn {
+                queue_tx: queue_tx.clone(),
+                connected_tx,
+                nickname,
+                capabilities,
+            },
+        ))
+        .unwrap();
+
+    read_viewer_requests(
+        id,
+        reader,
+        viewer_update_tx,
+        queue_tx,
+        connected_rx,
+        heartbeat.timeout,
+        files,
+    )
+    .await;
+}
+
+/// Writes out the messages queued up for a viewer,
+/// so that a slow viewer only holds up itself.
+async fn write_viewer_messages(
+    mut writer: Writer,
+    queue_rx: Receiver<Arc<ViewerMessage>>,
+    connected_rx: Receiver<()>,
+    timeout: Duration,
+) {
+    let result = tokio::select! {
+        result = write_queued_messages(&mut writer, queue_rx, timeout) => result,
+        _ = connected_rx.recv_async() => Ok(()),
+    };
+
+    if let Err(e) = result {
+        error!("{:#}", e);
+    }
+
+    let _ = time::timeout(timeout, writer.shutdown()).await;
+}
+
+async fn write_queued_messages(
+    writer: &mut Writer,
+    queue_rx: Receiver<Arc<ViewerMessage>>,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    while let Ok(message) = queue_rx.recv_async().await {
+        // if a write takes this long
+        // the viewer probably isn’t reading what we send it
+        time::timeout(timeout, lines::write(writer, &*message))
+            .await
+            .map_err(|_| anyhow::anyhow!("timed out writing to viewer"))??;
+    }
 
     Ok(())
 }
 
-fn send_new_viewer_existing_history(
-    viewer: &mut TcpStream,
+async fn log_viewer_in(
+    reader: &mut Reader,
+    writer: &mut Writer,
+    accounts: Arc<Accounts>,
+    timeout: Duration,
+) -> anyhow::Result<Option<String>> {
+    // heartbeats only start once the viewer is logged in,
+    // so until then a silent viewer has to be caught here
+    let credentials: Credentials = time::timeout(timeout, lines::read(reader))
+        .await
+        .map_err(|_| anyhow::anyhow!("viewer never sent its credentials"))??;
+
+    let is_verified = accounts
+        .verify_async(credentials.nickname.clone(), credentials.password)
+        .await;
+
+    if is_verified {
+        lines::write(writer, &LoginResponse::LoggedIn).await?;
+        Ok(Some(credentials.nickname))
+    } else {
+        // viewers only get one try per connection,
+        // but it still shouldn’t be answered straight away
+        time::sleep(accounts::failed_login_delay(1)).await;
+        lines::write(writer, &LoginResponse::BadCredentials).await?;
+        Ok(None)
+    }
+}
+
+async fn read_viewer_requests(
+    id: ViewerId,
+    mut reader: Reader,
+    viewer_update_tx: Sender<(ViewerId, ViewerUpdate)>,
+    queue_tx: Sender<Arc<ViewerMessage>>,
+    connected_rx: Receiver<()>,
+    timeout: Duration,
+    files: Arc<FileStore>,
+) {
+    loop {
+        // viewers send heartbeats regularly,
+        // so if we don’t hear from one for a while
+        // its connection is probably half-open
+        let request = tokio::select! {
+            request = time::timeout(timeout, lines::read(&mut reader)) => request,
+            _ = connected_rx.recv_async() => {
+                info!("viewer was disconnected");
+                break;
+            }
+        };
+
+        match request {
+            Ok(Ok(ViewerRequest::Heartbeat)) => {}
+
+            // answered here rather than by the viewer handler,
+            // so that reading from the disk only holds up the viewer that asked
+            Ok(Ok(ViewerRequest::Download {
+                sha256,
+                offset,
+                len,
+            })) => {
+                info!("received request to download part of a file");
+                let message = read_file_chunk(&files, sha256, offset, len).await;
+
+                // waiting for room in the queue stops a viewer
+                // from asking for more than it can keep up with
+                if queue_tx.send_async(Arc::new(message)).await.is_err() {
+                    info!("viewer was disconnected");
+                    break;
+                }
+            }
+
+            Ok(Ok(request)) => viewer_update_tx
+                .send((id, ViewerUpdate::Request(request)))
+                .unwrap(),
+
+            Ok(Err(jsonl::ReadError::Eof)) => {
+                info!("viewer closed connection");
+                break;
+            }
+
+            Ok(Err(e)) => {
+                error!("{:#}", anyhow::Error::new(e));
+                break;
+            }
+
+            Err(_) => {
+                info!("viewer stopped responding");
+                break;
+            }
+        }
+    }
+
+    viewer_update_tx.send((id, ViewerUpdate::Closed)).unwrap();
+}
+
+async fn read_file_chunk(
+    files: &Arc<FileStore>,
+    sha256: String,
+    offset: u64,
+    len: u64,
+) -> ViewerMessage {
+    let result = {
+        let sha256 = sha256.clone();
+        let len = len.min(MAX_CHUNK_SIZE);
+        files
+            .run(move |files| files.read(&sha256, offset, len))
+            .await
+    };
+
+    match result {
+        Ok(data) => ViewerMessage::FileChunk {
+            sha256,
+            offset,
+            data,
+        },
+        Err(e) => {
+            info!("download failed: {:#}", e);
+            ViewerMessage::DownloadFailed {
+                sha256,
+                reason: format!("{:#}", e),
+            }
+        }
+    }
+}
+
+/// Answers a request the viewer handler has to deal with.
+///
+/// Anything needing the history handler is waited for on a task of its own,
+/// which queues the reply for the viewer,
+/// so that one viewer’s request doesn’t hold up events to everyone else.
+fn handle_viewer_request(
+    viewers: &mut HashMap<ViewerId, Viewer>,
+    id: ViewerId,
+    request: ViewerRequest,
     history_request_tx: &Sender<HistoryRequest>,
-) -> anyhow::Result<()> {
-    let (history_tx, history_rx) = flume::bounded(0);
+    viewer_update_tx: &Sender<(ViewerId, ViewerUpdate)>,
+) {
+    let viewer = match viewers.get_mut(&id) {
+        Some(viewer) => viewer,
+        None => return,
+    };
+
+    let (room, query) = match request {
+        ViewerRequest::JoinRoom { room, history } => {
+            // new events are sent from now on, so none can be missed,
+            // and the viewer holds on to any that arrive before its history
+            viewer.rooms.insert(room.clone());
+            (room, history)
+        }
+
+        ViewerRequest::History { room, query } => (room, query),
+
+        ViewerRequest::LeaveRoom { room } => {
+            viewer.rooms.remove(&room);
+            return;
+        }
+
+        ViewerRequest::ListRooms => {
+            spawn_reply(
+                id,
+                viewer_update_tx,
+                send_rooms(viewer.queue_tx.clone(), history_request_tx.clone()),
+            );
+            return;
+        }
+
+        // handled before reaching here
+        ViewerRequest::Heartbeat | ViewerRequest::Download { .. } => return,
+    };
+
+    spawn_reply(
+        id,
+        viewer_update_tx,
+        send_history(
+            viewer.queue_tx.clone(),
+            viewer.capabilities.clone(),
+            viewer.nickname.clone(),
+            room,
+            query,
+            history_request_tx.clone(),
+        ),
+    );
+}
+
+/// Waits for `reply` to be queued on a task of its own,
+/// removing the viewer if it turns out to have gone.
+fn spawn_reply(
+    id: ViewerId,
+    viewer_update_tx: &Sender<(ViewerId, ViewerUpdate)>,
+    reply: impl Future<Output = bool> + Send + 'static,
+) {
+    let viewer_update_tx = viewer_update_tx.clone();
+
+    tokio::spawn(async move {
+        if !reply.await {
+            let _ = viewer_update_tx
+                .send_async((id, ViewerUpdate::Closed))
+                .await;
+        }
+    });
+}
+
+/// Returns whether the viewer is still connected.
+async fn send_rooms(
+    queue_tx: Sender<Arc<ViewerMessage>>,
+    history_request_tx: Sender<HistoryRequest>,
+) -> bool {
+    let (rooms_tx, rooms_rx) = flume::bounded(0);
 
     history_request_tx
-        .send(HistoryRequest { history_tx })
+        .send_async(HistoryRequest::Rooms { rooms_tx })
+        .await
+        .unwrap();
+    let rooms = rooms_rx.recv_async().await.unwrap();
+
+    queue(&queue_tx, Arc::new(ViewerMessage::Rooms(rooms)))
+}
+
+/// Returns whether the viewer is still connected.
+async fn send_history(
+    queue_tx: Sender<Arc<ViewerMessage>>,
+    capabilities: HashSet<Capability>,
+    nickname: String,
+    room: String,
+    query: HistoryQuery,
+    history_request_tx: Sender<HistoryRequest>,
+) -> bool {
+    let (pages_tx, pages_rx) = flume::bounded(0);
+
+    history_request_tx
+        .send_async(HistoryRequest::Events {
+            room,
+            nickname,
+            query,
+            pages_tx,
+        })
+        .await
         .unwrap();
     info!("requested history");
 
-    let history = history_rx.recv().unwrap();
-    jsonl::write(viewer, &history)?;
-    info!("sent history to viewer");
+    for mut page in pages_rx.recv_async().await.unwrap() {
+        // the page’s indices are unaffected,
+        // since they refer to the room’s whole history
+        page.events
+            .retain(|event| supports(&capabilities, &event.event));
 
-    Ok(())
+        if !queue(&queue_tx, Arc::new(ViewerMessage::HistoryPage(page))) {
+            return false;
+        }
+    }
+    info!("queued history for viewer");
+
+    true
 }
 
-fn handle_new_event(viewers: &RefCell<HashMap<ViewerId, TcpStream>>, event: Event) {
+fn handle_new_event(viewers: &mut HashMap<ViewerId, Viewer>, event: Event) {
     let mut closed_viewers = Vec::new();
     send_event_to_viewers(viewers, event, &mut closed_viewers);
     remove_closed_viewers(viewers, closed_viewers.into_iter());
 }
 
 fn send_event_to_viewers(
-    viewers: &RefCell<HashMap<ViewerId, TcpStream>>,
+    viewers: &HashMap<ViewerId, Viewer>,
     event: Event,
     closed_viewers: &mut Vec<ViewerId>,
 ) {
-    let mut viewers = viewers.borrow_mut();
-
-    for (id, viewer) in viewers.iter_mut() {
-        match jsonl::write(viewer, &event) {
-            Ok(()) => info!("forwarded event to viewer"),
+    let recipients: Vec<_> = viewers
+        .iter()
+        .filter(|(_, viewer)| viewer.should_receive(&event))
+        .collect();
 
-            Err(jsonl::WriteError::Io(io_error))
-                if io_error.kind() == io::ErrorKind::BrokenPipe =>
-            {
-                info!("found closed viewer");
-                closed_viewers.push(*id);
-            }
+    // every recipient shares the same copy
+    let message = Arc::new(ViewerMessage::Event(event));
 
-            Err(e) => error!("{:#}", anyhow::Error::new(e)),
+    for (id, viewer) in recipients {
+        if viewer.send(Arc::clone(&message)) {
+            info!("queued event for viewer");
+        } else {
+            closed_viewers.push(*id);
         }
     }
 }
 
+fn send_heartbeats(viewers: &mut HashMap<ViewerId, Viewer>) {
+    let message = Arc::new(ViewerMessage::Heartbeat);
+
+    let closed_viewers: Vec<_> = viewers
+        .iter()
+        .filter(|(_, viewer)| !viewer.send(Arc::clone(&message)))
+        .map(|(id, _)| *id)
+        .collect();
+
+    remove_closed_viewers(viewers, closed_viewers.into_iter());
+}
+
 fn remove_closed_viewers(
-    viewers: &RefCell<HashMap<ViewerId, TcpStream>>,
+    viewers: &mut HashMap<ViewerId, Viewer>,
     closed_viewers: impl Iterator<Item = ViewerId>,
 ) {
-    let mut viewers = viewers.borrow_mut();
-
     for id in closed_viewers {
         info!("removed closed viewer");
 
@@ -129,3 +612,197 @@ fn remove_closed_viewers(
         assert!(removed_viewer.is_some())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::connection_handler::tests::{assert_closed, identified_client};
+    use crate::stream::ServerStream;
+    use crate::ConnectionKind;
+    use crate::{Message, TypingEvent, User};
+    use chrono::Utc;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use uuid::Uuid;
+
+    fn message_event() -> Event {
+        Event {
+            seq: 1,
+            id: Uuid::new_v4(),
+            event: EventKind::Message(Message::Text {
+                body: "hi".to_string(),
+            }),
+            user: User {
+                nickname: "someone".to_string(),
+                color: None,
+            },
+            room: "general".to_string(),
+            time_occurred: Utc::now(),
+        }
+    }
+
+    /// A viewer in the default room.
+    fn viewer(
+        queue_tx: Sender<Arc<ViewerMessage>>,
+        connected_tx: Sender<()>,
+        capabilities: &[Capability],
+    ) -> Viewer {
+        Viewer {
+            queue_tx,
+            _connected_tx: connected_tx,
+            rooms: std::iter::once("general".to_string()).collect(),
+            nickname: "luna".to_string(),
+            capabilities: capabilities.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn viewer_with_full_queue_is_disconnected() {
+        // nobody reads from the queue, as if the viewer were stuck
+        let (queue_tx, _queue_rx) = flume::bounded(1);
+        let (connected_tx, connected_rx) = flume::bounded::<()>(0);
+
+        let viewer = viewer(queue_tx, connected_tx, Capability::SUPPORTED);
+        let mut viewers: HashMap<_, _> = std::iter::once((ViewerId(0), viewer)).collect();
+
+        handle_new_event(&mut viewers, message_event());
+        assert_eq!(viewers.len(), 1);
+
+        handle_new_event(&mut viewers, message_event());
+        assert!(viewers.is_empty());
+        assert!(connected_rx.is_disconnected());
+    }
+
+    #[test]
+    fn viewer_is_only_sent_events_it_supports() {
+        let (queue_tx, queue_rx) = flume::unbounded();
+        let (connected_tx, _connected_rx) = flume::bounded(0);
+
+        let viewer = viewer(queue_tx, connected_tx, &[]);
+        let mut viewers: HashMap<_, _> = std::iter::once((ViewerId(0), viewer)).collect();
+
+        let typing_event = Event {
+            event: EventKind::Typing(TypingEvent::Start),
+            ..message_event()
+        };
+        handle_new_event(&mut viewers, typing_event);
+        handle_new_event(&mut viewers, message_event());
+
+        let received: Vec<_> = queue_rx.drain().collect();
+        assert_eq!(received.len(), 1);
+        assert!(matches!(
+            *received[0],
+            ViewerMessage::Event(Event {
+                event: EventKind::Message(_),
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn waiting_for_history_doesnt_hold_up_events() {
+        let (queue_tx, queue_rx) = flume::unbounded();
+        let (connected_tx, _connected_rx) = flume::bounded(0);
+        let viewer = viewer(queue_tx, connected_tx, Capability::SUPPORTED);
+        let mut viewers: HashMap<_, _> = std::iter::once((ViewerId(0), viewer)).collect();
+
+        // a history handler that’s busy with something else
+        let (history_request_tx, history_request_rx) = flume::unbounded();
+        let (viewer_update_tx, _viewer_update_rx) = flume::unbounded();
+
+        handle_viewer_request(
+            &mut viewers,
+            ViewerId(0),
+            ViewerRequest::JoinRoom {
+                room: "random".to_string(),
+                history: HistoryQuery::Latest { count: 10 },
+            },
+            &history_request_tx,
+            &viewer_update_tx,
+        );
+        handle_new_event(
+            &mut viewers,
+            Event {
+                room: "random".to_string(),
+                ..message_event()
+            },
+        );
+
+        assert!(matches!(
+            *queue_rx.recv_async().await.unwrap(),
+            ViewerMessage::Event(_)
+        ));
+
+        // the history still arrives once the history handler gets to it
+        let HistoryRequest::Events { pages_tx, .. } =
+            history_request_rx.recv_async().await.unwrap()
+        else {
+            panic!("expected a request for events");
+        };
+        let page = crate::HistoryPage {
+            room: "random".to_string(),
+            events: Vec::new(),
+            first_index: 0,
+            is_last: true,
+            more_after: None,
+        };
+        pages_tx.send_async(vec![page]).await.unwrap();
+        assert!(matches!(
+            *queue_rx.recv_async().await.unwrap(),
+            ViewerMessage::HistoryPage(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn viewer_that_never_logs_in_is_disconnected() {
+        let (client, connection) = identified_client(ConnectionKind::Viewer).await;
+
+        let dir =
+            std::env::temp_dir().join(format!("nunitius-viewer-login-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let accounts = Accounts::open(dir.join("accounts.jsonl")).unwrap();
+        let files = FileStore::open(dir.join("files")).unwrap();
+        let (viewer_update_tx, viewer_update_rx) = flume::unbounded();
+
+        tokio::spawn(serve_viewer(
+            ViewerId(0),
+            connection,
+            Arc::new(accounts),
+            viewer_update_tx,
+            Heartbeat {
+                interval: Duration::from_millis(20),
+                timeout: Duration::from_millis(50),
+            },
+            Arc::new(files),
+        ));
+
+        assert_closed(client).await;
+        assert!(viewer_update_rx.is_empty());
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_written_in_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (_, writer) = tokio::io::split(ServerStream::Tcp(server));
+
+        let (queue_tx, queue_rx) = flume::bounded(QUEUE_CAPACITY);
+        let (_connected_tx, connected_rx) = flume::bounded(0);
+
+        queue_tx
+            .send(Arc::new(ViewerMessage::Rooms(vec!["general".to_string()])))
+            .unwrap();
+        queue_tx.send(Arc::new(ViewerMessage::Heartbeat)).unwrap();
+        drop(queue_tx);
+
+        write_viewer_messages(writer, queue_rx, connected_rx, Duration::from_secs(5)).await;
+
+        let mut contents = String::new();
+        client.read_to_string(&mut contents).await.unwrap();
+
+        assert_eq!(contents, "{\"Rooms\":[\"general\"]}\n\"Heartbeat\"\n");
+    }
+}
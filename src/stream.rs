@@ -0,0 +1,393 @@
+use crate::config::Heartbeat;
+use rustls::pki_types::ServerName;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::TlsAcceptor;
+
+/// A connection between a client and the server,
+/// which may or may not be encrypted.
+///
+/// Like a `TcpStream`, it can be cloned
+/// so that one thread can read from it while another writes to it.
+pub enum Stream {
+    Tcp(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(stream) => Ok(Self::Tcp(stream.try_clone()?)),
+            Self::Tls(stream) => Ok(Self::Tls(stream.try_clone()?)),
+        }
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Self::Tls(stream) => stream.shutdown(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A TLS session shared between clones of the same stream.
+///
+/// Reading from the socket happens without holding the lock,
+/// so a thread waiting for data doesn’t stop other threads from writing.
+pub struct TlsStream {
+    session: Arc<Mutex<rustls::Connection>>,
+    socket: TcpStream,
+}
+
+impl TlsStream {
+    fn new(mut session: rustls::Connection, mut socket: TcpStream) -> io::Result<Self> {
+        while session.is_handshaking() {
+            session.complete_io(&mut socket)?;
+        }
+
+        Ok(Self {
+            session: Arc::new(Mutex::new(session)),
+            socket,
+        })
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            session: Arc::clone(&self.session),
+            socket: self.socket.try_clone()?,
+        })
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.send_close_notify();
+        // the socket is about to go away anyway,
+        // so there’s no point reporting a failure to say goodbye
+        let _ = self.write_tls(&mut session);
+
+        self.socket.shutdown(Shutdown::Both)
+    }
+
+    fn write_tls(&self, session: &mut rustls::Connection) -> io::Result<()> {
+        while session.wants_write() {
+            session.write_tls(&mut &self.socket)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut ciphertext = [0; 4096];
+
+        loop {
+            match self.session.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                // clients usually just exit without closing the session properly;
+                // since messages are newline-terminated we’d notice if one was cut off
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                result => return result,
+            }
+
+            let len = self.socket.read(&mut ciphertext)?;
+            let mut ciphertext = &ciphertext[..len];
+            let mut session = self.session.lock().unwrap();
+
+            // an empty read tells the session the socket has closed
+            loop {
+                session.read_tls(&mut ciphertext)?;
+                session
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+                if ciphertext.is_empty() {
+                    break;
+                }
+            }
+
+            // the session may need to respond, e.g. to a key update
+            self.write_tls(&mut session)?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let len = session.writer().write(buf)?;
+        self.write_tls(&mut session)?;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.writer().flush()?;
+        self.write_tls(&mut session)
+    }
+}
+
+/// How a client reaches the server.
+#[derive(Clone)]
+pub struct Connector {
+    pub host: String,
+    pub port: u16,
+    pub tls: Option<Arc<rustls::ClientConfig>>,
+    pub heartbeat: Heartbeat,
+}
+
+impl Connector {
+    /// Reads from the returned stream time out
+    /// once we haven’t heard from the server for the heartbeat timeout.
+    pub fn connect(&self) -> anyhow::Result<Stream> {
+        let socket = TcpStream::connect((self.host.as_str(), self.port))?;
+        socket.set_read_timeout(Some(self.heartbeat.timeout))?;
+
+        match self.tls {
+            Some(ref tls) => {
+                let server_name = ServerName::try_from(self.host.clone())?;
+                let session = rustls::ClientConnection::new(Arc::clone(tls), server_name)?;
+
+                Ok(Stream::Tls(TlsStream::new(session.into(), socket)?))
+            }
+            None => Ok(Stream::Tcp(socket)),
+        }
+    }
+}
+
+/// Whether `error` came from a read timing out,
+/// which is reported as a different kind of error on different platforms.
+pub fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// The server’s end of a connection,
+/// which may or may not be encrypted.
+pub enum ServerStream {
+    Tcp(tokio::net::TcpStream),
+    Tls(Box<tokio_rustls::server::TlsStream<tokio::net::TcpStream>>),
+}
+
+impl AsyncRead for ServerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => match Pin::new(stream).poll_read(cx, buf) {
+                // see the comment in `TlsStream::read`
+                Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    Poll::Ready(Ok(()))
+                }
+                poll => poll,
+            },
+        }
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Sets up incoming connections on the server.
+#[derive(Clone)]
+pub struct Acceptor {
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl Acceptor {
+    pub async fn accept(&self, socket: tokio::net::TcpStream) -> io::Result<ServerStream> {
+        match self.tls {
+            Some(ref tls) => {
+                let stream = TlsAcceptor::from(Arc::clone(tls)).accept(socket).await?;
+                Ok(ServerStream::Tls(Box::new(stream)))
+            }
+            None => Ok(ServerStream::Tcp(socket)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::{fs, thread};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    struct Certificates {
+        ca: PathBuf,
+        cert: PathBuf,
+        key: PathBuf,
+    }
+
+    /// A certificate for localhost signed by a freshly generated certificate authority.
+    fn generate_certificates(name: &str) -> Certificates {
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&key, &ca)
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("nunitius-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let certificates = Certificates {
+            ca: dir.join("ca.pem"),
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+        };
+        fs::write(&certificates.ca, ca.pem()).unwrap();
+        fs::write(&certificates.cert, cert.pem()).unwrap();
+        fs::write(&certificates.key, key.serialize_pem()).unwrap();
+
+        certificates
+    }
+
+    /// Echoes every line sent to the returned port back to the sender.
+    fn spawn_echo_server(acceptor: Acceptor) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        listener.set_nonblocking(true).unwrap();
+
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                let (socket, _) = listener.accept().await.unwrap();
+                let Ok(stream) = acceptor.accept(socket).await else {
+                    return;
+                };
+                let (reader, mut writer) = tokio::io::split(stream);
+                let mut reader = tokio::io::BufReader::new(reader);
+
+                let mut line = String::new();
+                while reader.read_line(&mut line).await.unwrap() > 0 {
+                    writer.write_all(line.as_bytes()).await.unwrap();
+                    writer.flush().await.unwrap();
+                    line.clear();
+                }
+            });
+        });
+
+        port
+    }
+
+    #[test]
+    fn tls_round_trip_with_custom_ca() {
+        let certificates = generate_certificates("round-trip");
+        let port = spawn_echo_server(Acceptor {
+            tls: Some(tls::server_config(&certificates.cert, &certificates.key).unwrap()),
+        });
+
+        let connector = Connector {
+            host: "localhost".to_string(),
+            port,
+            tls: Some(tls::client_config(Some(&certificates.ca)).unwrap()),
+            heartbeat: Heartbeat::default(),
+        };
+        let mut writer = connector.connect().unwrap();
+        let reader = BufReader::new(writer.try_clone().unwrap());
+
+        // reading and writing happen on different threads, as in the clients
+        let reader = thread::spawn(move || {
+            let mut reader = reader;
+            (0..3)
+                .map(|_| jsonl::read::<_, String>(&mut reader).unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        for line in &["one", "two", "three"] {
+            jsonl::write(&mut writer, line).unwrap();
+        }
+
+        assert_eq!(reader.join().unwrap(), ["one", "two", "three"]);
+    }
+
+    #[test]
+    fn untrusted_certificate_is_rejected() {
+        let certificates = generate_certificates("untrusted");
+        let port = spawn_echo_server(Acceptor {
+            tls: Some(tls::server_config(&certificates.cert, &certificates.key).unwrap()),
+        });
+
+        let connector = Connector {
+            host: "localhost".to_string(),
+            port,
+            tls: Some(tls::client_config(None).unwrap()),
+            heartbeat: Heartbeat::default(),
+        };
+
+        assert!(connector.connect().is_err());
+    }
+}
@@ -1,4 +1,8 @@
+mod actions;
 mod app;
+mod connection;
+mod download;
+mod preview;
 mod protocol;
 mod timeline;
 mod ui;
@@ -6,30 +10,85 @@ mod ui;
 #[cfg(test)]
 mod dummy_events;
 
-pub use app::{App, RenderedUi};
-pub use protocol::Protocol;
+pub use actions::{
+    preview_selected_file, rejoin, save_selected_file, select_file, switch_room, HISTORY_PAGE_SIZE,
+};
+pub use app::{App, ConnectionState, RenderedUi};
+pub use connection::{maintain_connection, ConnectionEvent};
+pub use download::{DownloadEvent, Downloads};
+pub use preview::Preview;
+pub use protocol::{Channels, Protocol, Requester};
 pub use timeline::Timeline;
+pub use ui::sanitize;
 
-use crate::{Event as ServerEvent, EventKind as ServerEventKind, Message, User};
+use crate::{Event as ServerEvent, EventKind as ServerEventKind, FileMetadata, Message, User};
 use chrono::{DateTime, Utc};
+use uuid::Uuid;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Event {
+    pub seq: u64,
+    pub id: Uuid,
     pub event: EventKind,
     pub user: User,
+    pub room: String,
     pub time_occurred: DateTime<Utc>,
 }
 
 impl Event {
+    /// How many rows of the terminal the event takes up,
+    /// since a message can span several lines.
+    pub fn num_rows(&self) -> usize {
+        match self.event {
+            EventKind::Message(Message::Text { ref body })
+            | EventKind::Message(Message::Action { ref body })
+            | EventKind::DirectMessage {
+                message: Message::Text { ref body } | Message::Action { ref body },
+                ..
+            } => body.split('\n').count(),
+            _ => 1,
+        }
+    }
+
+    /// The file the event is about, if it can be downloaded.
+    pub fn file(&self) -> Option<&FileMetadata> {
+        match self.event {
+            EventKind::Message(Message::File {
+                metadata: Some(ref metadata),
+                ..
+            })
+            | EventKind::DirectMessage {
+                message:
+                    Message::File {
+                        metadata: Some(ref metadata),
+                        ..
+                    },
+                ..
+            } => Some(metadata),
+            _ => None,
+        }
+    }
+
     fn from_server_event(server_event: ServerEvent) -> Option<Self> {
         Some(Self {
+            seq: server_event.seq,
+            id: server_event.id,
             event: match server_event.event {
                 ServerEventKind::Message(msg) => EventKind::Message(msg),
                 ServerEventKind::Login => EventKind::Login,
                 ServerEventKind::Logout => EventKind::Logout,
                 ServerEventKind::Typing(_) => return None,
+                ServerEventKind::JoinRoom => EventKind::JoinRoom,
+                ServerEventKind::LeaveRoom => EventKind::LeaveRoom,
+                ServerEventKind::DirectMessage { to, message } => {
+                    EventKind::DirectMessage { to, message }
+                }
+                ServerEventKind::NicknameChange { old_nickname } => {
+                    EventKind::NicknameChange { old_nickname }
+                }
             },
             user: server_event.user,
+            room: server_event.room,
             time_occurred: server_event.time_occurred,
         })
     }
@@ -40,4 +99,8 @@ pub enum EventKind {
     Message(Message),
     Login,
     Logout,
+    JoinRoom,
+    LeaveRoom,
+    DirectMessage { to: String, message: Message },
+    NicknameChange { old_nickname: String },
 }
@@ -0,0 +1,99 @@
+//! What the viewer’s keys do,
+//! for both the viewer and the combined client.
+
+use super::{App, Downloads, Requester};
+use crate::{FileMetadata, HistoryQuery};
+use anyhow::Context;
+
+/// How many events to ask for at a time when scrolling back through history.
+pub const HISTORY_PAGE_SIZE: usize = 100;
+
+/// Sets up a new connection to match the state we had before we were disconnected.
+pub fn rejoin(
+    app: &mut App,
+    downloads: &mut Downloads,
+    requester: &mut Requester,
+) -> anyhow::Result<()> {
+    let history = app.history_to_resume_from(HISTORY_PAGE_SIZE);
+    requester.join_room(app.room().to_string(), history)?;
+    requester.list_rooms()?;
+
+    downloads.resume();
+    downloads.send_requests(requester)?;
+
+    if let Some(preview) = app.preview() {
+        preview.send_request(requester)?;
+    }
+
+    Ok(())
+}
+
+/// Moves from the room we were in to the one the app has switched to, if any.
+pub fn switch_room(
+    old_room: String,
+    new_room: Option<String>,
+    requester: Option<&mut Requester>,
+) -> anyhow::Result<()> {
+    // if we’re disconnected we’ll join the new room once we reconnect
+    if let (Some(new_room), Some(requester)) = (new_room, requester) {
+        requester.leave_room(old_room)?;
+        requester.join_room(
+            new_room,
+            HistoryQuery::Latest {
+                count: HISTORY_PAGE_SIZE,
+            },
+        )?;
+        requester.list_rooms()?;
+    }
+
+    Ok(())
+}
+
+pub fn select_file(app: &mut App, select: impl FnOnce(&mut App) -> Option<&FileMetadata>) {
+    let notice = select(app).map(|file| {
+        format!(
+            "Selected {}; press Ctrl-V to preview it or Ctrl-S to save it.",
+            file.filename
+        )
+    });
+    app.set_notice(notice);
+}
+
+pub fn preview_selected_file(
+    app: &mut App,
+    requester: Option<&mut Requester>,
+) -> anyhow::Result<()> {
+    app.set_notice(None);
+
+    let preview = app
+        .preview_selected_file()
+        .context("there’s no file selected; choose one with Alt-Up")?;
+
+    // if we’re disconnected it’s asked for once we reconnect
+    if let Some(requester) = requester {
+        preview.send_request(requester)?;
+    }
+
+    Ok(())
+}
+
+pub fn save_selected_file(
+    app: &mut App,
+    downloads: &mut Downloads,
+    requester: Option<&mut Requester>,
+) -> anyhow::Result<()> {
+    let file = app
+        .selected_file()
+        .cloned()
+        .context("there’s no file selected; choose one with Alt-Up")?;
+
+    let notice = match downloads.start(file.clone(), requester)? {
+        Some(download_event) => download_event.to_string(),
+        None => format!("Downloading {}…", file.filename),
+    };
+
+    app.clear_selection();
+    app.set_notice(Some(notice));
+
+    Ok(())
+}
@@ -1,31 +1,79 @@
-use super::{ui, Event, Timeline};
-use crate::User;
+use super::{ui, Event, EventKind, Preview, Timeline};
+use crate::{FileMetadata, HistoryPage, HistoryQuery, User, ViewerMessage};
+use itertools::Itertools;
 use std::collections::HashSet;
+use std::io::{self, Write};
+use std::time::Duration;
+use uuid::Uuid;
 
 pub struct App {
     timeline: Timeline,
     currently_typing_users: HashSet<User>,
     terminal_height: usize,
+    room: String,
+    rooms: Vec<String>,
+    // None while we wait for the history of a room we’ve just joined
+    history_start: Option<usize>,
+    is_fetching_history: bool,
+    /// Events that arrived while we were still waiting for history,
+    /// held back so that they don’t get ahead of it.
+    /// None unless there’s more history to come.
+    held_events: Option<Vec<Event>>,
+    connection_state: ConnectionState,
+    /// Something the user should know about that isn’t an event.
+    notice: Option<String>,
+    /// The event of the file the user has picked out to save.
+    selected_file: Option<Uuid>,
+    /// Shown instead of the timeline while it’s open.
+    preview: Option<Preview>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Reconnecting { retry_in: Duration },
 }
 
 impl App {
-    pub fn new(terminal_height: usize) -> Self {
+    pub fn new(terminal_height: usize, room: String) -> Self {
         Self {
-            timeline: Timeline::new(terminal_height - 1),
+            timeline: Timeline::new(timeline_height(terminal_height)),
             currently_typing_users: HashSet::new(),
             terminal_height,
+            rooms: vec![room.clone()],
+            room,
+            history_start: None,
+            is_fetching_history: true,
+            held_events: None,
+            connection_state: ConnectionState::Connecting,
+            notice: None,
+            selected_file: None,
+            preview: None,
         }
     }
 
     pub fn render(&self) -> RenderedUi {
         let mut output = RenderedUi::default();
 
-        let visible_events = self.timeline.visible_events();
-        for event in visible_events {
-            output.add_line(&ui::render_event(event));
+        output.add_line(&format!(
+            "{} {}{}",
+            ui::render_rooms(self.rooms.iter(), &self.room),
+            ui::render_connection_state(self.connection_state),
+            ui::render_notice(self.notice.as_deref()),
+        ));
+
+        let timeline_height = timeline_height(self.terminal_height);
+        let rows = match self.preview {
+            Some(ref preview) => preview.render(timeline_height),
+            None => self.render_timeline(timeline_height),
+        };
+
+        for row in &rows {
+            output.add_line(row);
         }
 
-        for _ in 0..self.terminal_height - visible_events.len() - 1 {
+        for _ in rows.len()..timeline_height {
             output.add_empty_line();
         }
 
@@ -36,21 +84,223 @@ impl App {
         output
     }
 
+    fn render_timeline(&self, height: usize) -> Vec<String> {
+        let rendered_events: Vec<_> = self
+            .timeline
+            .visible_events()
+            .iter()
+            .map(|event| {
+                if Some(event.id) == self.selected_file {
+                    ui::render_selected_event(event)
+                } else {
+                    ui::render_event(event)
+                }
+            })
+            .collect();
+        let rows: Vec<_> = rendered_events
+            .iter()
+            .flat_map(|event| event.split('\n'))
+            .collect();
+
+        // an event too tall for the screen is cut off at the top
+        rows[rows.len().saturating_sub(height)..]
+            .iter()
+            .map(|row| row.to_string())
+            .collect()
+    }
+
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
     pub fn handle_event(&mut self, event: Event) {
-        self.timeline.add_event(event);
+        if let Some(ref mut held_events) = self.held_events {
+            held_events.push(event);
+            return;
+        }
+
+        // direct messages are shown no matter which room we’re in,
+        // but we may still receive a few events from a room we’ve just left
+        if matches!(event.event, EventKind::DirectMessage { .. }) || event.room == self.room {
+            self.add_event(event);
+        }
+    }
+
+    fn add_event(&mut self, event: Event) {
+        // an event can arrive both in a history page and live
+        // if it happened just as we joined the room
+        let is_duplicate = self
+            .timeline
+            .last_event()
+            .is_some_and(|last_event| event.seq <= last_event.seq);
+
+        if !is_duplicate {
+            self.timeline.add_event(event);
+        }
+    }
+
+    pub fn set_connection_state(&mut self, connection_state: ConnectionState) {
+        self.connection_state = connection_state;
+
+        if let ConnectionState::Reconnecting { .. } = connection_state {
+            self.currently_typing_users.clear();
+            // they’ll be part of the history we resume from
+            self.held_events = None;
+        }
     }
 
+    /// The history we need when (re)joining the current room,
+    /// which only includes events we haven’t seen yet.
+    pub fn history_to_resume_from(&mut self, count: usize) -> HistoryQuery {
+        self.is_fetching_history = true;
+        // new events can arrive before the history does
+        self.held_events = Some(Vec::new());
+
+        match self.timeline.last_event() {
+            Some(last_event) => HistoryQuery::After {
+                seq: last_event.seq,
+            },
+            None => HistoryQuery::Latest { count },
+        }
+    }
+
+    /// Returns the query for the rest of the history
+    /// if there was more than the server could send in one go.
+    pub fn handle_history_page(&mut self, page: HistoryPage) -> Option<HistoryQuery> {
+        if page.room != self.room {
+            return None;
+        }
+
+        let events = page.events.into_iter().filter_map(Event::from_server_event);
+
+        match self.history_start {
+            Some(history_start) if page.first_index < history_start => {
+                self.timeline.prepend_events(events.collect());
+                self.history_start = Some(page.first_index);
+            }
+
+            _ => {
+                for event in events {
+                    self.add_event(event);
+                }
+
+                self.history_start.get_or_insert(page.first_index);
+            }
+        }
+
+        if !page.is_last {
+            return None;
+        }
+
+        if let Some(seq) = page.more_after {
+            self.held_events.get_or_insert_with(Vec::new);
+            return Some(HistoryQuery::After { seq });
+        }
+
+        self.is_fetching_history = false;
+
+        for event in self.held_events.take().into_iter().flatten() {
+            self.handle_event(event);
+        }
+
+        None
+    }
+
+    /// Returns the index older history should be fetched from
+    /// if the user has scrolled to the top of the timeline.
+    pub fn older_history_to_fetch(&mut self) -> Option<usize> {
+        match self.history_start {
+            Some(history_start)
+                if self.timeline.at_top() && history_start > 0 && !self.is_fetching_history =>
+            {
+                self.is_fetching_history = true;
+                Some(history_start)
+            }
+
+            _ => None,
+        }
+    }
+
+    pub fn set_rooms(&mut self, rooms: Vec<String>) {
+        self.rooms = rooms;
+
+        if !self.rooms.contains(&self.room) {
+            self.rooms.push(self.room.clone());
+            self.rooms.sort();
+        }
+    }
+
+    /// Switches to the next room in the list,
+    /// returning its name so that it can be joined.
+    pub fn next_room(&mut self) -> Option<String> {
+        self.switch_room(1)
+    }
+
+    /// Switches to the previous room in the list,
+    /// returning its name so that it can be joined.
+    pub fn previous_room(&mut self) -> Option<String> {
+        self.switch_room(self.rooms.len() - 1)
+    }
+
+    /// Switches to `room`, even if it isn’t in the list yet,
+    /// returning its name if it needs to be joined.
+    pub fn join_room(&mut self, room: String) -> Option<String> {
+        if room == self.room {
+            return None;
+        }
+
+        if !self.rooms.contains(&room) {
+            self.rooms.push(room.clone());
+            self.rooms.sort();
+        }
+
+        self.enter_room(room.clone());
+        Some(room)
+    }
+
+    fn switch_room(&mut self, offset: usize) -> Option<String> {
+        let current_idx = self.rooms.iter().position(|room| *room == self.room)?;
+        let new_room = self.rooms[(current_idx + offset) % self.rooms.len()].clone();
+
+        if new_room == self.room {
+            return None;
+        }
+
+        self.enter_room(new_room.clone());
+        Some(new_room)
+    }
+
+    fn enter_room(&mut self, room: String) {
+        self.room = room;
+        self.timeline = Timeline::new(timeline_height(self.terminal_height));
+        self.currently_typing_users.clear();
+        self.history_start = None;
+        self.is_fetching_history = true;
+        // new events can arrive before the history does
+        self.held_events = Some(Vec::new());
+        self.selected_file = None;
+        self.preview = None;
+    }
+
+    /// Scrolls the preview instead of the timeline while it’s open.
     pub fn scroll_up(&mut self) {
-        self.timeline.scroll_up();
+        match self.preview {
+            Some(ref mut preview) => preview.scroll_up(),
+            None => self.timeline.scroll_up(),
+        }
     }
 
+    /// Scrolls the preview instead of the timeline while it’s open.
     pub fn scroll_down(&mut self) {
-        self.timeline.scroll_down();
+        match self.preview {
+            Some(ref mut preview) => preview.scroll_down(),
+            None => self.timeline.scroll_down(),
+        }
     }
 
     pub fn resize(&mut self, new_terminal_height: usize) {
         self.terminal_height = new_terminal_height;
-        self.timeline.resize(new_terminal_height - 1);
+        self.timeline.resize(timeline_height(new_terminal_height));
     }
 
     pub fn start_typing(&mut self, user: User) {
@@ -60,6 +310,102 @@ impl App {
     pub fn stop_typing(&mut self, user: &User) {
         self.currently_typing_users.remove(user);
     }
+
+    pub fn set_notice(&mut self, notice: Option<String>) {
+        self.notice = notice;
+    }
+
+    /// Selects the file sent before the selected one,
+    /// or the latest file if none is selected,
+    /// returning it if there is one.
+    pub fn select_older_file(&mut self) -> Option<&FileMetadata> {
+        let selected_idx = self.selected_file_idx();
+        let file_idxs = self.file_idxs();
+
+        let idx = match selected_idx {
+            // the oldest file stays selected
+            Some(selected_idx) => file_idxs
+                .into_iter()
+                .rfind(|&idx| idx < selected_idx)
+                .unwrap_or(selected_idx),
+            None => file_idxs.last().copied()?,
+        };
+
+        self.select_event(Some(idx))
+    }
+
+    /// Selects the file sent after the selected one,
+    /// returning it if there is one;
+    /// moving on from the latest file selects nothing.
+    pub fn select_newer_file(&mut self) -> Option<&FileMetadata> {
+        let selected_idx = self.selected_file_idx()?;
+        let idx = self.file_idxs().into_iter().find(|&idx| idx > selected_idx);
+
+        self.select_event(idx)
+    }
+
+    pub fn selected_file(&self) -> Option<&FileMetadata> {
+        self.timeline.events()[self.selected_file_idx()?].file()
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.selected_file = None;
+    }
+
+    /// Opens a preview of the selected file,
+    /// returning it so that the start of the file can be asked for.
+    pub fn preview_selected_file(&mut self) -> Option<&Preview> {
+        let file = self.selected_file()?.clone();
+        Some(self.preview.insert(Preview::new(file)))
+    }
+
+    pub fn preview(&self) -> Option<&Preview> {
+        self.preview.as_ref()
+    }
+
+    /// Returns whether there was a preview to close.
+    pub fn close_preview(&mut self) -> bool {
+        self.preview.take().is_some()
+    }
+
+    /// Passes the start of a file to the preview if it’s waiting for it,
+    /// handing back any other message.
+    pub fn handle_download_message(&mut self, message: ViewerMessage) -> Option<ViewerMessage> {
+        match self.preview {
+            Some(ref mut preview) => preview.handle_message(message),
+            None => Some(message),
+        }
+    }
+
+    fn select_event(&mut self, idx: Option<usize>) -> Option<&FileMetadata> {
+        self.selected_file = idx.map(|idx| self.timeline.events()[idx].id);
+        // the file being previewed is no longer the selected one
+        self.preview = None;
+
+        let idx = idx?;
+        self.timeline.scroll_to(idx);
+        self.timeline.events()[idx].file()
+    }
+
+    fn selected_file_idx(&self) -> Option<usize> {
+        let selected_file = self.selected_file?;
+
+        self.timeline
+            .events()
+            .iter()
+            .position(|event| event.id == selected_file)
+    }
+
+    /// The indices in the timeline of events with files that can be saved.
+    fn file_idxs(&self) -> Vec<usize> {
+        self.timeline
+            .events()
+            .iter()
+            .enumerate()
+            .filter(|(_, event)| event.file().is_some())
+            .map(|(idx, _)| idx)
+            .collect()
+    }
 }
 
 #[derive(Default)]
@@ -87,4 +433,247 @@ impl RenderedUi {
     pub fn lines(&self) -> impl Iterator<Item = &str> {
         self.buf.split('\n')
     }
+
+    /// Writes the lines out for a terminal in raw mode,
+    /// where going to the next line needs a carriage return too.
+    pub fn print(&self, out: &mut impl Write) -> io::Result<()> {
+        for line in Itertools::intersperse(self.lines(), "\r\n") {
+            write!(out, "{}", line)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The rows left for the timeline once the room list and typing users are shown,
+/// which is always at least one however small the terminal gets.
+fn timeline_height(terminal_height: usize) -> usize {
+    terminal_height.saturating_sub(2).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::viewer::dummy_events::{EVENT_1, EVENT_2};
+    use crate::{Message, DEFAULT_ROOM};
+
+    fn event(event: &Event, seq: u64) -> Event {
+        Event {
+            seq,
+            ..event.clone()
+        }
+    }
+
+    fn empty_page() -> HistoryPage {
+        HistoryPage {
+            room: DEFAULT_ROOM.to_string(),
+            events: Vec::new(),
+            first_index: 0,
+            is_last: true,
+            more_after: None,
+        }
+    }
+
+    #[test]
+    fn resumes_from_last_seen_event() {
+        let mut app = App::new(10, DEFAULT_ROOM.to_string());
+        assert_eq!(
+            app.history_to_resume_from(5),
+            HistoryQuery::Latest { count: 5 }
+        );
+        app.handle_history_page(empty_page());
+
+        app.handle_event(event(&EVENT_1, 1));
+        app.handle_event(event(&EVENT_2, 2));
+        assert_eq!(
+            app.history_to_resume_from(5),
+            HistoryQuery::After { seq: 2 }
+        );
+    }
+
+    #[test]
+    fn tiny_terminals_still_show_a_row_of_timeline() {
+        let mut app = App::new(0, DEFAULT_ROOM.to_string());
+        app.handle_event(event(&EVENT_1, 1));
+        app.render();
+
+        app.resize(1);
+        app.handle_event(event(&EVENT_2, 2));
+        app.render();
+        assert_eq!(app.timeline.visible_events(), [event(&EVENT_2, 2)]);
+    }
+
+    #[test]
+    fn events_already_seen_are_ignored() {
+        let mut app = App::new(10, DEFAULT_ROOM.to_string());
+        app.handle_event(event(&EVENT_1, 1));
+        app.handle_event(event(&EVENT_2, 2));
+        app.handle_event(event(&EVENT_2, 2));
+
+        assert_eq!(
+            app.timeline.visible_events(),
+            [event(&EVENT_1, 1), event(&EVENT_2, 2)]
+        );
+    }
+
+    #[test]
+    fn events_arriving_before_the_history_wait_for_it() {
+        let mut app = App::new(10, DEFAULT_ROOM.to_string());
+        app.join_room("random".to_string());
+
+        app.handle_event(Event {
+            room: "random".to_string(),
+            ..event(&EVENT_1, 5)
+        });
+        assert!(app.timeline.visible_events().is_empty());
+
+        app.handle_history_page(HistoryPage {
+            room: "random".to_string(),
+            events: vec![crate::Event {
+                seq: 3,
+                id: Uuid::nil(),
+                event: crate::EventKind::Login,
+                user: EVENT_1.user.clone(),
+                room: "random".to_string(),
+                time_occurred: EVENT_1.time_occurred,
+            }],
+            ..empty_page()
+        });
+        let seqs: Vec<_> = app
+            .timeline
+            .visible_events()
+            .iter()
+            .map(|event| event.seq)
+            .collect();
+        assert_eq!(seqs, [3, 5]);
+    }
+
+    #[test]
+    fn new_events_wait_for_the_rest_of_the_history() {
+        let mut app = App::new(10, DEFAULT_ROOM.to_string());
+        app.handle_event(event(&EVENT_1, 1));
+        let page = |seq, more_after| HistoryPage {
+            room: DEFAULT_ROOM.to_string(),
+            events: vec![crate::Event {
+                seq,
+                id: Uuid::nil(),
+                event: crate::EventKind::Login,
+                user: EVENT_1.user.clone(),
+                room: DEFAULT_ROOM.to_string(),
+                time_occurred: EVENT_1.time_occurred,
+            }],
+            first_index: 1,
+            is_last: true,
+            more_after,
+        };
+
+        assert_eq!(
+            app.handle_history_page(page(2, Some(2))),
+            Some(HistoryQuery::After { seq: 2 })
+        );
+        app.handle_event(event(&EVENT_2, 4));
+        assert_eq!(app.timeline.visible_events().len(), 2);
+
+        assert_eq!(app.handle_history_page(page(3, None)), None);
+        let seqs: Vec<_> = app
+            .timeline
+            .visible_events()
+            .iter()
+            .map(|event| event.seq)
+            .collect();
+        assert_eq!(seqs, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn joining_a_new_room_adds_it_to_the_list() {
+        let mut app = App::new(10, DEFAULT_ROOM.to_string());
+        app.handle_event(event(&EVENT_1, 1));
+
+        assert_eq!(
+            app.join_room("random".to_string()),
+            Some("random".to_string())
+        );
+        assert_eq!(app.join_room("random".to_string()), None);
+        assert_eq!(app.rooms, ["general", "random"]);
+        assert!(app.timeline.visible_events().is_empty());
+    }
+
+    #[test]
+    fn files_are_selected_from_newest_to_oldest() {
+        let mut app = App::new(10, DEFAULT_ROOM.to_string());
+        let file = |seq, filename: &str| Event {
+            id: Uuid::new_v4(),
+            event: EventKind::Message(Message::File {
+                metadata: Some(FileMetadata {
+                    filename: filename.to_string(),
+                    size: 0,
+                    mime_type: "text/plain".to_string(),
+                    sha256: String::new(),
+                }),
+                contents: Vec::new(),
+            }),
+            ..event(&EVENT_1, seq)
+        };
+
+        app.handle_event(file(1, "old.txt"));
+        app.handle_event(event(&EVENT_2, 2));
+        app.handle_event(file(3, "new.txt"));
+        app.handle_event(event(&EVENT_2, 4));
+        assert_eq!(app.select_newer_file(), None);
+
+        let filename = |file: Option<&FileMetadata>| file.map(|file| file.filename.clone());
+        assert_eq!(
+            filename(app.select_older_file()),
+            Some("new.txt".to_string())
+        );
+        assert_eq!(
+            filename(app.select_older_file()),
+            Some("old.txt".to_string())
+        );
+        assert_eq!(
+            filename(app.select_older_file()),
+            Some("old.txt".to_string())
+        );
+        assert_eq!(
+            filename(app.select_newer_file()),
+            Some("new.txt".to_string())
+        );
+        assert_eq!(app.select_newer_file(), None);
+        assert_eq!(app.selected_file(), None);
+    }
+
+    #[test]
+    fn previews_replace_the_timeline_until_closed() {
+        let mut app = App::new(10, DEFAULT_ROOM.to_string());
+        assert!(app.preview_selected_file().is_none());
+
+        app.handle_event(Event {
+            event: EventKind::Message(Message::File {
+                metadata: Some(FileMetadata {
+                    filename: "empty.txt".to_string(),
+                    size: 0,
+                    mime_type: "text/plain".to_string(),
+                    sha256: String::new(),
+                }),
+                contents: Vec::new(),
+            }),
+            ..event(&EVENT_1, 1)
+        });
+        app.select_older_file();
+        assert!(app.preview_selected_file().is_some());
+
+        let rendered = app.render();
+        let rows: Vec<_> = rendered.lines().collect();
+        assert!(rows[1].contains("empty.txt (0 B, text/plain)"));
+        assert!(rows[2].contains("The file is empty."));
+
+        assert!(app.close_preview());
+        assert!(!app.close_preview());
+        assert!(app
+            .render()
+            .lines()
+            .nth(1)
+            .unwrap()
+            .contains("sent empty.txt"));
+    }
 }
@@ -0,0 +1,92 @@
+use super::protocol::{IncorrectCredentials, ReadingEvents};
+use super::{Channels, Protocol, Requester};
+use crate::backoff::Backoff;
+use crate::stream::Connector;
+use crate::Credentials;
+use flume::{Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+pub enum ConnectionEvent {
+    /// We’ve (re)connected to the server,
+    /// but haven’t joined any rooms yet.
+    Connected(Requester),
+    Disconnected {
+        error: anyhow::Error,
+        retry_in: Duration,
+    },
+    /// The server didn’t accept our credentials,
+    /// so there’s no point trying again.
+    LoginFailed,
+}
+
+/// Stays connected to the server for as long as the program runs,
+/// reconnecting with exponential backoff whenever the connection is lost.
+pub fn maintain_connection(
+    connector: Connector,
+    credentials: Credentials,
+    channels: Channels,
+    connection_event_tx: Sender<ConnectionEvent>,
+) {
+    let mut backoff = Backoff::default();
+
+    loop {
+        let error = match connect(&connector, &credentials, channels.clone()) {
+            Ok((mut protocol, requester)) => {
+                backoff.reset();
+
+                // dropped once the connection is lost,
+                // which stops the heartbeats
+                let (_connected_tx, connected_rx) = flume::bounded::<()>(0);
+                thread::spawn({
+                    let requester = requester.clone();
+                    let interval = connector.heartbeat.interval;
+                    move || send_heartbeats(requester, interval, connected_rx)
+                });
+
+                connection_event_tx
+                    .send(ConnectionEvent::Connected(requester))
+                    .unwrap();
+
+                let Err(e) = protocol.read_events();
+                e
+            }
+            Err(e) if e.is::<IncorrectCredentials>() => {
+                connection_event_tx
+                    .send(ConnectionEvent::LoginFailed)
+                    .unwrap();
+                return;
+            }
+            Err(e) => e,
+        };
+
+        let retry_in = backoff.next_delay();
+        connection_event_tx
+            .send(ConnectionEvent::Disconnected { error, retry_in })
+            .unwrap();
+
+        thread::sleep(retry_in);
+    }
+}
+
+fn connect(
+    connector: &Connector,
+    credentials: &Credentials,
+    channels: Channels,
+) -> anyhow::Result<(Protocol<ReadingEvents>, Requester)> {
+    let protocol = Protocol::connect(connector)?
+        .say_hello()?
+        .send_connection_kind()?
+        .log_in(credentials, channels)?;
+    let requester = protocol.requester()?;
+
+    Ok((protocol, requester))
+}
+
+fn send_heartbeats(mut requester: Requester, interval: Duration, connected_rx: Receiver<()>) {
+    while let Err(RecvTimeoutError::Timeout) = connected_rx.recv_timeout(interval) {
+        if requester.heartbeat().is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,446 @@
+use super::Requester;
+use crate::{FileMetadata, ViewerMessage};
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// How much to ask for at once,
+/// so that events arriving during a download don’t wait long behind it.
+pub(super) const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// How much to ask for before any of it has arrived,
+/// so there’s always something on its way.
+const WINDOW_SIZE: u64 = 4 * CHUNK_SIZE;
+
+/// What happened to a download, for telling the user about.
+#[derive(Debug, PartialEq)]
+pub enum DownloadEvent {
+    Progress { filename: String, percent: u64 },
+    Finished { filename: String, path: PathBuf },
+    Failed { filename: String, reason: String },
+}
+
+impl fmt::Display for DownloadEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Progress { filename, percent } => {
+                write!(f, "Downloading {}… {}%", filename, percent)
+            }
+            Self::Finished { filename, path } => {
+                write!(f, "Saved {} to {}.", filename, path.display())
+            }
+            Self::Failed { filename, reason } => {
+                write!(f, "Couldn’t download {} ({}).", filename, reason)
+            }
+        }
+    }
+}
+
+/// The files being downloaded into a directory,
+/// which all share the connection to the server.
+pub struct Downloads {
+    dir: PathBuf,
+    downloads: Vec<Download>,
+}
+
+impl Downloads {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            downloads: Vec::new(),
+        }
+    }
+
+    /// Starts downloading a file,
+    /// failing with a message meant for the user.
+    ///
+    /// If we’re disconnected it carries on once [`Downloads::send_requests`]
+    /// is called with a new connection.
+    /// Empty files have nothing to download,
+    /// so they’re saved straight away.
+    pub fn start(
+        &mut self,
+        metadata: FileMetadata,
+        requester: Option<&mut Requester>,
+    ) -> anyhow::Result<Option<DownloadEvent>> {
+        anyhow::ensure!(
+            self.position(&metadata.sha256).is_none(),
+            "{} is already being downloaded",
+            metadata.filename
+        );
+
+        let download = Download::start(metadata, &self.dir)?;
+
+        if download.is_complete() {
+            let filename = download.metadata.filename.clone();
+            let path = download.finish()?;
+            return Ok(Some(DownloadEvent::Finished { filename, path }));
+        }
+
+        self.downloads.push(download);
+
+        if let Some(requester) = requester {
+            self.send_requests(requester)?;
+        }
+
+        Ok(None)
+    }
+
+    /// Asks for as much of every file as can be on its way at once.
+    pub fn send_requests(&mut self, requester: &mut Requester) -> anyhow::Result<()> {
+        for download in &mut self.downloads {
+            while let Some((offset, len)) = download.next_request() {
+                requester.download(download.metadata.sha256.clone(), offset, len)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Carries on with every download from however much of it has arrived,
+    /// since whatever else was on its way was lost with the connection.
+    pub fn resume(&mut self) {
+        for download in &mut self.downloads {
+            download.resume();
+        }
+    }
+
+    /// Handles [`ViewerMessage::FileChunk`] and [`ViewerMessage::DownloadFailed`],
+    /// after which [`Downloads::send_requests`] should be called to ask for more.
+    pub fn handle_message(&mut self, message: ViewerMessage) -> Option<DownloadEvent> {
+        let (idx, result) = match message {
+            ViewerMessage::FileChunk {
+                sha256,
+                offset,
+                data,
+            } => {
+                let idx = self.position(&sha256)?;
+                (idx, self.downloads[idx].write(offset, &data))
+            }
+            ViewerMessage::DownloadFailed { sha256, reason } => {
+                (self.position(&sha256)?, Err(anyhow::anyhow!(reason)))
+            }
+            _ => return None,
+        };
+
+        match result {
+            Ok(percent) if !self.downloads[idx].is_complete() => {
+                percent.map(|percent| DownloadEvent::Progress {
+                    filename: self.downloads[idx].metadata.filename.clone(),
+                    percent,
+                })
+            }
+
+            Ok(_) => {
+                let download = self.downloads.remove(idx);
+                let filename = download.metadata.filename.clone();
+
+                Some(match download.finish() {
+                    Ok(path) => DownloadEvent::Finished { filename, path },
+                    Err(e) => DownloadEvent::Failed {
+                        filename,
+                        reason: format!("{:#}", e),
+                    },
+                })
+            }
+
+            Err(e) => {
+                let download = self.downloads.remove(idx);
+                let filename = download.metadata.filename.clone();
+                download.abandon();
+
+                Some(DownloadEvent::Failed {
+                    filename,
+                    reason: format!("{:#}", e),
+                })
+            }
+        }
+    }
+
+    fn position(&self, sha256: &str) -> Option<usize> {
+        self.downloads
+            .iter()
+            .position(|download| download.metadata.sha256 == sha256)
+    }
+}
+
+/// A file arriving from the server a chunk at a time,
+/// kept out of sight in the directory it’s going to
+/// until all of it has arrived.
+///
+/// Whatever arrived before a download was cut off is kept,
+/// even if the viewer was closed in the meantime,
+/// so starting it again carries on from there.
+struct Download {
+    metadata: FileMetadata,
+    dir: PathBuf,
+    partial_path: PathBuf,
+    file: File,
+    requested: u64,
+    received: u64,
+    // hashing as chunks arrive saves going over the whole file at the end
+    hasher: Sha256,
+    /// The percentage last reported to the user.
+    reported_percent: Option<u64>,
+}
+
+impl Download {
+    fn start(metadata: FileMetadata, dir: &Path) -> anyhow::Result<Self> {
+        // the hash comes from the sender,
+        // so it could otherwise name a file anywhere
+        anyhow::ensure!(
+            metadata.has_valid_hash(),
+            "the server sent a file without a proper hash"
+        );
+
+        let partial_path = dir.join(format!(".{}.part", metadata.sha256));
+
+        let open = || -> io::Result<_> {
+            fs::create_dir_all(dir)?;
+            let mut file = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&partial_path)?;
+
+            // chunks are always asked for whole,
+            // so anything after the last whole one is thrown away
+            let len = file.metadata()?.len();
+            let received = if len > metadata.size {
+                0
+            } else {
+                len / CHUNK_SIZE * CHUNK_SIZE
+            };
+            file.set_len(received)?;
+
+            let mut hasher = Sha256::new();
+            io::copy(&mut (&mut file).take(received), &mut hasher)?;
+
+            Ok((file, received, hasher))
+        };
+        let (file, received, hasher) =
+            open().with_context(|| format!("couldn’t write to ‘{}’", dir.display()))?;
+
+        Ok(Self {
+            metadata,
+            dir: dir.to_path_buf(),
+            partial_path,
+            file,
+            requested: received,
+            received,
+            hasher,
+            reported_percent: None,
+        })
+    }
+
+    /// The next part of the file to ask for,
+    /// if there’s more to come that can be on its way at once.
+    fn next_request(&mut self) -> Option<(u64, u64)> {
+        if self.requested >= self.metadata.size || self.requested - self.received >= WINDOW_SIZE {
+            return None;
+        }
+
+        let offset = self.requested;
+        let len = CHUNK_SIZE.min(self.metadata.size - offset);
+        self.requested += len;
+
+        Some((offset, len))
+    }
+
+    fn resume(&mut self) {
+        self.requested = self.received;
+    }
+
+    /// Writes a chunk to disk,
+    /// returning the percentage of the file that’s arrived
+    /// if it’s changed since it was last reported.
+    fn write(&mut self, offset: u64, data: &[u8]) -> anyhow::Result<Option<u64>> {
+        // left over from before we reconnected and asked for it again
+        if offset != self.received {
+            return Ok(None);
+        }
+
+        anyhow::ensure!(
+            data.len() as u64 == CHUNK_SIZE.min(self.metadata.size - offset),
+            "the server sent the wrong amount of the file"
+        );
+
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        self.hasher.update(data);
+        self.received += data.len() as u64;
+
+        let percent = self.received * 100 / self.metadata.size;
+
+        if self.reported_percent == Some(percent) {
+            Ok(None)
+        } else {
+            self.reported_percent = Some(percent);
+            Ok(Some(percent))
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received == self.metadata.size
+    }
+
+    /// Checks the file is the one that was sent
+    /// and moves it to where the user can see it,
+    /// returning where that is.
+    fn finish(mut self) -> anyhow::Result<PathBuf> {
+        if format!("{:x}", self.hasher.finalize_reset()) != self.metadata.sha256 {
+            self.abandon();
+            anyhow::bail!("the file was corrupted on its way here");
+        }
+
+        let path = free_path(&self.dir, &self.metadata.filename);
+
+        self.file.sync_all()?;
+        fs::rename(&self.partial_path, &path)
+            .with_context(|| format!("couldn’t write to ‘{}’", path.display()))?;
+
+        Ok(path)
+    }
+
+    fn abandon(self) {
+        let _ = fs::remove_file(&self.partial_path);
+    }
+}
+
+/// Where to save a file without overwriting anything,
+/// going by the name it was sent with but never outside `dir`.
+fn free_path(dir: &Path, filename: &str) -> PathBuf {
+    let filename = Path::new(filename)
+        .file_name()
+        .map_or_else(|| "download".into(), |filename| filename.to_string_lossy());
+    let path = dir.join(&*filename);
+
+    if !path.exists() {
+        return path;
+    }
+
+    let (stem, extension) = match filename.rsplit_once('.') {
+        // a leading dot starts a hidden file’s name rather than its extension
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (&*filename, None),
+    };
+
+    (1..)
+        .map(|n| match extension {
+            Some(extension) => dir.join(format!("{} ({}).{}", stem, n, extension)),
+            None => dir.join(format!("{} ({})", stem, n)),
+        })
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("nunitius-download-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn metadata(filename: &str, contents: &[u8]) -> FileMetadata {
+        FileMetadata {
+            filename: filename.to_string(),
+            size: contents.len() as u64,
+            mime_type: "application/octet-stream".to_string(),
+            sha256: format!("{:x}", Sha256::digest(contents)),
+        }
+    }
+
+    #[test]
+    fn chunks_are_asked_for_a_window_at_a_time() {
+        let dir = temp_dir("window");
+        let contents: Vec<u8> = (0..WINDOW_SIZE + 10).map(|i| i as u8).collect();
+        let mut download = Download::start(metadata("big", &contents), &dir).unwrap();
+
+        let mut requests = Vec::new();
+        while let Some(request) = download.next_request() {
+            requests.push(request);
+        }
+        assert_eq!(requests.len() as u64, WINDOW_SIZE / CHUNK_SIZE);
+
+        for (offset, len) in requests {
+            let chunk = &contents[offset as usize..(offset + len) as usize];
+            download.write(offset, chunk).unwrap();
+        }
+        assert_eq!(download.next_request(), Some((WINDOW_SIZE, 10)));
+        assert_eq!(
+            download
+                .write(WINDOW_SIZE, &contents[WINDOW_SIZE as usize..])
+                .unwrap(),
+            Some(100)
+        );
+
+        assert!(download.is_complete());
+        let path = download.finish().unwrap();
+        assert_eq!(path, dir.join("big"));
+        assert_eq!(fs::read(path).unwrap(), contents);
+    }
+
+    #[test]
+    fn corrupted_files_are_thrown_away() {
+        let dir = temp_dir("corrupted");
+        let mut download = Download::start(metadata("notes.txt", b"hello\n"), &dir).unwrap();
+
+        download.next_request();
+        download.write(0, b"jello\n").unwrap();
+
+        assert!(download.finish().is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn downloads_carry_on_from_what_was_saved_before() {
+        let dir = temp_dir("resume");
+        let contents: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| i as u8).collect();
+
+        let mut download = Download::start(metadata("big", &contents), &dir).unwrap();
+        download.next_request();
+        download.write(0, &contents[..CHUNK_SIZE as usize]).unwrap();
+        drop(download);
+
+        let mut download = Download::start(metadata("big", &contents), &dir).unwrap();
+        assert_eq!(download.next_request(), Some((CHUNK_SIZE, 10)));
+        download
+            .write(CHUNK_SIZE, &contents[CHUNK_SIZE as usize..])
+            .unwrap();
+        assert_eq!(fs::read(download.finish().unwrap()).unwrap(), contents);
+    }
+
+    #[test]
+    fn hashes_that_arent_hashes_are_refused() {
+        let dir = temp_dir("hashes");
+        let metadata = FileMetadata {
+            sha256: "/../../../x".to_string(),
+            ..metadata("notes.txt", b"hello\n")
+        };
+
+        assert!(Download::start(metadata, &dir).is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn saved_files_never_overwrite_or_escape_their_directory() {
+        let dir = temp_dir("names");
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        fs::write(dir.join("notes (1).txt"), "").unwrap();
+        fs::write(dir.join(".profile"), "").unwrap();
+
+        assert_eq!(free_path(&dir, "notes.txt"), dir.join("notes (2).txt"));
+        assert_eq!(free_path(&dir, ".profile"), dir.join(".profile (1)"));
+        assert_eq!(free_path(&dir, "../../etc/passwd"), dir.join("passwd"));
+        assert_eq!(free_path(&dir, ".."), dir.join("download"));
+    }
+}
@@ -1,16 +1,20 @@
 use super::{Event, EventKind};
-use crate::User;
+use crate::{User, DEFAULT_ROOM};
 use chrono::Utc;
 use once_cell::sync::Lazy;
+use uuid::Uuid;
 
 macro_rules! define_dummy_event {
     ($name:ident) => {
         pub(super) static $name: Lazy<Event> = Lazy::new(|| Event {
+            seq: 0,
+            id: Uuid::nil(),
             event: EventKind::Login,
             user: User {
                 nickname: stringify!($name).to_string(),
                 color: None,
             },
+            room: DEFAULT_ROOM.to_string(),
             time_occurred: Utc::now(),
         });
     };
@@ -0,0 +1,380 @@
+use super::download::CHUNK_SIZE;
+use super::{ui, Requester};
+use crate::{FileMetadata, ViewerMessage};
+use crossterm::style::{self, style, Stylize};
+use std::path::Path;
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{self, FontStyle, Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+/// How much of the start of a file is shown.
+///
+/// It’s as much as a download asks for at once,
+/// so that if the same file is being previewed and downloaded
+/// it doesn’t matter which of them gets which answer from the server.
+const PREVIEW_SIZE: u64 = CHUNK_SIZE;
+
+/// The biggest binary file that’s shown byte by byte.
+const HEX_DUMP_SIZE: u64 = 4 * 1024;
+
+const HEX_DUMP_WIDTH: usize = 16;
+
+/// A look at what’s in a file without saving it,
+/// which is shown in place of the timeline.
+pub struct Preview {
+    metadata: FileMetadata,
+    // None until the start of the file arrives
+    rows: Option<Vec<String>>,
+    /// How many rows have been scrolled past.
+    scroll: usize,
+}
+
+impl Preview {
+    pub fn new(metadata: FileMetadata) -> Self {
+        // there’s nothing to wait for in an empty file
+        let rows = (metadata.size == 0).then(|| render_contents(&metadata, &[]));
+
+        Self {
+            metadata,
+            rows,
+            scroll: 0,
+        }
+    }
+
+    pub fn metadata(&self) -> &FileMetadata {
+        &self.metadata
+    }
+
+    /// Asks for the start of the file unless it’s already arrived,
+    /// which needs doing again if we reconnect before it does.
+    pub fn send_request(&self, requester: &mut Requester) -> anyhow::Result<()> {
+        if self.rows.is_some() {
+            return Ok(());
+        }
+
+        requester.download(
+            self.metadata.sha256.clone(),
+            0,
+            PREVIEW_SIZE.min(self.metadata.size),
+        )
+    }
+
+    /// Handles the answer to [`Preview::send_request`],
+    /// handing back any other message so that it can go to [`Downloads`](super::Downloads).
+    pub fn handle_message(&mut self, message: ViewerMessage) -> Option<ViewerMessage> {
+        if self.rows.is_some() {
+            return Some(message);
+        }
+
+        match message {
+            ViewerMessage::FileChunk {
+                ref sha256,
+                offset: 0,
+                ref data,
+            } if *sha256 == self.metadata.sha256 => {
+                self.rows = Some(render_contents(&self.metadata, data));
+                None
+            }
+            ViewerMessage::DownloadFailed {
+                ref sha256,
+                ref reason,
+            } if *sha256 == self.metadata.sha256 => {
+                let error = format!("Couldn’t preview {} ({}).", self.metadata.filename, reason);
+                self.rows = Some(vec![style(error).red().to_string()]);
+                None
+            }
+            _ => Some(message),
+        }
+    }
+
+    pub fn scroll_up(&mut self) {
+        self.scroll = self.scroll.saturating_sub(1);
+    }
+
+    /// Stops once the last row is at the top.
+    pub fn scroll_down(&mut self) {
+        let num_rows = self.rows.as_ref().map_or(0, Vec::len);
+
+        if self.scroll + 1 < num_rows {
+            self.scroll += 1;
+        }
+    }
+
+    /// Renders as many rows as fit in `height`,
+    /// starting with one that says what the file is.
+    pub(super) fn render(&self, height: usize) -> Vec<String> {
+        let mut rows = vec![ui::render_preview_header(&self.metadata)];
+
+        match self.rows {
+            Some(ref contents) => rows.extend(
+                contents
+                    .iter()
+                    .skip(self.scroll)
+                    .take(height.saturating_sub(1))
+                    .cloned(),
+            ),
+            None => rows.push(style("Loading…").dim().to_string()),
+        }
+
+        rows
+    }
+}
+
+/// Renders the start of a file as rows,
+/// going by what kind of file it is.
+fn render_contents(metadata: &FileMetadata, data: &[u8]) -> Vec<String> {
+    if metadata.size == 0 {
+        return vec![style("The file is empty.").dim().to_string()];
+    }
+
+    if metadata.mime_type.starts_with("image/") {
+        return vec![match imagesize::blob_size(data) {
+            Ok(size) => format!("{} × {} pixels", size.width, size.height),
+            Err(_) => style("Couldn’t tell how big the image is.")
+                .dim()
+                .to_string(),
+        }];
+    }
+
+    let text = match as_text(data) {
+        Some(text) => text,
+        None if metadata.size <= HEX_DUMP_SIZE => return hex_dump(data),
+        None => {
+            let message = format!(
+                "There’s no preview of binary files bigger than {}.",
+                ui::render_size(HEX_DUMP_SIZE)
+            );
+            return vec![style(message).dim().to_string()];
+        }
+    };
+
+    let mut rows = highlight(&metadata.filename, text);
+
+    if (data.len() as u64) < metadata.size {
+        let message = format!(
+            "… only the first {} is shown",
+            ui::render_size(PREVIEW_SIZE)
+        );
+        rows.push(style(message).dim().to_string());
+    }
+
+    rows
+}
+
+/// Reads `data` as UTF-8 text, if that’s what it looks like.
+fn as_text(data: &[u8]) -> Option<&str> {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        // the preview can end partway through a character
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&data[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+
+    (!text.contains('\0')).then_some(text)
+}
+
+/// Shows each byte in hexadecimal,
+/// alongside the ones that are printable ASCII.
+fn hex_dump(data: &[u8]) -> Vec<String> {
+    data.chunks(HEX_DUMP_WIDTH)
+        .enumerate()
+        .map(|(idx, bytes)| {
+            let hex: Vec<_> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let (first_half, second_half) = hex.split_at(hex.len().min(HEX_DUMP_WIDTH / 2));
+            let ascii: String = bytes
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        char::from(byte)
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+
+            format!(
+                "{:08x}  {:<23}  {:<23}  |{}|",
+                idx * HEX_DUMP_WIDTH,
+                first_half.join(" "),
+                second_half.join(" "),
+                ascii
+            )
+        })
+        .collect()
+}
+
+struct Highlighting {
+    syntaxes: SyntaxSet,
+    theme: Theme,
+}
+
+/// Loading the syntaxes takes a moment,
+/// so it’s only done the first time a text file is previewed.
+fn highlighting() -> &'static Highlighting {
+    static HIGHLIGHTING: OnceLock<Highlighting> = OnceLock::new();
+
+    HIGHLIGHTING.get_or_init(|| Highlighting {
+        syntaxes: SyntaxSet::load_defaults_newlines(),
+        theme: ThemeSet::load_defaults()
+            .themes
+            .remove("base16-ocean.dark")
+            .unwrap(),
+    })
+}
+
+/// Colours each line of `text` going by the extension of `filename`,
+/// or by its first line (such as a shebang) if there isn’t one we know.
+fn highlight(filename: &str, text: &str) -> Vec<String> {
+    let Highlighting { syntaxes, theme } = highlighting();
+
+    let syntax = Path::new(filename)
+        .extension()
+        .and_then(|extension| syntaxes.find_syntax_by_extension(&extension.to_string_lossy()))
+        .or_else(|| syntaxes.find_syntax_by_first_line(text))
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+    let mut highlighter = HighlightLines::new(syntax, theme);
+
+    LinesWithEndings::from(text)
+        .map(|line| match highlighter.highlight_line(line, syntaxes) {
+            Ok(regions) => regions
+                .into_iter()
+                .map(|(region_style, region)| render_region(region_style, region))
+                .collect(),
+            // the line is still worth seeing without colours
+            Err(_) => ui::sanitize(line),
+        })
+        .collect()
+}
+
+fn render_region(region_style: highlighting::Style, region: &str) -> String {
+    let highlighting::Color { r, g, b, .. } = region_style.foreground;
+    let mut styled = style(ui::sanitize(region)).with(style::Color::Rgb { r, g, b });
+
+    if region_style.font_style.contains(FontStyle::BOLD) {
+        styled = styled.bold();
+    }
+    if region_style.font_style.contains(FontStyle::ITALIC) {
+        styled = styled.italic();
+    }
+    if region_style.font_style.contains(FontStyle::UNDERLINE) {
+        styled = styled.underlined();
+    }
+
+    styled.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn metadata(filename: &str, mime_type: &str, contents: &[u8]) -> FileMetadata {
+        FileMetadata {
+            filename: filename.to_string(),
+            size: contents.len() as u64,
+            mime_type: mime_type.to_string(),
+            sha256: format!("{:x}", Sha256::digest(contents)),
+        }
+    }
+
+    /// Takes out the escape sequences that style the text.
+    fn unstyled(row: &str) -> String {
+        let mut unstyled = String::new();
+        let mut chars = row.chars();
+
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|&c| c == 'm');
+            } else {
+                unstyled.push(c);
+            }
+        }
+
+        unstyled
+    }
+
+    #[test]
+    fn text_is_highlighted_and_cut_off() {
+        let contents = "fn main() {\n\tprintln!(\"\x1b[2J\");\n}\n".repeat(3000);
+        let metadata = metadata("main.rs", "text/x-rust", contents.as_bytes());
+        let data = &contents.as_bytes()[..PREVIEW_SIZE as usize];
+
+        let rows = render_contents(&metadata, data);
+        assert_ne!(rows[0], "fn main() {");
+        assert_eq!(unstyled(&rows[0]), "fn main() {");
+        assert_eq!(unstyled(&rows[1]), "    println!(\"�[2J\");");
+        assert_eq!(
+            unstyled(rows.last().unwrap()),
+            "… only the first 65.5 kB is shown"
+        );
+    }
+
+    #[test]
+    fn small_binaries_are_shown_byte_by_byte() {
+        let mut contents: Vec<u8> = (0x3a..0x5c).collect();
+        contents[0] = 0;
+
+        assert_eq!(
+            render_contents(
+                &metadata("data", "application/octet-stream", &contents),
+                &contents
+            ),
+            [
+                "00000000  00 3b 3c 3d 3e 3f 40 41  42 43 44 45 46 47 48 49  |.;<=>?@ABCDEFGHI|",
+                "00000010  4a 4b 4c 4d 4e 4f 50 51  52 53 54 55 56 57 58 59  |JKLMNOPQRSTUVWXY|",
+                "00000020  5a 5b                                             |Z[|",
+            ]
+        );
+
+        let contents = [0xff; HEX_DUMP_SIZE as usize + 1];
+        let rows = render_contents(
+            &metadata("data", "application/octet-stream", &contents),
+            &contents,
+        );
+        assert_eq!(
+            unstyled(&rows[0]),
+            "There’s no preview of binary files bigger than 4.1 kB."
+        );
+    }
+
+    #[test]
+    fn images_are_described_by_their_size() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\x02\x80\0\0\x01\xe0\x08\x06\0\0\0";
+
+        assert_eq!(
+            render_contents(&metadata("cat.png", "image/png", png), png),
+            ["640 × 480 pixels"]
+        );
+    }
+
+    #[test]
+    fn only_the_start_of_the_previewed_file_is_taken() {
+        let contents = b"hello\n";
+        let mut preview = Preview::new(metadata("notes.txt", "text/plain", contents));
+        let chunk = |sha256: &str, offset| ViewerMessage::FileChunk {
+            sha256: sha256.to_string(),
+            offset,
+            data: contents.to_vec(),
+        };
+
+        let other_file = chunk(&"0".repeat(64), 0);
+        assert_eq!(preview.handle_message(other_file.clone()), Some(other_file));
+        let later_chunk = chunk(&preview.metadata.sha256, 6);
+        assert_eq!(
+            preview.handle_message(later_chunk.clone()),
+            Some(later_chunk)
+        );
+
+        assert_eq!(
+            preview.handle_message(chunk(&preview.metadata.sha256, 0)),
+            None
+        );
+        assert_eq!(unstyled(&preview.render(10)[1]), "hello");
+
+        // a download of the same file gets the next answer
+        let start = chunk(&preview.metadata.sha256, 0);
+        assert_eq!(preview.handle_message(start.clone()), Some(start));
+    }
+}
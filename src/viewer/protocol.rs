@@ -1,86 +1,225 @@
 use super::{Event, ServerEvent};
-use crate::ConnectionKind;
+use crate::stream::{self, Connector, Stream};
+use crate::{
+    Capability, ConnectionKind, Credentials, Hello, HelloResponse, HistoryPage, HistoryQuery,
+    LoginResponse, ViewerMessage, ViewerRequest,
+};
 use flume::Sender;
+use std::fmt;
 use std::io::BufReader;
-use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
 
 pub struct Protocol<S: ProtocolState>(S);
 
 pub trait ProtocolState {}
+impl ProtocolState for SayingHello {}
 impl ProtocolState for SendingConnectionKind {}
-impl ProtocolState for ReadingHistory {}
+impl ProtocolState for LoggingIn {}
 impl ProtocolState for ReadingEvents {}
 
-pub struct SendingConnectionKind {
-    stream: TcpStream,
+/// Where everything the server sends us ends up.
+#[derive(Clone)]
+pub struct Channels {
+    pub server_event_tx: Sender<ServerEvent>,
+    pub event_tx: Sender<Event>,
+    pub history_page_tx: Sender<HistoryPage>,
+    pub rooms_tx: Sender<Vec<String>>,
+    /// Parts of files being downloaded, or why they can’t be.
+    pub download_message_tx: Sender<ViewerMessage>,
 }
 
-impl Protocol<SendingConnectionKind> {
-    pub fn connect(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
-        Ok(Self(SendingConnectionKind {
-            stream: TcpStream::connect(addr)?,
+pub struct SayingHello {
+    stream: BufReader<Stream>,
+}
+
+impl Protocol<SayingHello> {
+    pub fn connect(connector: &Connector) -> anyhow::Result<Self> {
+        Ok(Self(SayingHello {
+            stream: BufReader::new(connector.connect()?),
         }))
     }
 
-    pub fn send_connection_kind(
-        mut self,
-        server_event_tx: Sender<ServerEvent>,
-        event_tx: Sender<Event>,
-    ) -> anyhow::Result<Protocol<ReadingHistory>> {
-        jsonl::write(&mut self.0.stream, &ConnectionKind::Viewer)?;
-
-        Ok(Protocol(ReadingHistory {
-            stream: BufReader::new(self.0.stream),
-            server_event_tx,
-            event_tx,
+    /// Fails if the server can’t talk to us,
+    /// with its explanation of why.
+    pub fn say_hello(mut self) -> anyhow::Result<Protocol<SendingConnectionKind>> {
+        jsonl::write(self.0.stream.get_mut(), &Hello::new("nunitius-viewer"))?;
+        let capabilities =
+            jsonl::read::<_, HelloResponse>(&mut self.0.stream)?.into_capabilities()?;
+
+        Ok(Protocol(SendingConnectionKind {
+            stream: self.0.stream,
+            capabilities,
         }))
     }
 }
 
-pub struct ReadingHistory {
-    stream: BufReader<TcpStream>,
-    server_event_tx: Sender<ServerEvent>,
-    event_tx: Sender<Event>,
+pub struct SendingConnectionKind {
+    stream: BufReader<Stream>,
+    capabilities: Vec<Capability>,
 }
 
-impl Protocol<ReadingHistory> {
-    pub fn read_history(mut self) -> anyhow::Result<Protocol<ReadingEvents>> {
-        let history: Vec<ServerEvent> = jsonl::read(&mut self.0.stream)?;
+impl Protocol<SendingConnectionKind> {
+    pub fn send_connection_kind(mut self) -> anyhow::Result<Protocol<LoggingIn>> {
+        jsonl::write(self.0.stream.get_mut(), &ConnectionKind::Viewer)?;
+
+        Ok(Protocol(LoggingIn {
+            stream: self.0.stream,
+            capabilities: self.0.capabilities,
+        }))
+    }
+}
 
-        for server_event in history {
-            self.0.server_event_tx.send(server_event.clone()).unwrap();
+pub struct LoggingIn {
+    stream: BufReader<Stream>,
+    capabilities: Vec<Capability>,
+}
 
-            if let Some(event) = Event::from_server_event(server_event) {
-                self.0.event_tx.send(event).unwrap();
-            }
+impl Protocol<LoggingIn> {
+    /// Fails with [`IncorrectCredentials`] if the server doesn’t accept `credentials`.
+    pub fn log_in(
+        mut self,
+        credentials: &Credentials,
+        channels: Channels,
+    ) -> anyhow::Result<Protocol<ReadingEvents>> {
+        jsonl::write(self.0.stream.get_mut(), credentials)?;
+
+        match jsonl::read(&mut self.0.stream)? {
+            LoginResponse::LoggedIn => Ok(Protocol(ReadingEvents {
+                stream: self.0.stream,
+                capabilities: self.0.capabilities,
+                channels,
+            })),
+            _ => Err(IncorrectCredentials.into()),
         }
+    }
+}
 
-        Ok(Protocol(ReadingEvents {
-            stream: self.0.stream,
-            server_event_tx: self.0.server_event_tx,
-            event_tx: self.0.event_tx,
-        }))
+#[derive(Debug)]
+pub struct IncorrectCredentials;
+
+impl fmt::Display for IncorrectCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "incorrect nickname or password")
     }
 }
 
+impl std::error::Error for IncorrectCredentials {}
+
 pub struct ReadingEvents {
-    stream: BufReader<TcpStream>,
-    server_event_tx: Sender<ServerEvent>,
-    event_tx: Sender<Event>,
+    stream: BufReader<Stream>,
+    capabilities: Vec<Capability>,
+    channels: Channels,
 }
 
 impl Protocol<ReadingEvents> {
+    pub fn requester(&self) -> anyhow::Result<Requester> {
+        Ok(Requester {
+            stream: Arc::new(Mutex::new(self.0.stream.get_ref().try_clone()?)),
+            capabilities: self.0.capabilities.clone(),
+        })
+    }
+
     pub fn read_events(&mut self) -> anyhow::Result<Never> {
+        let channels = &self.0.channels;
+
         loop {
-            let server_event: ServerEvent = jsonl::read(&mut self.0.stream)?;
+            let message = match jsonl::read(&mut self.0.stream) {
+                Ok(message) => message,
+                Err(jsonl::ReadError::Io(e)) if stream::is_timeout(&e) => {
+                    anyhow::bail!("server stopped responding")
+                }
+                Err(e) => return Err(e.into()),
+            };
 
-            self.0.server_event_tx.send(server_event.clone()).unwrap();
+            match message {
+                ViewerMessage::Event(server_event) => {
+                    channels.server_event_tx.send(server_event.clone()).unwrap();
 
-            if let Some(event) = Event::from_server_event(server_event) {
-                self.0.event_tx.send(event).unwrap();
+                    if let Some(event) = Event::from_server_event(server_event) {
+                        channels.event_tx.send(event).unwrap();
+                    }
+                }
+
+                ViewerMessage::HistoryPage(page) => channels.history_page_tx.send(page).unwrap(),
+
+                ViewerMessage::Rooms(rooms) => channels.rooms_tx.send(rooms).unwrap(),
+
+                message @ (ViewerMessage::FileChunk { .. }
+                | ViewerMessage::DownloadFailed { .. }) => {
+                    channels.download_message_tx.send(message).unwrap()
+                }
+
+                ViewerMessage::Heartbeat => {}
             }
         }
     }
 }
 
+/// Sends requests to the server;
+/// the server’s replies are received by [`Protocol::read_events`].
+///
+/// Clones share the same connection,
+/// so requests can be sent from several threads at once.
+#[derive(Clone)]
+pub struct Requester {
+    stream: Arc<Mutex<Stream>>,
+    /// What both we and the server support.
+    capabilities: Vec<Capability>,
+}
+
+impl Requester {
+    pub fn join_room(&mut self, room: String, history: HistoryQuery) -> anyhow::Result<()> {
+        self.send(&ViewerRequest::JoinRoom { room, history })
+    }
+
+    pub fn leave_room(&mut self, room: String) -> anyhow::Result<()> {
+        self.send(&ViewerRequest::LeaveRoom { room })
+    }
+
+    pub fn list_rooms(&mut self) -> anyhow::Result<()> {
+        self.send(&ViewerRequest::ListRooms)
+    }
+
+    pub fn fetch_history_before(
+        &mut self,
+        room: String,
+        before: usize,
+        count: usize,
+    ) -> anyhow::Result<()> {
+        self.fetch_history(room, HistoryQuery::Before { before, count })
+    }
+
+    pub fn fetch_history(&mut self, room: String, query: HistoryQuery) -> anyhow::Result<()> {
+        self.send(&ViewerRequest::History { room, query })
+    }
+
+    /// Fails if the server doesn’t support downloads,
+    /// since it would hang up on us if we asked anyway.
+    pub fn download(&mut self, sha256: String, offset: u64, len: u64) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.supports(Capability::Downloads),
+            "the server doesn’t support downloading files"
+        );
+
+        self.send(&ViewerRequest::Download {
+            sha256,
+            offset,
+            len,
+        })
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    pub fn heartbeat(&mut self) -> anyhow::Result<()> {
+        self.send(&ViewerRequest::Heartbeat)
+    }
+
+    fn send(&mut self, request: &ViewerRequest) -> anyhow::Result<()> {
+        jsonl::write(&mut *self.stream.lock().unwrap(), request)?;
+        Ok(())
+    }
+}
+
 pub enum Never {}
@@ -1,5 +1,8 @@
 use super::Event;
 
+/// The events in a room, and which of them fit on screen.
+///
+/// Events can take up more than one row, so `height` is in rows.
 pub struct Timeline {
     events: Vec<Event>,
     height: usize,
@@ -20,17 +23,29 @@ impl Timeline {
         self.scroll_to_bottom();
     }
 
-    pub fn visible_events(&self) -> &[Event] {
-        let visible_events = &self.events[self.top_event_idx..self.bottom_event_idx()];
+    /// Adds events older than any currently in the timeline
+    /// without changing which events are visible.
+    pub fn prepend_events(&mut self, events: Vec<Event>) {
+        let num_new_events = events.len();
+        self.events.splice(0..0, events);
+        self.top_event_idx += num_new_events;
 
-        let expected_num_events = if self.can_all_events_fit_on_screen() {
-            self.events.len()
-        } else {
-            self.height
-        };
-        assert_eq!(visible_events.len(), expected_num_events);
+        // there may have been empty space to fill
+        if self.past_bottom() {
+            self.scroll_to_bottom();
+        }
+    }
 
-        visible_events
+    pub fn last_event(&self) -> Option<&Event> {
+        self.events.last()
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn visible_events(&self) -> &[Event] {
+        &self.events[self.top_event_idx..self.bottom_event_idx()]
     }
 
     pub fn resize(&mut self, new_height: usize) {
@@ -53,43 +68,82 @@ impl Timeline {
         }
     }
 
+    /// Scrolls just far enough for the event at `idx` to be visible.
+    pub fn scroll_to(&mut self, idx: usize) {
+        if idx < self.top_event_idx {
+            self.top_event_idx = idx;
+        }
+
+        while idx >= self.bottom_event_idx() && !self.at_bottom() {
+            self.top_event_idx += 1;
+        }
+    }
+
     fn scroll_to_bottom(&mut self) {
-        self.top_event_idx = if self.can_all_events_fit_on_screen() {
-            0
-        } else {
-            self.events.len() - self.height
-        };
+        self.top_event_idx = self.top_event_idx_at_bottom();
     }
 
     fn past_bottom(&self) -> bool {
-        self.top_event_idx + self.height > self.events.len()
+        self.top_event_idx > self.top_event_idx_at_bottom()
     }
 
-    fn at_top(&self) -> bool {
+    pub fn at_top(&self) -> bool {
         self.top_event_idx == 0
     }
 
     fn at_bottom(&self) -> bool {
-        self.bottom_event_idx() == self.events.len()
+        self.top_event_idx >= self.top_event_idx_at_bottom()
     }
 
+    /// The index just past the last event that fits on screen.
+    ///
+    /// The top event is always shown, even if it’s too tall to fit.
     fn bottom_event_idx(&self) -> usize {
-        if self.can_all_events_fit_on_screen() {
-            self.events.len()
-        } else {
-            self.top_event_idx + self.height
+        let mut num_rows = 0;
+
+        for (idx, event) in self.events.iter().enumerate().skip(self.top_event_idx) {
+            num_rows += event.num_rows();
+
+            if num_rows > self.height && idx > self.top_event_idx {
+                return idx;
+            }
         }
+
+        self.events.len()
     }
 
-    fn can_all_events_fit_on_screen(&self) -> bool {
-        self.events.len() <= self.height
+    /// The index of the top event when scrolled all the way down.
+    fn top_event_idx_at_bottom(&self) -> usize {
+        let mut num_rows = 0;
+
+        for (idx, event) in self.events.iter().enumerate().rev() {
+            num_rows += event.num_rows();
+
+            if num_rows > self.height {
+                // the last event is shown even if it’s too tall to fit
+                return (idx + 1).min(self.events.len() - 1);
+            }
+        }
+
+        0
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::super::dummy_events::*;
+    use super::super::EventKind;
     use super::*;
+    use crate::Message;
+
+    fn message(event: &Event, body: &str) -> Event {
+        Event {
+            event: EventKind::Message(Message::Text {
+                body: body.to_string(),
+            }),
+            ..event.clone()
+        }
+    }
 
     #[test]
     fn empty_has_no_visible_events() {
@@ -218,6 +272,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn prepending_events_does_not_scroll() {
+        let mut timeline = Timeline::new(2);
+
+        timeline.add_event(EVENT_3.clone());
+        timeline.add_event(EVENT_4.clone());
+
+        timeline.prepend_events(vec![EVENT_1.clone(), EVENT_2.clone()]);
+        assert_eq!(
+            timeline.visible_events(),
+            [EVENT_3.clone(), EVENT_4.clone()]
+        );
+
+        timeline.scroll_up();
+        assert_eq!(
+            timeline.visible_events(),
+            [EVENT_2.clone(), EVENT_3.clone()]
+        );
+    }
+
+    #[test]
+    fn prepending_events_fills_the_screen() {
+        let mut timeline = Timeline::new(3);
+
+        timeline.add_event(EVENT_3.clone());
+        timeline.add_event(EVENT_4.clone());
+
+        timeline.prepend_events(vec![EVENT_1.clone(), EVENT_2.clone()]);
+        assert_eq!(
+            timeline.visible_events(),
+            [EVENT_2.clone(), EVENT_3.clone(), EVENT_4.clone()]
+        );
+    }
+
     #[test]
     fn resizing_smaller_does_not_scroll() {
         let mut timeline = Timeline::new(3);
@@ -278,4 +366,38 @@ mod tests {
             [EVENT_1.clone(), EVENT_2.clone(), EVENT_3.clone()]
         );
     }
+
+    #[test]
+    fn multi_line_events_take_up_several_rows() {
+        let mut timeline = Timeline::new(3);
+
+        timeline.add_event(EVENT_1.clone());
+        timeline.add_event(EVENT_2.clone());
+        timeline.add_event(message(&EVENT_3, "one\ntwo"));
+        assert_eq!(
+            timeline.visible_events(),
+            [EVENT_2.clone(), message(&EVENT_3, "one\ntwo")]
+        );
+
+        timeline.scroll_up();
+        assert_eq!(
+            timeline.visible_events(),
+            [EVENT_1.clone(), EVENT_2.clone()]
+        );
+    }
+
+    #[test]
+    fn events_taller_than_the_screen_are_still_visible() {
+        let mut timeline = Timeline::new(2);
+
+        timeline.add_event(EVENT_1.clone());
+        timeline.add_event(message(&EVENT_2, "one\ntwo\nthree"));
+        assert_eq!(
+            timeline.visible_events(),
+            [message(&EVENT_2, "one\ntwo\nthree")]
+        );
+
+        timeline.scroll_up();
+        assert_eq!(timeline.visible_events(), std::slice::from_ref(&*EVENT_1));
+    }
 }
@@ -1,13 +1,19 @@
-use super::{Event, EventKind};
-use crate::{Color, Message, User};
+use super::{ConnectionState, Event, EventKind};
+use crate::{Color, FileMetadata, Message, User};
 use chrono::Local;
-use crossterm::style::{self, style, Styler};
+use crossterm::style::{self, style, Stylize};
 
+/// Put before every line of a message after the first,
+/// so that they line up under the time it was sent.
+const CONTINUATION_INDENT: &str = "        ";
+
+/// Renders an event as one line per row it takes up.
 pub(super) fn render_event(
     Event {
         event,
         user,
         time_occurred,
+        ..
     }: &Event,
 ) -> String {
     let user = render_user(user);
@@ -16,25 +22,167 @@ pub(super) fn render_event(
     let local_time_occurred = local_time_occurred.format("%H:%M");
 
     match event {
-        EventKind::Message(message) => match message {
-            Message::Text { body } => format!("[{}] {}: {}", local_time_occurred, user, body),
-            Message::File { contents } => format!(
-                "[{}] {} sent a file: ‘{}’",
+        EventKind::Message(message) => {
+            format!(
+                "[{}] {}",
                 local_time_occurred,
-                user,
-                String::from_utf8_lossy(contents)
-                    .lines()
-                    .next()
-                    .unwrap_or("")
-            ),
-        },
+                render_message(&user, message)
+            )
+        }
         EventKind::Login => format!("[{}] {} logged in!", local_time_occurred, user),
         EventKind::Logout => format!("[{}] {} logged out!", local_time_occurred, user),
+        EventKind::JoinRoom => format!("[{}] {} joined the room", local_time_occurred, user),
+        EventKind::LeaveRoom => format!("[{}] {} left the room", local_time_occurred, user),
+        EventKind::NicknameChange { old_nickname } => format!(
+            "[{}] {} is now known as {}",
+            local_time_occurred,
+            style(sanitize(old_nickname)).bold(),
+            user
+        ),
+        EventKind::DirectMessage { to, message } => {
+            let from_and_to = format!("{} → {}", user, style(sanitize(to)).bold());
+
+            format!(
+                "[{}] {} {}",
+                local_time_occurred,
+                style("(private)").italic(),
+                render_message(&from_and_to, message),
+            )
+        }
+    }
+}
+
+/// Marks an event as selected without disturbing its own styling.
+pub(super) fn render_selected_event(event: &Event) -> String {
+    format!("{} {}", style("›").reverse(), render_event(event))
+}
+
+/// Says which file is being previewed, and how to stop.
+pub(super) fn render_preview_header(metadata: &FileMetadata) -> String {
+    format!(
+        "{} {}",
+        style(format!(
+            " {} ({}, {}) ",
+            sanitize(&metadata.filename),
+            render_size(metadata.size),
+            sanitize(&metadata.mime_type)
+        ))
+        .reverse(),
+        style("Esc to close").dim()
+    )
+}
+
+pub(super) fn render_connection_state(connection_state: ConnectionState) -> String {
+    match connection_state {
+        ConnectionState::Connecting => style("(connecting…)").dim().to_string(),
+        ConnectionState::Connected => String::new(),
+        ConnectionState::Reconnecting { retry_in } => style(format!(
+            "(disconnected, reconnecting in {}s…)",
+            retry_in.as_secs_f32().ceil()
+        ))
+        .red()
+        .to_string(),
+    }
+}
+
+pub(super) fn render_notice(notice: Option<&str>) -> String {
+    match notice {
+        // notices can mention the names of files other people sent
+        Some(notice) => style(format!(" {}", sanitize(notice))).dim().to_string(),
+        None => String::new(),
+    }
+}
+
+fn render_message(from: &str, message: &Message) -> String {
+    match message {
+        Message::Text { body } => format!("{}: {}", from, indent_continuation_lines(body)),
+        Message::Action { body } => format!(
+            "{} {} {}",
+            style("*").dim(),
+            from,
+            indent_continuation_lines(body)
+        ),
+        Message::File {
+            metadata: Some(metadata),
+            ..
+        } => format!(
+            "{} sent {} ({}, {})",
+            from,
+            sanitize(&metadata.filename),
+            render_size(metadata.size),
+            sanitize(&metadata.mime_type)
+        ),
+        Message::File {
+            metadata: None,
+            contents,
+        } => format!(
+            "{} sent a file ({})",
+            from,
+            render_size(contents.len() as u64)
+        ),
+    }
+}
+
+/// Rounds a number of bytes to one decimal place of the largest unit that fits.
+pub(super) fn render_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["kB", "MB", "GB", "TB"];
+
+    if bytes < 1000 {
+        return format!("{} B", bytes);
     }
+
+    let mut size = bytes as f64 / 1000.0;
+    let mut unit = 0;
+
+    // going by the rounded size means 999 999 bytes is 1.0 MB rather than 1000.0 kB
+    while size >= 999.95 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn indent_continuation_lines(body: &str) -> String {
+    body.split('\n')
+        .map(sanitize)
+        .collect::<Vec<_>>()
+        .join(&format!("\n{}", CONTINUATION_INDENT))
+}
+
+/// Makes part of a line safe to print on a row of its own.
+///
+/// Everything that came from another user goes through this,
+/// so that nobody gets to control anyone else’s terminal.
+pub fn sanitize(text: &str) -> String {
+    let mut sanitized = String::with_capacity(text.len());
+
+    for c in text.trim_end_matches(['\n', '\r']).chars() {
+        match c {
+            '\t' => sanitized.push_str("    "),
+            c if c.is_control() => sanitized.push('�'),
+            c => sanitized.push(c),
+        }
+    }
+
+    sanitized
+}
+
+pub(super) fn render_rooms<'a>(rooms: impl Iterator<Item = &'a String>, current: &str) -> String {
+    rooms
+        .map(|room| {
+            if room == current {
+                style(format!("#{}", sanitize(room))).reverse().to_string()
+            } else {
+                format!("#{}", sanitize(room))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
 pub(super) fn render_currently_typing_users<'a>(
-    mut users: impl Iterator<Item = &'a User> + ExactSizeIterator,
+    mut users: impl ExactSizeIterator<Item = &'a User>,
 ) -> String {
     match users.len() {
         0 => String::new(),
@@ -47,7 +195,7 @@ pub(super) fn render_currently_typing_users<'a>(
 }
 
 fn render_user(user: &User) -> String {
-    let base_styled_content = style(&user.nickname).bold();
+    let base_styled_content = style(sanitize(&user.nickname)).bold();
 
     if let Some(ref color) = user.color {
         let color = match color {
@@ -64,3 +212,39 @@ fn render_user(user: &User) -> String {
         base_styled_content.to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_are_rounded_to_the_nearest_unit() {
+        assert_eq!(render_size(0), "0 B");
+        assert_eq!(render_size(999), "999 B");
+        assert_eq!(render_size(1000), "1.0 kB");
+        assert_eq!(render_size(1_234_567), "1.2 MB");
+        assert_eq!(render_size(999_999), "1.0 MB");
+        assert_eq!(render_size(5_000_000_000_000_000), "5000.0 TB");
+    }
+
+    #[test]
+    fn other_users_cant_control_the_terminal() {
+        let event = Event {
+            event: EventKind::Message(Message::Text {
+                body: "hi\x1b[2J\nthere\x07".to_string(),
+            }),
+            user: User {
+                nickname: "luna\x1b]0;pwned\x07".to_string(),
+                color: None,
+            },
+            ..crate::viewer::dummy_events::EVENT_1.clone()
+        };
+
+        let rendered = render_event(&event);
+        assert!(!rendered.contains("\x07"));
+        assert!(!rendered.contains("\x1b[2J"));
+        assert!(!rendered.contains("\x1b]"));
+        assert!(rendered.contains("luna�]0;pwned�"));
+        assert!(rendered.contains(&format!("hi�[2J\n{}there�", CONTINUATION_INDENT)));
+    }
+}
@@ -0,0 +1,282 @@
+//! Checks that the server can hold lots of idle connections at once
+//! without needing a thread for each.
+//!
+//! It takes a while, so it only runs when asked for:
+//!
+//! ```sh
+//! cargo test --release --test load -- --ignored
+//! ```
+//!
+//! Set `NUNITIUS_LOAD_TEST_CONNECTIONS` to change how many viewers connect,
+//! keeping in mind that each one needs a file descriptor in both processes.
+
+use argon2::password_hash::PasswordHasher;
+use argon2::{Algorithm, Argon2, Params, Version};
+use nunitius::{
+    ConnectionKind, Credentials, EventKind, Hello, HelloResponse, HistoryQuery, Login,
+    LoginResponse, Message, SenderEvent, User, ViewerMessage, ViewerRequest,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, fs};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::time;
+
+const DEFAULT_CONNECTIONS: usize = 10_000;
+const NICKNAME: &str = "luna";
+const PASSWORD: &str = "hunter2";
+
+/// Kills the server when the test ends, however it ends.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn spawn_server(port: u16) -> Server {
+    let dir = env::temp_dir().join(format!("nunitius-load-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    // the default parameters would make logging in thousands of viewers take minutes
+    let password_hash = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(Params::MIN_M_COST, Params::MIN_T_COST, 1, None).unwrap(),
+    )
+    .hash_password(PASSWORD.as_bytes())
+    .unwrap()
+    .to_string();
+
+    let accounts = serde_json::json!({ "nickname": NICKNAME, "password_hash": password_hash });
+    fs::write(dir.join("accounts.jsonl"), format!("{}\n", accounts)).unwrap();
+    fs::write(dir.join("config.toml"), "").unwrap();
+    let _ = fs::remove_file(dir.join("history.jsonl"));
+    let _ = fs::remove_file(dir.join("direct-messages.jsonl"));
+
+    let child = Command::new(env!("CARGO_BIN_EXE_nunitius-server"))
+        .arg("--config")
+        .arg(dir.join("config.toml"))
+        .arg("--accounts")
+        .arg(dir.join("accounts.jsonl"))
+        .arg("--history")
+        .arg(dir.join("history.jsonl"))
+        .arg("--direct-messages")
+        .arg(dir.join("direct-messages.jsonl"))
+        .arg("--files")
+        .arg(dir.join("files"))
+        .args(["--address", "127.0.0.1", "--port", &port.to_string()])
+        .args(["--log-level", "warn"])
+        // the viewers stay quiet for the whole test
+        .args(["--heartbeat-interval", "60", "--heartbeat-timeout", "600"])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    Server(child)
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// A client’s end of a connection, speaking JSON Lines.
+struct Client {
+    reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
+    writer: tokio::net::tcp::OwnedWriteHalf,
+}
+
+impl Client {
+    async fn connect(port: u16, kind: ConnectionKind) -> Self {
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (reader, writer) = stream.into_split();
+        let mut client = Self {
+            reader: BufReader::new(reader),
+            writer,
+        };
+
+        client.write(&Hello::new("load-test")).await;
+        client
+            .read::<HelloResponse>()
+            .await
+            .into_capabilities()
+            .unwrap();
+
+        client.write(&kind).await;
+        client
+    }
+
+    async fn write<T: Serialize>(&mut self, t: &T) {
+        let mut line = serde_json::to_vec(t).unwrap();
+        line.push(b'\n');
+        self.writer.write_all(&line).await.unwrap();
+    }
+
+    async fn read<T: DeserializeOwned>(&mut self) -> T {
+        let mut line = String::new();
+        let len = self.reader.read_line(&mut line).await.unwrap();
+        assert_ne!(len, 0, "server closed the connection");
+
+        serde_json::from_str(&line).unwrap()
+    }
+}
+
+/// Logs a viewer in and has it join the default room.
+async fn connect_viewer(port: u16) -> Client {
+    let mut viewer = Client::connect(port, ConnectionKind::Viewer).await;
+
+    viewer
+        .write(&Credentials {
+            nickname: NICKNAME.to_string(),
+            password: PASSWORD.to_string(),
+        })
+        .await;
+    assert_eq!(
+        viewer.read::<LoginResponse>().await,
+        LoginResponse::LoggedIn
+    );
+
+    viewer
+        .write(&ViewerRequest::JoinRoom {
+            room: "general".to_string(),
+            history: HistoryQuery::Latest { count: 0 },
+        })
+        .await;
+
+    loop {
+        if let ViewerMessage::HistoryPage(page) = viewer.read().await {
+            if page.is_last {
+                return viewer;
+            }
+        }
+    }
+}
+
+async fn wait_for_server(port: u16) {
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+            return;
+        }
+
+        time::sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("server didn’t start listening");
+}
+
+#[cfg(target_os = "linux")]
+fn thread_count(server: &Server) -> usize {
+    let status = fs::read_to_string(
+        PathBuf::from("/proc")
+            .join(server.0.id().to_string())
+            .join("status"),
+    )
+    .unwrap();
+
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn many_idle_viewers_all_receive_a_message() {
+    let connections = env::var("NUNITIUS_LOAD_TEST_CONNECTIONS")
+        .map(|connections| connections.parse().unwrap())
+        .unwrap_or(DEFAULT_CONNECTIONS);
+
+    let port = free_port();
+    let server = spawn_server(port);
+    wait_for_server(port).await;
+
+    // connecting all at once would overflow the listen backlog
+    let connecting = Arc::new(Semaphore::new(256));
+    let (joined_tx, joined_rx) = flume::unbounded();
+
+    let viewers: Vec<_> = (0..connections)
+        .map(|_| {
+            let connecting = Arc::clone(&connecting);
+            let joined_tx = joined_tx.clone();
+
+            tokio::spawn(async move {
+                let permit = connecting.acquire().await.unwrap();
+                let mut viewer = connect_viewer(port).await;
+                drop(permit);
+                joined_tx.send(()).unwrap();
+
+                loop {
+                    if let ViewerMessage::Event(event) = viewer.read().await {
+                        if let EventKind::Message(message) = event.event {
+                            return message;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for _ in 0..connections {
+        time::timeout(Duration::from_secs(120), joined_rx.recv_async())
+            .await
+            .expect("viewers took too long to connect")
+            .unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let parallelism = std::thread::available_parallelism().unwrap().get();
+        let threads = thread_count(&server);
+        assert!(
+            threads <= 3 * parallelism + 16,
+            "server used {} threads for {} connections",
+            threads,
+            connections
+        );
+    }
+
+    let mut sender = Client::connect(port, ConnectionKind::Sender).await;
+    sender
+        .write(&Login {
+            user: User {
+                nickname: NICKNAME.to_string(),
+                color: None,
+            },
+            password: PASSWORD.to_string(),
+        })
+        .await;
+    assert_eq!(
+        sender.read::<LoginResponse>().await,
+        LoginResponse::LoggedIn
+    );
+
+    let message = Message::Text {
+        body: "hello, everyone".to_string(),
+    };
+    sender.write(&SenderEvent::Message(message.clone())).await;
+
+    for viewer in viewers {
+        let received = time::timeout(Duration::from_secs(120), viewer)
+            .await
+            .expect("viewers took too long to receive the message")
+            .unwrap();
+        assert_eq!(received, message);
+    }
+
+    drop(server);
+}
@@ -0,0 +1,371 @@
+//! Checks that uploads and downloads cut off halfway
+//! carry on from where they got to once the client reconnects,
+//! and still end up with the whole file.
+
+use argon2::password_hash::PasswordHasher;
+use argon2::{Algorithm, Argon2, Params, Version};
+use nunitius::config::Heartbeat;
+use nunitius::sender::connection::{self, ConnectionEvent, ServerConnection};
+use nunitius::sender::upload::Upload;
+use nunitius::server::FileStore;
+use nunitius::stream::Connector;
+use nunitius::viewer::{self, Channels, DownloadEvent, Downloads};
+use nunitius::{Credentials, FileMetadata, LoginResponse, User, ViewerMessage};
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use std::{env, fs};
+
+const NICKNAME: &str = "luna";
+const PASSWORD: &str = "hunter2";
+const FILE_SIZE: usize = 2 * 1024 * 1024;
+/// How long to wait for anything before giving up on the test.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Kills the server when the test ends, however it ends.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Sets up a directory for the server with an account to log in with.
+fn server_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("nunitius-resume-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    // the default parameters make logging in slow in debug builds
+    let password_hash = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(Params::MIN_M_COST, Params::MIN_T_COST, 1, None).unwrap(),
+    )
+    .hash_password(PASSWORD.as_bytes())
+    .unwrap()
+    .to_string();
+
+    let accounts = serde_json::json!({ "nickname": NICKNAME, "password_hash": password_hash });
+    fs::write(dir.join("accounts.jsonl"), format!("{}\n", accounts)).unwrap();
+    fs::write(dir.join("config.toml"), "").unwrap();
+
+    dir
+}
+
+fn spawn_server(dir: &Path, port: u16) -> Server {
+    let server = Server(
+        Command::new(env!("CARGO_BIN_EXE_nunitius-server"))
+            .arg("--config")
+            .arg(dir.join("config.toml"))
+            .arg("--accounts")
+            .arg(dir.join("accounts.jsonl"))
+            .arg("--history")
+            .arg(dir.join("history.jsonl"))
+            .arg("--direct-messages")
+            .arg(dir.join("direct-messages.jsonl"))
+            .arg("--files")
+            .arg(dir.join("files"))
+            .args(["--address", "127.0.0.1", "--port", &port.to_string()])
+            .args(["--log-level", "warn"])
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return server;
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    panic!("server didn’t start listening");
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Sits between the clients and the server,
+/// slowing everything down enough to cut connections off partway through a file.
+struct Proxy {
+    port: u16,
+    connections: Arc<Mutex<Vec<TcpStream>>>,
+    /// How many bytes the clients have sent and received.
+    transferred: Arc<AtomicU64>,
+}
+
+impl Proxy {
+    fn start(server_port: u16) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = Self {
+            port: listener.local_addr().unwrap().port(),
+            connections: Arc::default(),
+            transferred: Arc::default(),
+        };
+
+        let connections = Arc::clone(&proxy.connections);
+        let transferred = Arc::clone(&proxy.transferred);
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = client.unwrap();
+                let server = TcpStream::connect(("127.0.0.1", server_port)).unwrap();
+
+                let mut connections = connections.lock().unwrap();
+                connections.push(client.try_clone().unwrap());
+                connections.push(server.try_clone().unwrap());
+
+                for (from, to) in [
+                    (client.try_clone().unwrap(), server.try_clone().unwrap()),
+                    (server, client),
+                ] {
+                    let transferred = Arc::clone(&transferred);
+                    thread::spawn(move || forward(from, to, &transferred));
+                }
+            }
+        });
+
+        proxy
+    }
+
+    fn kill_connections(&self) {
+        for connection in self.connections.lock().unwrap().drain(..) {
+            let _ = connection.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn transferred(&self) -> u64 {
+        self.transferred.load(Ordering::SeqCst)
+    }
+}
+
+fn forward(mut from: TcpStream, mut to: TcpStream, transferred: &AtomicU64) {
+    let mut buf = [0; 4096];
+
+    while let Ok(len @ 1..) = from.read(&mut buf) {
+        if to.write_all(&buf[..len]).is_err() {
+            break;
+        }
+
+        transferred.fetch_add(len as u64, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let _ = from.shutdown(Shutdown::Both);
+    let _ = to.shutdown(Shutdown::Both);
+}
+
+fn connector(port: u16) -> Connector {
+    Connector {
+        host: "127.0.0.1".to_string(),
+        port,
+        tls: None,
+        heartbeat: Heartbeat {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+        },
+    }
+}
+
+fn random_file(dir: &Path) -> (PathBuf, Vec<u8>) {
+    // not very random, but nothing repeats often enough to hide a misplaced chunk
+    let contents: Vec<u8> = (0..FILE_SIZE as u64)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+        .collect();
+    let path = dir.join("original.bin");
+    fs::write(&path, &contents).unwrap();
+
+    (path, contents)
+}
+
+#[test]
+fn uploads_carry_on_after_the_connection_is_lost() {
+    let dir = server_dir("upload");
+    let server_port = free_port();
+    let _server = spawn_server(&dir, server_port);
+    let proxy = Proxy::start(server_port);
+    let connector = connector(proxy.port);
+
+    let user = User {
+        nickname: NICKNAME.to_string(),
+        color: None,
+    };
+    let mut server_connection = ServerConnection::connect(&connector).unwrap();
+    assert_eq!(
+        server_connection
+            .log_in(user.clone(), PASSWORD.to_string())
+            .unwrap(),
+        LoginResponse::LoggedIn
+    );
+
+    let (_sender_event_tx, sender_event_rx) = flume::unbounded();
+    let (upload_tx, upload_rx) = flume::unbounded();
+    let (sender_message_tx, _sender_message_rx) = flume::unbounded();
+    let (connection_event_tx, connection_event_rx) = flume::unbounded();
+
+    thread::spawn(move || {
+        connection::maintain_connection(
+            connector,
+            user,
+            PASSWORD.to_string(),
+            server_connection,
+            connection::Channels {
+                sender_event_rx,
+                upload_rx,
+                sender_message_tx,
+            },
+            connection_event_tx,
+        )
+    });
+
+    let (path, contents) = random_file(&dir);
+    let upload = Upload::open(&path).unwrap();
+    let sha256 = upload.metadata().sha256.clone();
+    upload_tx.send(upload).unwrap();
+
+    let mut transferred_at_reconnect = None;
+
+    loop {
+        match connection_event_rx.recv_timeout(TIMEOUT).unwrap() {
+            ConnectionEvent::UploadProgress { percent, .. }
+                if percent >= 50 && transferred_at_reconnect.is_none() =>
+            {
+                proxy.kill_connections();
+            }
+            ConnectionEvent::Reconnected => {
+                transferred_at_reconnect = Some(proxy.transferred());
+            }
+            ConnectionEvent::UploadFinished { .. } => break,
+            ConnectionEvent::UploadFailed { reason, .. } => panic!("upload failed: {}", reason),
+            _ => {}
+        }
+    }
+
+    // starting again from scratch would mean sending more than the whole file,
+    // even before it’s encoded
+    let transferred_at_reconnect = transferred_at_reconnect.expect("connection wasn’t lost");
+    assert!(proxy.transferred() - transferred_at_reconnect < FILE_SIZE as u64);
+
+    let files = FileStore::open(dir.join("files")).unwrap();
+    assert_eq!(
+        files.read(&sha256, 0, FILE_SIZE as u64 + 1).unwrap(),
+        contents
+    );
+}
+
+#[test]
+fn downloads_carry_on_after_the_connection_is_lost() {
+    let dir = server_dir("download");
+    let (_, contents) = random_file(&dir);
+    let metadata = FileMetadata {
+        filename: "copy.bin".to_string(),
+        ..FileStore::open(dir.join("files"))
+            .unwrap()
+            .insert(None, &contents)
+            .unwrap()
+    };
+
+    let server_port = free_port();
+    let _server = spawn_server(&dir, server_port);
+    let proxy = Proxy::start(server_port);
+
+    let (server_event_tx, _server_event_rx) = flume::unbounded();
+    let (event_tx, _event_rx) = flume::unbounded();
+    let (history_page_tx, _history_page_rx) = flume::unbounded();
+    let (rooms_tx, _rooms_rx) = flume::unbounded();
+    let (download_message_tx, download_message_rx) = flume::unbounded();
+    let (connection_event_tx, connection_event_rx) = flume::unbounded();
+
+    let connector = connector(proxy.port);
+    thread::spawn(move || {
+        viewer::maintain_connection(
+            connector,
+            Credentials {
+                nickname: NICKNAME.to_string(),
+                password: PASSWORD.to_string(),
+            },
+            Channels {
+                server_event_tx,
+                event_tx,
+                history_page_tx,
+                rooms_tx,
+                download_message_tx,
+            },
+            connection_event_tx,
+        )
+    });
+
+    let mut downloads = Downloads::new(dir.join("downloads"));
+    let mut requester = match connection_event_rx.recv_timeout(TIMEOUT).unwrap() {
+        viewer::ConnectionEvent::Connected(requester) => requester,
+        _ => panic!("viewer didn’t connect"),
+    };
+    assert_eq!(
+        downloads.start(metadata, Some(&mut requester)).unwrap(),
+        None
+    );
+
+    let mut transferred_at_reconnect = None;
+
+    let path = loop {
+        let next = flume::Selector::new()
+            .recv(&connection_event_rx, |connection_event| {
+                Next::ConnectionEvent(connection_event.unwrap())
+            })
+            .recv(&download_message_rx, |download_message| {
+                Next::DownloadMessage(download_message.unwrap())
+            })
+            .wait_timeout(TIMEOUT)
+            .unwrap();
+
+        match next {
+            Next::ConnectionEvent(viewer::ConnectionEvent::Connected(new_requester)) => {
+                transferred_at_reconnect = Some(proxy.transferred());
+                requester = new_requester;
+                downloads.resume();
+            }
+            Next::ConnectionEvent(_) => continue,
+            Next::DownloadMessage(download_message) => {
+                match downloads.handle_message(download_message) {
+                    Some(DownloadEvent::Progress { percent, .. })
+                        if percent >= 50 && transferred_at_reconnect.is_none() =>
+                    {
+                        proxy.kill_connections();
+                    }
+                    Some(DownloadEvent::Finished { path, .. }) => break path,
+                    Some(DownloadEvent::Failed { reason, .. }) => {
+                        panic!("download failed: {}", reason)
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // requests sent on a killed connection are sent again once we’ve reconnected
+        let _ = downloads.send_requests(&mut requester);
+    };
+
+    let transferred_at_reconnect = transferred_at_reconnect.expect("connection wasn’t lost");
+    assert!(proxy.transferred() - transferred_at_reconnect < FILE_SIZE as u64);
+
+    assert_eq!(path, dir.join("downloads").join("copy.bin"));
+    assert_eq!(fs::read(path).unwrap(), contents);
+}
+
+enum Next {
+    ConnectionEvent(viewer::ConnectionEvent),
+    DownloadMessage(ViewerMessage),
+}